@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use cpu::PROGRAM_START;
+use instruction::Instruction;
+use instruction::Instruction::*;
+use symbols::{SourceLine, Symbols};
+
+// An assembler for the Cowgod-style mnemonics produced by the disassembler,
+// e.g. LD V0, 0x12. Source is line based:
+//
+//     ; comments run to the end of the line
+//     SPEED EQU 2          ; constants, also written SPEED = 2
+//     start:  LD I, sprite ; labels may precede an instruction
+//             JP start
+//     sprite: DB 0x80, 0b11000000
+//             DW 0x1234
+//             ORG 0x300    ; moves the assembly address
+//
+// Numbers are decimal, 0x hex or 0b binary, and operands may add or subtract
+// numbers, labels and constants, e.g. sprite + 2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// assembles source into a ROM, to be loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with_symbols(source, "").map(|(rom, _)| rom)
+}
+
+// assembles as above, along with the labels and the source line of each
+// statement, in the named file, as symbols for debugging
+pub fn assemble_with_symbols(source: &str, file: &str) -> Result<(Vec<u8>, Symbols), AsmError> {
+    let mut assembler = Assembler::new();
+    for (index, line) in source.lines().enumerate() {
+        assembler.parse_line(index + 1, line)?;
+    }
+    let rom = assembler.emit()?;
+    Ok((rom, assembler.symbols(file)))
+}
+
+fn error<T>(line: usize, column: usize, message: String) -> Result<T, AsmError> {
+    Err(AsmError { line, column, message })
+}
+
+// an operand, or part of one, along with its position in the line
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        error(self.line, self.column, message)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64),
+    Symbol(Token),
+}
+
+// terms which are summed, each with its sign
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(i64, Term)>,
+    token: Token,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(Expr),
+    Value(Expr),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction { mnemonic: Token, operands: Vec<(Operand, Token)> },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+struct Assembler {
+    address: u32,
+    statements: Vec<(u32, Statement)>,
+    labels: HashMap<String, u32>,
+    constants: HashMap<String, Expr>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            // the default origin
+            address: PROGRAM_START as u32,
+            statements: Vec::new(),
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+        }
+    }
+
+    fn parse_line(&mut self, line: usize, text: &str) -> Result<(), AsmError> {
+        let text = match text.find(';') {
+            Some(comment) => &text[..comment],
+            None => text,
+        };
+        let mut words = split_words(line, text);
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        // label:
+        if words[0].text.ends_with(':') {
+            let label = words.remove(0);
+            let name = label.text.trim_end_matches(':').to_string();
+            self.define(&Token { text: name.clone(), ..label.clone() })?;
+            self.labels.insert(name, self.address);
+            if words.is_empty() {
+                return Ok(());
+            }
+        }
+
+        // NAME EQU value, or NAME = value
+        if words.len() > 1 && (words[1].text.eq_ignore_ascii_case("EQU") || words[1].text == "=") {
+            let name = words[0].clone();
+            self.define(&name)?;
+            let value = rest(text, &words[2..]).map_or_else(
+                || error(line, words[1].column, "expected a value".to_string()),
+                |value| parse_expr(&value))?;
+            self.constants.insert(name.text, value);
+            return Ok(());
+        }
+
+        let mnemonic = words.remove(0);
+        let operands = match rest(text, &words) {
+            Some(rest) => split_operands(&rest),
+            None => Vec::new(),
+        };
+
+        match mnemonic.text.to_ascii_uppercase().as_str() {
+            "ORG" => {
+                if operands.len() != 1 {
+                    return mnemonic.error("ORG takes a single address".to_string());
+                }
+                let origin = self.evaluate(&parse_expr(&operands[0])?, 0)?;
+                if origin < PROGRAM_START as i64 || origin > 0xFFFF {
+                    return operands[0].error(format!("origin 0x{:X} is outside of program memory", origin));
+                }
+                self.address = origin as u32;
+            },
+            "DB" | "DW" => {
+                let values = operands.iter().map(parse_expr).collect::<Result<Vec<_>, _>>()?;
+                if values.is_empty() {
+                    return mnemonic.error(format!("{} requires at least one value", mnemonic.text));
+                }
+                let start = self.address;
+                let statement = if mnemonic.text.eq_ignore_ascii_case("DB") {
+                    self.address += values.len() as u32;
+                    Statement::Bytes(values)
+                } else {
+                    self.address += 2 * values.len() as u32;
+                    Statement::Words(values)
+                };
+                self.statements.push((start, statement));
+            },
+            _ => {
+                let operands = operands.into_iter()
+                    .map(|token| parse_operand(&token).map(|operand| (operand, token)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let long = operands.iter().any(|(operand, _)| matches!(*operand, Operand::Long(_)));
+                let start = self.address;
+                self.address += if long { 4 } else { 2 };
+                self.statements.push((start, Statement::Instruction { mnemonic, operands }));
+            },
+        }
+        if self.address > 0x10000 {
+            return error(line, 1, "program extends past the end of memory".to_string());
+        }
+        Ok(())
+    }
+
+    // checks a label or constant name is valid and not already in use
+    fn define(&self, name: &Token) -> Result<(), AsmError> {
+        let valid = name.text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        if !valid || parse_register(&name.text).is_some() {
+            return name.error(format!("'{}' is not a valid name", name.text));
+        }
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return name.error(format!("'{}' is already defined", name.text));
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, expr: &Expr, depth: usize) -> Result<i64, AsmError> {
+        let mut total = 0;
+        for &(sign, ref term) in &expr.terms {
+            let value = match *term {
+                Term::Number(value) => value,
+                Term::Symbol(ref symbol) => {
+                    if let Some(address) = self.labels.get(&symbol.text) {
+                        *address as i64
+                    } else if let Some(constant) = self.constants.get(&symbol.text) {
+                        if depth > 16 {
+                            return symbol.error(format!("'{}' is defined in terms of itself", symbol.text));
+                        }
+                        self.evaluate(constant, depth + 1)?
+                    } else {
+                        return symbol.error(format!("undefined label or constant '{}'", symbol.text));
+                    }
+                },
+            };
+            total += sign * value;
+        }
+        Ok(total)
+    }
+
+    fn value(&self, expr: &Expr, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = self.evaluate(expr, 0)?;
+        // bytes may also be written as negative numbers
+        let min = if max == 0xFF { -0x80 } else { 0 };
+        if value < min || value > max {
+            return expr.token.error(format!("{} {} is out of range", what, value));
+        }
+        Ok(value & max)
+    }
+
+    fn symbols(&self, file: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        // a label at the very end of memory has no address
+        for (name, &address) in self.labels.iter().filter(|&(_, &address)| address <= 0xFFFF) {
+            symbols.add_label(name, address as u16);
+        }
+        for &(address, ref statement) in &self.statements {
+            let line = match *statement {
+                Statement::Instruction { ref mnemonic, .. } => mnemonic.line,
+                Statement::Bytes(ref values) | Statement::Words(ref values) => values[0].token.line,
+            };
+            symbols.add_line(address as u16, SourceLine { file: file.to_string(), line });
+        }
+        symbols
+    }
+
+    fn emit(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for &(address, ref statement) in &self.statements {
+            let bytes = match *statement {
+                Statement::Bytes(ref values) => values.iter()
+                    .map(|value| self.value(value, 0xFF, "byte").map(|b| b as u8))
+                    .collect::<Result<Vec<_>, _>>()?,
+                Statement::Words(ref values) => {
+                    let mut bytes = Vec::new();
+                    for value in values {
+                        let word = self.value(value, 0xFFFF, "word")?;
+                        bytes.push((word >> 8) as u8);
+                        bytes.push(word as u8);
+                    }
+                    bytes
+                },
+                Statement::Instruction { ref mnemonic, ref operands } => {
+                    let (instruction, long) = self.encode(mnemonic, operands)?;
+                    let opcode = instruction.encode();
+                    let mut bytes = vec![(opcode >> 8) as u8, opcode as u8];
+                    if let Some(long) = long {
+                        bytes.push((long >> 8) as u8);
+                        bytes.push(long as u8);
+                    }
+                    bytes
+                },
+            };
+            let start = address as usize - PROGRAM_START;
+            if rom.len() < start + bytes.len() {
+                rom.resize(start + bytes.len(), 0);
+            }
+            rom[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(rom)
+    }
+
+    // the instruction for a mnemonic and its operands, along with the
+    // operand word of LD I, LONG
+    fn encode(&self, mnemonic: &Token, operands: &[(Operand, Token)])
+              -> Result<(Instruction, Option<u16>), AsmError> {
+        use self::Operand::*;
+
+        let name = mnemonic.text.to_ascii_uppercase();
+        let ops: Vec<&Operand> = operands.iter().map(|(operand, _)| operand).collect();
+        let addr = |expr: &Expr| self.value(expr, 0xFFF, "address").map(|a| a as u16);
+        let byte = |expr: &Expr| self.value(expr, 0xFF, "byte").map(|b| b as u8);
+        let nibble = |expr: &Expr| self.value(expr, 0xF, "nibble").map(|n| n as u8);
+
+        let instruction = match (name.as_str(), ops.as_slice()) {
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => Low,
+            ("HIGH", []) => High,
+            ("AUDIO", []) => Audio,
+            ("SCD", [Value(n)]) => ScrollDown(nibble(n)?),
+            ("SCU", [Value(n)]) => ScrollUp(nibble(n)?),
+            ("PLANE", [Value(n)]) => Plane(nibble(n)?),
+            ("JP", [Value(a)]) => Jp(addr(a)?),
+            ("JP", [&V(0), Value(a)]) => JpV0(addr(a)?),
+            ("CALL", [Value(a)]) => Call(addr(a)?),
+            ("SE", [&V(x), &V(y)]) => SeReg(x, y),
+            ("SE", [&V(x), Value(kk)]) => SeByte(x, byte(kk)?),
+            ("SNE", [&V(x), &V(y)]) => SneReg(x, y),
+            ("SNE", [&V(x), Value(kk)]) => SneByte(x, byte(kk)?),
+            ("SAVE", [&V(x), &V(y)]) => SaveRange(x, y),
+            ("LOAD", [&V(x), &V(y)]) => LoadRange(x, y),
+            ("LD", [&V(x), &V(y)]) => LdReg(x, y),
+            ("LD", [&V(x), Value(kk)]) => LdByte(x, byte(kk)?),
+            ("LD", [&I, Value(a)]) => LdI(addr(a)?),
+            ("LD", [&I, Long(a)]) => {
+                let long = self.value(a, 0xFFFF, "address")? as u16;
+                return Ok((LdILong, Some(long)));
+            },
+            ("LD", [&V(x), &Dt]) => LdVxDt(x),
+            ("LD", [&V(x), &K]) => LdVxK(x),
+            ("LD", [&Dt, &V(x)]) => LdDtVx(x),
+            ("LD", [&St, &V(x)]) => LdStVx(x),
+            ("LD", [&F, &V(x)]) => LdFVx(x),
+            ("LD", [&Hf, &V(x)]) => LdHfVx(x),
+            ("LD", [&B, &V(x)]) => LdBVx(x),
+            ("LD", [&IndirectI, &V(x)]) => LdIVx(x),
+            ("LD", [&V(x), &IndirectI]) => LdVxI(x),
+            ("LD", [&R, &V(x)]) => LdRVx(x),
+            ("LD", [&V(x), &R]) => LdVxR(x),
+            ("ADD", [&V(x), &V(y)]) => AddReg(x, y),
+            ("ADD", [&V(x), Value(kk)]) => AddByte(x, byte(kk)?),
+            ("ADD", [&I, &V(x)]) => AddIVx(x),
+            ("OR", [&V(x), &V(y)]) => Or(x, y),
+            ("AND", [&V(x), &V(y)]) => And(x, y),
+            ("XOR", [&V(x), &V(y)]) => Xor(x, y),
+            ("SUB", [&V(x), &V(y)]) => Sub(x, y),
+            ("SUBN", [&V(x), &V(y)]) => Subn(x, y),
+            ("SHR", [&V(x)]) => Shr(x, x),
+            ("SHR", [&V(x), &V(y)]) => Shr(x, y),
+            ("SHL", [&V(x)]) => Shl(x, x),
+            ("SHL", [&V(x), &V(y)]) => Shl(x, y),
+            ("RND", [&V(x), Value(kk)]) => Rnd(x, byte(kk)?),
+            ("DRW", [&V(x), &V(y), Value(n)]) => Drw(x, y, nibble(n)?),
+            ("SKP", [&V(x)]) => Skp(x),
+            ("SKNP", [&V(x)]) => Sknp(x),
+            ("PITCH", [&V(x)]) => Pitch(x),
+            _ => {
+                let known = ["CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD",
+                    "SCU", "PLANE", "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR",
+                    "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "PITCH"];
+                if !known.contains(&name.as_str()) {
+                    return mnemonic.error(format!("unknown instruction '{}'", mnemonic.text));
+                }
+                let token = operands.first().map_or(mnemonic, |(_, token)| token);
+                return token.error(format!("invalid operands for {}", name));
+            },
+        };
+        Ok((instruction, None))
+    }
+}
+
+// whitespace separated words, recording the column each starts at
+fn split_words(line: usize, text: &str) -> Vec<Token> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(index),
+            (Some(from), true) => {
+                words.push(Token { text: text[from..index].to_string(), line, column: from + 1 });
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    words
+}
+
+// the remainder of the line from the first of words onwards
+fn rest(text: &str, words: &[Token]) -> Option<Token> {
+    let first = words.first()?;
+    let text = text[first.column - 1..].trim_end().to_string();
+    Some(Token { text, ..first.clone() })
+}
+
+fn split_operands(token: &Token) -> Vec<Token> {
+    let mut operands = Vec::new();
+    let mut offset = 0;
+    for part in token.text.split(',') {
+        let trimmed = part.trim_start();
+        let column = token.column + offset + (part.len() - trimmed.len());
+        operands.push(Token { text: trimmed.trim_end().to_string(), line: token.line, column });
+        offset += part.len() + 1;
+    }
+    operands
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('v'), Some(digit), None) =>
+            digit.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+fn parse_operand(token: &Token) -> Result<Operand, AsmError> {
+    if let Some(x) = parse_register(&token.text) {
+        return Ok(Operand::V(x));
+    }
+    let operand = match token.text.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        upper if upper.starts_with("LONG ") => {
+            let skip = token.text[4..].len() - token.text[4..].trim_start().len() + 4;
+            let address = Token {
+                text: token.text[skip..].to_string(),
+                column: token.column + skip,
+                ..token.clone()
+            };
+            Operand::Long(parse_expr(&address)?)
+        },
+        _ => Operand::Value(parse_expr(token)?),
+    };
+    Ok(operand)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// a sum of numbers and symbols, e.g. sprite + 2 or -1
+fn parse_expr(token: &Token) -> Result<Expr, AsmError> {
+    let mut terms = Vec::new();
+    let mut sign = 1;
+    let mut expect_term = true;
+    let mut chars = token.text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let column = token.column + index;
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '+' || c == '-' {
+            if !expect_term {
+                sign = if c == '-' { -1 } else { 1 };
+                expect_term = true;
+                continue;
+            }
+            if c == '-' && terms.is_empty() && sign == 1 {
+                sign = -1;
+                continue;
+            }
+            return error(token.line, column, format!("unexpected '{}'", c));
+        }
+        if !expect_term {
+            return error(token.line, column, "expected '+' or '-'".to_string());
+        }
+        let mut end = index + c.len_utf8();
+        while let Some(&(next, c)) = chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                end = next + c.len_utf8();
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let text = &token.text[index..end];
+        let term = if c.is_ascii_digit() {
+            match parse_number(text) {
+                Some(value) => Term::Number(value),
+                None => return error(token.line, column, format!("invalid number '{}'", text)),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            Term::Symbol(Token { text: text.to_string(), line: token.line, column })
+        } else {
+            return error(token.line, column, format!("unexpected '{}'", c));
+        };
+        terms.push((sign, term));
+        sign = 1;
+        expect_term = false;
+    }
+    if expect_term {
+        let column = token.column + token.text.len();
+        return error(token.line, column, "expected a value".to_string());
+    }
+    Ok(Expr { terms, token: token.clone() })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, assemble_with_symbols, AsmError};
+    use cpu::Cpu;
+    use disasm::{disassemble, Syntax};
+
+    fn error(source: &str) -> AsmError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn assemble_instructions() {
+        let rom = assemble("
+            CLS
+            LD V0, 0x12     ; comment
+            ld va, 10
+            DRW V0, V1, 5
+            LD [I], VF
+            LD I, LONG 0x1234
+            SHR V3
+        ").unwrap();
+
+        assert_eq!(rom, vec![0x00, 0xE0, 0x60, 0x12, 0x6A, 0x0A, 0xD0, 0x15, 0xFF, 0x55,
+            0xF0, 0x00, 0x12, 0x34, 0x83, 0x36]);
+    }
+
+    #[test]
+    fn labels_and_forward_references() {
+        let rom = assemble("
+            start:  JP end
+            loop:   JP loop
+            end:    CALL start
+        ").unwrap();
+
+        assert_eq!(rom, vec![0x12, 0x04, 0x12, 0x02, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn symbols() {
+        let (rom, symbols) = assemble_with_symbols("\
+start:  JP end
+
+loop:   JP loop
+sprite: DB 0x80, 0x40
+        DW 0x2010
+end:    CALL start", "test.asm").unwrap();
+
+        assert_eq!(rom.len(), 10);
+        assert_eq!(symbols.to_string(), "\
+start = 0x200
+loop = 0x202
+sprite = 0x204
+end = 0x208
+0x200 = test.asm:1
+0x202 = test.asm:3
+0x204 = test.asm:4
+0x206 = test.asm:5
+0x208 = test.asm:6
+");
+    }
+
+    #[test]
+    fn symbols_for_labels_sharing_an_address() {
+        let (_, symbols) = assemble_with_symbols("main:\nloop:\n  JP loop\n", "test.asm").unwrap();
+        assert_eq!(symbols.resolve("main"), Some(0x200));
+        assert_eq!(symbols.resolve("loop"), Some(0x200));
+        assert_eq!(symbols.label(0x200), Some("loop"));
+    }
+
+    #[test]
+    fn data_directives_and_constants() {
+        let rom = assemble("
+            HEIGHT EQU 2
+            OFFSET = HEIGHT + 1
+                    LD I, sprite + OFFSET
+                    DRW V0, V0, HEIGHT
+            sprite: DB 0b10000000, 0x40, -1
+                    DW 0x1234
+        ").unwrap();
+
+        assert_eq!(rom, vec![0xA2, 0x07, 0xD0, 0x02, 0x80, 0x40, 0xFF, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn org() {
+        let rom = assemble("
+                    JP main
+                    ORG 0x206
+            main:   RET
+        ").unwrap();
+
+        assert_eq!(rom, vec![0x12, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn errors_carry_line_and_column() {
+        assert_eq!(error("CLS\n  FOO V0"), AsmError {
+            line: 2, column: 3, message: "unknown instruction 'FOO'".to_string() });
+        assert_eq!(error("LD V0, 0x100"), AsmError {
+            line: 1, column: 8, message: "byte 256 is out of range".to_string() });
+        assert_eq!(error("  JP nowhere"), AsmError {
+            line: 1, column: 6, message: "undefined label or constant 'nowhere'".to_string() });
+        assert_eq!(error("SE V0, I"), AsmError {
+            line: 1, column: 4, message: "invalid operands for SE".to_string() });
+        assert_eq!(error("a: CLS\na: CLS").message, "'a' is already defined");
+        assert_eq!(error("ORG 0x100").message, "origin 0x100 is outside of program memory");
+        assert_eq!(error("DB 1 +").column, 7);
+    }
+
+    #[test]
+    fn assembled_program_runs() {
+        let rom = assemble("
+                    LD V0, 0
+            loop:   ADD V0, 1
+                    SE V0, 5
+                    JP loop
+            done:   JP done
+        ").unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        for _ in 0..20 {
+            cpu.execute_cycle().unwrap();
+        }
+        assert_eq!(cpu.v[0], 5);
+        assert_eq!(cpu.pc, 0x208);
+    }
+
+    #[test]
+    fn round_trip_bundled_roms() {
+        let roms: [&[u8]; 4] = [
+            include_bytes!("../web/roms/BRIX"),
+            include_bytes!("../web/roms/INVADERS"),
+            include_bytes!("../web/roms/TETRIS"),
+            include_bytes!("../web/roms/MAZE"),
+        ];
+        for rom in roms.iter() {
+            let mut source = String::new();
+            for line in disassemble(rom, 0x200, Syntax::Cowgod) {
+                if let Some(label) = line.label {
+                    source += &format!("{}:\n", label);
+                }
+                source += &format!("    {}\n", line.text);
+            }
+            assert_eq!(&assemble(&source).unwrap()[..], *rom);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+// Runs a ROM without a display, for use from a shell or CI:
+//
+//     chip8 ROM [--platform vip|chip48|schip|xo] [--frames N] [--instructions N]
+//               [--ipf N] [--timing ipf|vip] [--key K@FRAME[-FRAME]]... [--pbm FILE]
+//               [--png FILE] [--trace FILE] [--profile FILE] [--chrome-trace FILE]
+//               [--coverage FILE] [--heatmap FILE] [--listing FILE] [--symbols FILE]
+//               [--romdb FILE] [--gdb PORT]
+//
+// The ROM runs for the given number of 60 Hz frames, or instructions,
+// whichever ends first, executing --ipf instructions per frame, or with
+// --timing vip as many as the COSMAC VIP would in the time. Each --key
+// holds the hex key K down from the first frame up to, but not including, the
+// second, which defaults to the frame after. The final display is written to
+// the image files and the registers are dumped to stdout. The exit status is
+// 1 if the CPU faults and 2 if the arguments or files are bad.
+//
+// With --trace every instruction executed is written to FILE, one line each
+// in the format of trace::format_event, for comparison with tracediff.
+// --profile writes a report of where the time went, the hottest addresses and
+// instructions, and --chrome-trace the same per frame for a trace viewer.
+// --coverage writes which of the ROM's bytes were executed, read or written,
+// and the code never executed, --heatmap the same for all of memory as an
+// image, and --listing a disassembly with the code and data separated.
+// A symbol file given by --symbols names addresses in the trace, the profile,
+// the listing and the debugger.
+//
+// A ROM found in the ROM database, by its SHA-1, runs on the platform, with
+// the quirks and instructions per frame, recommended there unless --platform
+// or --ipf say otherwise, and its title and keys are printed to stderr. The
+// ROMs in web/roms are built in, and --romdb adds those in FILE, which take
+// precedence, in the format of romdb::Database.
+//
+// With --gdb the ROM is instead run under the control of a GDB remote
+// protocol client, which connects to 127.0.0.1:PORT, until it detaches. It
+// runs at the speed --ipf or --timing set, as it would without.
+extern crate hello_rust;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use hello_rust::coverage::Coverage;
+use hello_rust::cpu::{Cpu, PROGRAM_START};
+use hello_rust::debugger::Debugger;
+use hello_rust::disasm::{self, Syntax};
+use hello_rust::gdb;
+use hello_rust::image;
+use hello_rust::machine::{Machine, Timing};
+use hello_rust::quirks::Platform;
+use hello_rust::romdb::Database;
+use hello_rust::symbols::Symbols;
+use hello_rust::profile::Profiler;
+use hello_rust::trace::{TraceWriter, Tracer};
+
+// a key held down for the frames [start, end)
+struct KeyPress {
+    key: u8,
+    start: u64,
+    end: u64,
+}
+
+struct Options {
+    rom: String,
+    platform: Option<Platform>,
+    frames: Option<u64>,
+    instructions: Option<u64>,
+    instructions_per_frame: Option<u64>,
+    timing: Timing,
+    keys: Vec<KeyPress>,
+    pbm: Option<String>,
+    png: Option<String>,
+    trace: Option<String>,
+    profile: Option<String>,
+    chrome_trace: Option<String>,
+    coverage: Option<String>,
+    heatmap: Option<String>,
+    listing: Option<String>,
+    symbols: Option<String>,
+    romdb: Option<String>,
+    gdb: Option<u16>,
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("chip8: {}", message);
+    eprintln!("usage: chip8 ROM [--platform vip|chip48|schip|xo] [--frames N] [--instructions N]");
+    eprintln!("                 [--ipf N] [--timing ipf|vip] [--key K@FRAME[-FRAME]]... [--pbm FILE]");
+    eprintln!("                 [--png FILE] [--trace FILE] [--profile FILE] [--chrome-trace FILE]");
+    eprintln!("                 [--coverage FILE] [--heatmap FILE] [--listing FILE] [--symbols FILE]");
+    eprintln!("                 [--romdb FILE] [--gdb PORT]");
+    process::exit(2);
+}
+
+fn parse_number(option: &str, value: &str) -> u64 {
+    value.parse().unwrap_or_else(|_| usage(&format!("invalid number '{}' for {}", value, option)))
+}
+
+fn parse_key(value: &str) -> Option<KeyPress> {
+    let mut parts = value.splitn(2, '@');
+    let key = u8::from_str_radix(parts.next()?, 16).ok().filter(|&key| key < 16)?;
+    let mut frames = parts.next()?.splitn(2, '-');
+    let start = frames.next()?.parse().ok()?;
+    let end = match frames.next() {
+        Some(end) => end.parse().ok().filter(|&end| end > start)?,
+        None => start + 1,
+    };
+    Some(KeyPress { key, start, end })
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        rom: String::new(),
+        platform: None,
+        frames: None,
+        instructions: None,
+        instructions_per_frame: None,
+        timing: Timing::Instructions,
+        keys: Vec::new(),
+        pbm: None,
+        png: None,
+        trace: None,
+        profile: None,
+        chrome_trace: None,
+        coverage: None,
+        heatmap: None,
+        listing: None,
+        symbols: None,
+        romdb: None,
+        gdb: None,
+    };
+    let mut rom = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if rom.is_some() {
+                usage(&format!("unexpected argument '{}'", arg));
+            }
+            rom = Some(arg.clone());
+            continue;
+        }
+        let value = args.next().unwrap_or_else(|| usage(&format!("{} requires a value", arg)));
+        match arg.as_str() {
+            "--platform" => options.platform = Some(Platform::from_name(value).unwrap_or_else(||
+                usage(&format!("unknown platform '{}'", value)))),
+            "--frames" => options.frames = Some(parse_number(arg, value)),
+            "--instructions" => options.instructions = Some(parse_number(arg, value)),
+            "--ipf" => options.instructions_per_frame = match parse_number(arg, value) {
+                0 => usage("--ipf must be at least 1"),
+                instructions => Some(instructions),
+            },
+            "--timing" => options.timing = match value.as_str() {
+                "ipf" => Timing::Instructions,
+                "vip" => Timing::CosmacVip,
+                _ => usage(&format!("unknown timing '{}'", value)),
+            },
+            "--key" => options.keys.push(parse_key(value).unwrap_or_else(||
+                usage(&format!("invalid key press '{}', expected K@FRAME[-FRAME]", value)))),
+            "--pbm" => options.pbm = Some(value.clone()),
+            "--png" => options.png = Some(value.clone()),
+            "--trace" => options.trace = Some(value.clone()),
+            "--profile" => options.profile = Some(value.clone()),
+            "--chrome-trace" => options.chrome_trace = Some(value.clone()),
+            "--coverage" => options.coverage = Some(value.clone()),
+            "--heatmap" => options.heatmap = Some(value.clone()),
+            "--listing" => options.listing = Some(value.clone()),
+            "--symbols" => options.symbols = Some(value.clone()),
+            "--romdb" => options.romdb = Some(value.clone()),
+            "--gdb" => options.gdb = Some(value.parse().unwrap_or_else(|_|
+                usage(&format!("invalid port '{}' for --gdb", value)))),
+            _ => usage(&format!("unknown option '{}'", arg)),
+        }
+    }
+    options.rom = rom.unwrap_or_else(|| usage("no ROM given"));
+    if options.frames.is_none() && options.instructions.is_none() {
+        options.frames = Some(60);
+    }
+    options
+}
+
+fn register_dump(cpu: &Cpu) -> String {
+    let mut out = String::new();
+    for (x, v) in cpu.v.iter().enumerate() {
+        out += &format!("V{:X}={:02X}{}", x, v, if x % 8 == 7 { "\n" } else { " " });
+    }
+    out += &format!("I={:04X} PC={:04X} SP={:X} DT={:02X} ST={:02X}\n", cpu.i, cpu.pc, cpu.sp, cpu.dt, cpu.st);
+    let stack: Vec<String> = cpu.stack[..cpu.sp as usize].iter().map(|a| format!("{:04X}", a)).collect();
+    out += &format!("stack=[{}]\n", stack.join(" "));
+    out
+}
+
+fn write(path: &str, bytes: &[u8]) {
+    if let Err(error) = fs::write(path, bytes) {
+        eprintln!("chip8: cannot write {}: {}", path, error);
+        process::exit(2);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args);
+
+    let rom = fs::read(&options.rom).unwrap_or_else(|error| {
+        eprintln!("chip8: cannot read {}: {}", options.rom, error);
+        process::exit(2);
+    });
+
+    let symbols = match options.symbols {
+        Some(ref path) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|error| {
+                eprintln!("chip8: cannot read {}: {}", path, error);
+                process::exit(2);
+            });
+            Symbols::parse(&text).unwrap_or_else(|error| {
+                eprintln!("chip8: {}:{}", path, error);
+                process::exit(2);
+            })
+        },
+        None => Symbols::new(),
+    };
+
+    let mut database = Database::builtin();
+    if let Some(ref path) = options.romdb {
+        let text = fs::read_to_string(path).unwrap_or_else(|error| {
+            eprintln!("chip8: cannot read {}: {}", path, error);
+            process::exit(2);
+        });
+        match Database::parse(&text) {
+            Ok(other) => database.merge(&other),
+            Err(error) => {
+                eprintln!("chip8: {}:{}", path, error);
+                process::exit(2);
+            },
+        }
+    }
+    let info = database.lookup(&rom);
+    if let Some(info) = info {
+        eprintln!("chip8: {}; keys: {}", info.title, if info.keys.is_empty() { "unknown" } else { &info.keys });
+    }
+
+    let mut cpu = Cpu::new();
+    match (options.platform, info) {
+        (Some(platform), _) => cpu.set_platform(platform),
+        (None, Some(info)) => {
+            cpu.set_platform(info.platform);
+            cpu.quirks = info.quirks;
+        },
+        (None, None) => {},
+    }
+    let instructions_per_frame = options.instructions_per_frame
+        .unwrap_or_else(|| info.map_or(10, |info| info.instructions_per_frame as u64)) as u32;
+    if let Err(error) = cpu.load_rom(&rom) {
+        eprintln!("chip8: cannot load {}: {}", options.rom, error);
+        process::exit(2);
+    }
+
+    let tracer = options.trace.as_ref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|error| {
+            eprintln!("chip8: cannot write {}: {}", path, error);
+            process::exit(2);
+        });
+        Arc::new(Mutex::new(TraceWriter::with_symbols(BufWriter::new(file), symbols.clone())))
+    });
+    let profiler = if options.profile.is_some() || options.chrome_trace.is_some() {
+        Some(Arc::new(Mutex::new(Profiler::new())))
+    } else {
+        None
+    };
+    let coverage = if options.coverage.is_some() || options.heatmap.is_some() || options.listing.is_some() {
+        Some(Arc::new(Mutex::new(Coverage::new())))
+    } else {
+        None
+    };
+    let mut tracers: Vec<Box<dyn Tracer + Send>> = Vec::new();
+    if let Some(ref tracer) = tracer {
+        tracers.push(Box::new(tracer.clone()));
+    }
+    if let Some(ref profiler) = profiler {
+        tracers.push(Box::new(profiler.clone()));
+    }
+    if let Some(ref coverage) = coverage {
+        tracers.push(Box::new(coverage.clone()));
+    }
+    if !tracers.is_empty() {
+        cpu.tracer = Some(Box::new(tracers));
+    }
+
+    let mut fault = None;
+    let mut executed = 0;
+    let mut frame = 0;
+    let mut machine = Machine::new(cpu);
+    machine.instructions_per_frame = instructions_per_frame;
+    machine.timing = options.timing;
+    if let Some(port) = options.gdb {
+        let mut debugger = Debugger::new(machine);
+        debugger.symbols = symbols.clone();
+        let address = format!("127.0.0.1:{}", port);
+        eprintln!("chip8: waiting for a debugger on {}", address);
+        if let Err(error) = gdb::listen(&address, &mut debugger) {
+            eprintln!("chip8: debugger session failed: {}", error);
+            process::exit(2);
+        }
+        machine = debugger.machine;
+        frame = machine.cpu.frame;
+    }
+    'run: while options.gdb.is_none() && options.frames.is_none_or(|frames| frame < frames) {
+        for press in &options.keys {
+            if frame >= press.start && frame < press.end {
+                machine.cpu.keypad.key_down(press.key);
+            } else if frame == press.end {
+                machine.cpu.keypad.key_up(press.key);
+            }
+        }
+        loop {
+            if options.instructions.is_some_and(|instructions| executed >= instructions) {
+                break 'run;
+            }
+            match machine.step() {
+                Ok(ended) => {
+                    executed += 1;
+                    if ended {
+                        break;
+                    }
+                },
+                Err(error) => {
+                    fault = Some(error);
+                    break 'run;
+                },
+            }
+        }
+        frame += 1;
+    }
+    cpu = machine.cpu;
+
+    cpu.tracer = None;
+    if let (Some(tracer), Some(path)) = (tracer, options.trace.as_ref()) {
+        if let Err(error) = tracer.lock().unwrap().flush() {
+            eprintln!("chip8: cannot write {}: {}", path, error);
+            process::exit(2);
+        }
+    }
+    if let Some(profiler) = profiler {
+        let profiler = profiler.lock().unwrap();
+        if let Some(ref path) = options.profile {
+            write(path, profiler.report(20, &symbols).as_bytes());
+        }
+        if let Some(ref path) = options.chrome_trace {
+            write(path, profiler.chrome_trace(&symbols).as_bytes());
+        }
+    }
+    if let Some(coverage) = coverage {
+        let coverage = coverage.lock().unwrap();
+        if let Some(ref path) = options.coverage {
+            let range = PROGRAM_START..PROGRAM_START + rom.len();
+            write(path, (coverage.report(&rom, PROGRAM_START as u16) + "\nmemory:\n" + &coverage.map(range)).as_bytes());
+        }
+        if let Some(ref path) = options.heatmap {
+            write(path, &coverage.heatmap(cpu.memory_size()));
+        }
+        if let Some(ref path) = options.listing {
+            let lines = coverage.disassemble(&rom, PROGRAM_START as u16, Syntax::Cowgod, &symbols);
+            write(path, disasm::listing(&lines, Syntax::Cowgod).as_bytes());
+        }
+    }
+    if let Some(ref path) = options.pbm {
+        write(path, &image::pbm(&cpu.display));
+    }
+    if let Some(ref path) = options.png {
+        write(path, &image::display_png(&cpu.display));
+    }
+    print!("{}", register_dump(&cpu));
+    println!("frames={} instructions={}", frame, executed);
+
+    if let Some(error) = fault {
+        match symbols.location(cpu.pc) {
+            Some(location) => eprintln!("chip8: {} ({})", error, location),
+            None => eprintln!("chip8: {}", error),
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,55 @@
+// Compares two traces written by chip8 --trace, or by another emulator in the
+// same format, and reports the first line at which they differ:
+//
+//     tracediff EXPECTED ACTUAL [--context N]
+//
+// The lines shared before the divergence are printed for context, 5 unless
+// given. The exit status is 0 if the traces match, 1 if they differ and 2 if
+// the arguments or files are bad.
+extern crate hello_rust;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use hello_rust::trace;
+
+fn usage(message: &str) -> ! {
+    eprintln!("tracediff: {}", message);
+    eprintln!("usage: tracediff EXPECTED ACTUAL [--context N]");
+    process::exit(2);
+}
+
+fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("tracediff: cannot read {}: {}", path, error);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let mut paths = Vec::new();
+    let mut context = 5;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--context" {
+            let value = args.next().unwrap_or_else(|| usage("--context requires a value"));
+            context = value.parse().unwrap_or_else(|_| usage(&format!("invalid number '{}' for --context", value)));
+        } else if arg.starts_with("--") {
+            usage(&format!("unknown option '{}'", arg));
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.len() != 2 {
+        usage("expected two traces");
+    }
+
+    match trace::diff(&read(&paths[0]), &read(&paths[1]), context) {
+        Some(divergence) => {
+            print!("{}", divergence);
+            process::exit(1);
+        },
+        None => println!("traces match"),
+    }
+}
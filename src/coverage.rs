@@ -0,0 +1,271 @@
+use std::ops::Range;
+
+use cpu::{Cpu, MemoryAccess, MEMORY_SIZE};
+use disasm::{self, Region, Syntax};
+use image;
+use instruction::Instruction;
+use symbols::Symbols;
+use trace::{TraceEvent, Tracer};
+
+// the width in bytes, and pixels, of each row of the heatmap
+const HEATMAP_WIDTH: usize = 64;
+
+// how a byte of memory was used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    // fetched as part of an instruction
+    pub executed: bool,
+    // read or written as data, through I
+    pub read: bool,
+    pub written: bool,
+}
+
+impl Usage {
+    pub fn is_data(&self) -> bool {
+        !self.executed && (self.read || self.written)
+    }
+
+    // e.g. "x--" for code, "-rw" for a byte read and written
+    fn flags(&self) -> String {
+        [(self.executed, 'x'), (self.read, 'r'), (self.written, 'w')].iter()
+            .map(|&(set, flag)| if set { flag } else { '-' })
+            .collect()
+    }
+}
+
+// Records, as a Tracer, which memory is executed as code, read as sprites or
+// other data, and written by Fx33, Fx55 and the like. Faulting instructions
+// are not recorded.
+pub struct Coverage {
+    // the times an instruction started at each address, and each address
+    // was read or written
+    starts: Vec<u32>,
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    // every byte of the executed instructions
+    code: Vec<bool>,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            starts: vec![0; MEMORY_SIZE],
+            reads: vec![0; MEMORY_SIZE],
+            writes: vec![0; MEMORY_SIZE],
+            code: vec![false; MEMORY_SIZE],
+        }
+    }
+
+    pub fn usage(&self, address: usize) -> Usage {
+        Usage { executed: self.code[address], read: self.reads[address] > 0, written: self.writes[address] > 0 }
+    }
+
+    // the number of times an instruction starting at the address executed
+    pub fn executions(&self, address: usize) -> u32 {
+        self.starts[address]
+    }
+
+    // what is known of the address, for disasm::disassemble_with
+    pub fn region(&self, address: u16) -> Region {
+        let address = address as usize;
+        if self.starts[address] > 0 {
+            Region::Code
+        } else if self.usage(address).is_data() {
+            Region::Data
+        } else {
+            Region::Unknown
+        }
+    }
+
+    // disassembles memory loaded at origin with code separated from data
+    pub fn disassemble(&self, bytes: &[u8], origin: u16, syntax: Syntax, symbols: &Symbols) -> Vec<disasm::Line> {
+        disasm::disassemble_with(bytes, origin, syntax, &|address| self.region(address), symbols)
+    }
+
+    // The usage of each address in range, as lines of "start-end flags" for
+    // each run of addresses used alike, e.g. "0200-0229 x--". Unused memory
+    // is left out.
+    pub fn map(&self, range: Range<usize>) -> String {
+        let mut out = String::new();
+        let mut run: Option<(usize, Usage)> = None;
+        for address in range.start..range.end + 1 {
+            let usage = if address < range.end { self.usage(address) } else { Usage::default() };
+            if let Some((start, previous)) = run {
+                if previous == usage {
+                    continue;
+                }
+                if previous != Usage::default() {
+                    out += &format!("{:04X}-{:04X} {}\n", start, address - 1, previous.flags());
+                }
+            }
+            run = Some((address, usage));
+        }
+        out
+    }
+
+    // the instructions in the sweep of a ROM loaded at origin which never
+    // executed, as ranges of consecutive instructions
+    pub fn unexecuted(&self, bytes: &[u8], origin: u16) -> Vec<Range<u16>> {
+        let mut ranges: Vec<Range<u16>> = Vec::new();
+        for line in self.disassemble(bytes, origin, Syntax::Cowgod, &Symbols::new()) {
+            if line.instruction.is_none() || self.starts[line.address as usize] > 0 {
+                continue;
+            }
+            let end = line.address + line.bytes.len() as u16;
+            match ranges.last_mut() {
+                Some(range) if range.end == line.address => range.end = end,
+                _ => ranges.push(line.address..end),
+            }
+        }
+        ranges
+    }
+
+    // a summary of a ROM's coverage, listing the code which never executed
+    pub fn report(&self, bytes: &[u8], origin: u16) -> String {
+        let range = origin as usize..origin as usize + bytes.len();
+        let count = |f: &dyn Fn(Usage) -> bool| range.clone().filter(|&address| f(self.usage(address))).count();
+        let unexecuted = self.unexecuted(bytes, origin);
+        let mut out = format!("{} bytes: {} executed, {} data, {} unused\n", bytes.len(),
+                              count(&|usage| usage.executed), count(&|usage| usage.is_data()),
+                              count(&|usage| usage == Usage::default()));
+        out += &format!("unexecuted code: {} bytes\n",
+                        unexecuted.iter().map(|range| range.len()).sum::<usize>());
+        for range in &unexecuted {
+            out += &format!("  {:04X}-{:04X}\n", range.start, range.end - 1);
+        }
+        out
+    }
+
+    // A PNG of the first size bytes of memory, a pixel each in rows of 64.
+    // Code is green, reads blue and writes red, brighter the more often used.
+    pub fn heatmap(&self, size: usize) -> Vec<u8> {
+        let size = size.min(MEMORY_SIZE);
+        let channel = |counts: &[u32]| {
+            let max = counts[..size].iter().cloned().max().unwrap_or(0).max(2) as f64;
+            counts[..size].iter()
+                .map(|&count| if count == 0 { 0 } else { (64.0 + 191.0 * (count as f64).ln() / max.ln()) as u8 })
+                .collect::<Vec<u8>>()
+        };
+        // every byte of an instruction is as hot as its start
+        let mut executed = vec![0; size];
+        for address in 0..size {
+            if self.code[address] {
+                executed[address] = if self.starts[address] > 0 { self.starts[address] } else { executed[address - 1] };
+            }
+        }
+        let (red, green, blue) = (channel(&self.writes), channel(&executed), channel(&self.reads));
+        let rows = size.div_ceil(HEATMAP_WIDTH);
+        let pixels: Vec<[u8; 3]> = (0..rows * HEATMAP_WIDTH).map(|address| {
+            if address >= size {
+                [0, 0, 0]
+            } else if red[address] == 0 && green[address] == 0 && blue[address] == 0 {
+                [24, 24, 24]
+            } else {
+                [red[address], green[address], blue[address]]
+            }
+        }).collect();
+        image::png(HEATMAP_WIDTH, rows, &pixels)
+    }
+}
+
+impl Tracer for Coverage {
+    fn trace(&mut self, event: &TraceEvent, _cpu: &Cpu) {
+        if event.fault.is_some() {
+            return;
+        }
+        let pc = event.pc as usize;
+        let size = Instruction::decode(event.opcode).map_or(2, |instruction| instruction.size() as usize);
+        self.starts[pc] = self.starts[pc].saturating_add(1);
+        for address in pc..(pc + size).min(MEMORY_SIZE) {
+            self.code[address] = true;
+        }
+        let (counts, range) = match event.access {
+            Some(MemoryAccess::Read(ref range)) => (&mut self.reads, range),
+            Some(MemoryAccess::Write(ref range)) => (&mut self.writes, range),
+            None => return,
+        };
+        for count in counts[range.start..range.end.min(MEMORY_SIZE)].iter_mut() {
+            *count = count.saturating_add(1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Coverage, Usage};
+    use cpu::Cpu;
+    use disasm::Syntax;
+    use symbols::Symbols;
+    use trace::run_traced;
+
+    fn coverage(program: &[u8], cycles: usize) -> Coverage {
+        run_traced(&mut Cpu::new(), program, 1, cycles, Coverage::new())
+    }
+
+    // draws a sprite, stores a digit, and skips over code never executed
+    const PROGRAM: [u8; 20] = [
+        0xA2, 0x0E, // 200: LD I, 0x20E
+        0xD0, 0x13, // 202: DRW V0, V1, 3
+        0xA3, 0x00, // 204: LD I, 0x300
+        0xF0, 0x33, // 206: LD B, V0
+        0x12, 0x0C, // 208: JP 0x20C
+        0x60, 0x01, // 20A: LD V0, 1 (unexecuted)
+        0x12, 0x0C, // 20C: JP 0x20C
+        0x3C, 0x42, // 20E: sprite data, which decodes as SE VC, 0x42
+        0xFF, 0x61, // 210: more data, then LD V1, 0x00
+        0x00,       // 212:
+        0x12,       // 213: JP 0x213, unexecuted and misaligned
+    ];
+
+    #[test]
+    fn classifies_memory() {
+        let coverage = coverage(&PROGRAM, 8);
+        assert_eq!(coverage.usage(0x200), Usage { executed: true, read: false, written: false });
+        assert_eq!(coverage.usage(0x20B), Usage::default());
+        assert_eq!(coverage.executions(0x20C), 3);
+        assert!(coverage.usage(0x20F).is_data());
+        assert_eq!(coverage.usage(0x302), Usage { executed: false, read: false, written: true });
+
+        assert_eq!(coverage.map(0x200..0x400), "\
+0200-0209 x--
+020C-020D x--
+020E-0210 -r-
+0300-0302 --w
+");
+    }
+
+    #[test]
+    fn separates_code_from_data() {
+        let coverage = coverage(&PROGRAM, 8);
+        let lines = coverage.disassemble(&PROGRAM, 0x200, Syntax::Cowgod, &Symbols::new());
+        let text: Vec<String> = lines.iter().map(|line| format!("{:04X} {}", line.address, line.text)).collect();
+        assert_eq!(text, vec![
+            "0200 LD I, L20E", "0202 DRW V0, V1, 3", "0204 LD I, 0x300", "0206 LD B, V0", "0208 JP L20C",
+            "020A LD V0, 0x01", "020C JP L20C", "020E DB 0x3C, 0x42", "0210 DB 0xFF", "0211 LD V1, 0x00",
+            "0213 DB 0x12",
+        ]);
+
+        assert_eq!(coverage.unexecuted(&PROGRAM, 0x200), vec![0x20A..0x20C, 0x211..0x213]);
+        assert_eq!(coverage.report(&PROGRAM, 0x200), "\
+20 bytes: 12 executed, 3 data, 5 unused
+unexecuted code: 4 bytes
+  020A-020B
+  0211-0212
+");
+    }
+
+    #[test]
+    fn draws_heatmap() {
+        let png = coverage(&PROGRAM, 8).heatmap(0x1000);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // 64 x 64 pixels
+        assert_eq!(&png[16..24], &[0, 0, 0, 64, 0, 0, 0, 64]);
+    }
+}
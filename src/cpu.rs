@@ -1,14 +1,98 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
 use keypad::Keypad;
-use display::{Display, FONT_SET};
+use display::{Display, BIG_FONT_SET, FONT_SET};
+use instruction::Instruction;
+use quirks::{Platform, Quirks};
 use rand::ComplementaryMultiplyWithCarryGen;
+use trace::{Registers, TraceEvent, Tracer};
+
+// faults raised while executing an instruction, each carrying the address
+// of the offending instruction. The CPU state is left untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    // CALL with all 16 stack frames in use
+    StackOverflow { pc: u16 },
+    // RET with an empty stack
+    StackUnderflow { pc: u16 },
+    // an instruction accessed memory beyond the end of the address space
+    MemoryOutOfBounds { pc: u16, address: usize },
+    // the program counter does not point at a complete instruction
+    PcOutOfRange { pc: u16 },
+    // the opcode is not part of the instruction set
+    UnknownOpcode { pc: u16, opcode: u16 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::StackOverflow { pc } =>
+                write!(f, "stack overflow at 0x{:04X}", pc),
+            CpuError::StackUnderflow { pc } =>
+                write!(f, "stack underflow at 0x{:04X}", pc),
+            CpuError::MemoryOutOfBounds { pc, address } =>
+                write!(f, "out of bounds memory access to 0x{:04X} at 0x{:04X}", address, pc),
+            CpuError::PcOutOfRange { pc } =>
+                write!(f, "program counter out of range at 0x{:04X}", pc),
+            CpuError::UnknownOpcode { pc, opcode } =>
+                write!(f, "unknown opcode 0x{:04X} at 0x{:04X}", opcode, pc),
+        }
+    }
+}
+
+impl CpuError {
+    // the address of the instruction which faulted
+    pub fn pc(&self) -> u16 {
+        match *self {
+            CpuError::StackOverflow { pc } |
+            CpuError::StackUnderflow { pc } |
+            CpuError::MemoryOutOfBounds { pc, .. } |
+            CpuError::PcOutOfRange { pc } |
+            CpuError::UnknownOpcode { pc, .. } => pc,
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+// reasons a ROM cannot be loaded by Cpu::load_rom
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Empty,
+    // the ROM is larger than the memory from PROGRAM_START to the end of the
+    // address space of the active platform
+    TooLarge { size: usize, available: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Empty => write!(f, "ROM is empty"),
+            LoadError::TooLarge { size, available } =>
+                write!(f, "ROM is {} bytes, but only {} bytes fit in memory", size, available),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+// the data memory read or written by an instruction, not including the
+// fetch of the instruction itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read(Range<usize>),
+    Write(Range<usize>),
+}
 
 pub struct Cpu {
     // index register
     pub i: u16,
     // program counter
     pub pc: u16,
-    // memory
-    pub memory: [u8; 4096],
+    // memory, sized for XO-CHIP but limited to 4 KiB on the other platforms
+    pub memory: [u8; MEMORY_SIZE],
     // registers
     pub v: [u8; 16],
     // th
@@ -21,212 +105,510 @@ pub struct Cpu {
     pub sp: u8,
     // delay timer
     pub dt: u8,
+    // sound timer, the buzzer sounds while this is non-zero
+    pub st: u8,
+    // SUPER-CHIP RPL user flags, persisted by Fx75 / Fx85
+    pub rpl: [u8; 16],
+    // set by the SUPER-CHIP 00FD exit instruction
+    pub halted: bool,
     // random number generator. Bit yucky
-    pub rand: ComplementaryMultiplyWithCarryGen
+    pub rand: ComplementaryMultiplyWithCarryGen,
+    // interpreter behaviours which vary between platforms
+    pub quirks: Quirks,
+    // enables the XO-CHIP instructions and the full 64 KiB address space
+    pub xo_chip: bool,
+    // XO-CHIP 1-bit audio pattern, played while the sound timer is non-zero
+    pub audio_pattern: [u8; 16],
+    // XO-CHIP audio pitch, the pattern plays at 4000 * 2 ^ ((pitch - 64) / 48) Hz
+    pub pitch: u8,
+    // the number of 60 Hz frames completed since reset, counted by decrement_timers
+    pub frame: u64,
+    // called after every instruction execute_cycle runs, or attempts to
+    pub tracer: Option<Box<dyn Tracer + Send>>
 }
 
-fn read_word(memory: [u8; 4096], index: u16) -> u16 {
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// where ROMs are loaded, and execution starts
+pub const PROGRAM_START: usize = 0x200;
+
+// the SUPER-CHIP big font follows the standard font in memory
+const BIG_FONT_ADDRESS: usize = 80;
+
+fn read_word(memory: &[u8], index: u16) -> u16 {
     (memory[index as usize] as u16) << 8
         | (memory[(index + 1) as usize] as u16)
 }
 
+// the registers Vx to Vy inclusive, in descending order if x > y
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y {
+        (x..y + 1).collect()
+    } else {
+        (y..x + 1).rev().collect()
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
 impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
             i: 0,
             pc: 0,
-            memory: [0; 4096],
+            memory: [0; MEMORY_SIZE],
             v: [0; 16],
             display: Display::new(),
             keypad: Keypad::new(),
             stack: [0; 16],
             sp: 0,
             dt: 0,
-            rand: ComplementaryMultiplyWithCarryGen::new(1)
+            st: 0,
+            rpl: [0; 16],
+            halted: false,
+            rand: ComplementaryMultiplyWithCarryGen::new(1),
+            quirks: Quirks::default(),
+            xo_chip: false,
+            audio_pattern: [0; 16],
+            pitch: 64,
+            frame: 0,
+            tracer: None
         }
     }
 
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.quirks = platform.quirks();
+        self.xo_chip = platform == Platform::XoChip;
+    }
+
+    // the size of the address space for the active platform
+    pub fn memory_size(&self) -> usize {
+        if self.xo_chip { MEMORY_SIZE } else { 0x1000 }
+    }
+
     pub fn reset(&mut self) {
         self.i = 0;
-        self.pc = 0x200;
-        self.memory = [0; 4096];
+        self.pc = PROGRAM_START as u16;
+        self.memory = [0; MEMORY_SIZE];
         self.v = [0; 16];
         self.stack = [0; 16];
         self.sp = 0;
         self.dt = 0;
+        self.st = 0;
+        self.halted = false;
+        self.audio_pattern = [0; 16];
+        self.pitch = 64;
+        self.frame = 0;
         self.rand = ComplementaryMultiplyWithCarryGen::new(1);
-        self.display.cls();
-        for i in 0..80 {
-            self.memory[i] = FONT_SET[i];
+        self.display.set_hires(false);
+        self.display.planes = 1;
+        self.memory[..80].copy_from_slice(&FONT_SET);
+        self.memory[BIG_FONT_ADDRESS..BIG_FONT_ADDRESS + 160].copy_from_slice(&BIG_FONT_SET);
+    }
+
+    // Resets the machine and loads the ROM at PROGRAM_START, for the active
+    // platform. A ROM which does not fit is rejected, leaving the machine
+    // unchanged.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        let available = self.memory_size() - PROGRAM_START;
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        if rom.len() > available {
+            return Err(LoadError::TooLarge { size: rom.len(), available });
         }
+        self.reset();
+        self.memory[PROGRAM_START..PROGRAM_START + rom.len()].copy_from_slice(rom);
+        Ok(())
     }
 
-    pub fn execute_cycle(&mut self) {
-        let opcode: u16 = read_word(self.memory, self.pc);
-        self.process_opcode(opcode);
+    pub fn execute_cycle(&mut self) -> Result<(), CpuError> {
+        if self.halted {
+            return Ok(());
+        }
+        if self.pc as usize + 1 >= self.memory_size() {
+            return Err(CpuError::PcOutOfRange { pc: self.pc });
+        }
+        let opcode: u16 = read_word(&self.memory, self.pc);
+        let mut tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return self.process_opcode(opcode),
+        };
+
+        let (pc, frame, before) = (self.pc, self.frame, Registers::of(self));
+        let operand = if opcode == 0xF000 && (pc as usize) + 3 < self.memory_size() {
+            Some(read_word(&self.memory, pc + 2))
+        } else {
+            None
+        };
+        let access = self.decode(opcode).and_then(|instruction| self.memory_access(instruction));
+        let result = self.process_opcode(opcode);
+        let event = TraceEvent { frame, pc, opcode, operand, before, access, fault: result.err() };
+        tracer.trace(&event, self);
+        self.tracer = Some(tracer);
+        result
     }
 
+    // called once at the end of every frame
     pub fn decrement_timers(&mut self) {
+        self.frame += 1;
         if self.dt > 0 {
             self.dt -= 1;
         }
+        if self.st > 0 {
+            self.st -= 1;
+        }
     }
 
-    fn process_opcode(&mut self, opcode: u16) {
+    pub fn is_sound_on(&self) -> bool {
+        self.st > 0
+    }
 
-        // extract various opcode parameters
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-        let vx = self.v[x];
-        let vy = self.v[y];
-        let nnn = opcode & 0x0FFF;
-        let kk = (opcode & 0x00FF) as u8;
-        let n = (opcode & 0x000F) as u8;
+    // the memory range [start, start + len) accessed by the instruction at
+    // pc, or a fault if any of it lies outside of the address space
+    fn memory_range(&self, pc: u16, start: u16, len: usize) -> Result<Range<usize>, CpuError> {
+        let start = start as usize;
+        if start + len > self.memory_size() {
+            return Err(CpuError::MemoryOutOfBounds {
+                pc,
+                address: start.max(self.memory_size()),
+            });
+        }
+        Ok(start..start + len)
+    }
 
-        // break up into nibbles
-        let op_1 = (opcode & 0xF000) >> 12;
-        let op_2 = (opcode & 0x0F00) >> 8;
-        let op_3 = (opcode & 0x00F0) >> 4;
-        let op_4 = opcode & 0x000F;
+    // skips the next instruction, which on XO-CHIP may be the four byte F000 nnnn
+    fn skip(&mut self) {
+        let size = if self.xo_chip && (self.pc as usize) + 1 < MEMORY_SIZE {
+            Instruction::decode(read_word(&self.memory, self.pc)).map_or(2, |next| next.size())
+        } else {
+            2
+        };
+        self.pc = self.pc.wrapping_add(size);
+    }
 
-        // increment the program counter
-        self.pc += 2;
+    // the value shifted by SHR / SHL
+    fn shift_operand(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy { self.v[y as usize] } else { self.v[x as usize] }
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+    }
+
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+    }
+
+    // decodes an opcode, rejecting instructions the platform does not support
+    pub fn decode(&self, opcode: u16) -> Option<Instruction> {
+        match Instruction::decode(opcode) {
+            Some(ref instruction) if instruction.is_xo_chip() && !self.xo_chip => None,
+            Some(Instruction::Plane(n)) if n > 3 => None,
+            decoded => decoded,
+        }
+    }
+
+    // the instruction at the program counter, if it is valid
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        if self.pc as usize + 1 >= self.memory_size() {
+            return None;
+        }
+        self.decode(read_word(&self.memory, self.pc))
+    }
+
+    // the rows of each plane's sprite DRW reads, and whether it draws 16
+    // pixels wide, or None if it draws nothing
+    fn sprite_rows(&self, n: u8) -> Option<(usize, bool)> {
+        if n != 0 {
+            return Some((n as usize, false));
+        }
+        if self.quirks.zero_height_draws_nothing {
+            return None;
+        }
+        // Dxy0 draws 16 x 16, or 8 x 16 in low resolution on some platforms
+        if self.display.hires || !self.quirks.lores_tall_sprites { Some((32, true)) } else { Some((16, false)) }
+    }
+
+    // the memory the instruction would access if executed now
+    pub fn memory_access(&self, instruction: Instruction) -> Option<MemoryAccess> {
+        use instruction::Instruction::*;
+
+        let i = self.i as usize;
+        match instruction {
+            SaveRange(x, y) => Some(MemoryAccess::Write(i..i + register_range(x as usize, y as usize).len())),
+            LoadRange(x, y) => Some(MemoryAccess::Read(i..i + register_range(x as usize, y as usize).len())),
+            Drw(_, _, n) => self.sprite_rows(n)
+                .map(|(rows, _)| MemoryAccess::Read(i..i + rows * self.display.plane_count())),
+            Audio => Some(MemoryAccess::Read(i..i + 16)),
+            LdBVx(_) => Some(MemoryAccess::Write(i..i + 3)),
+            LdIVx(x) => Some(MemoryAccess::Write(i..i + x as usize + 1)),
+            LdVxI(x) => Some(MemoryAccess::Read(i..i + x as usize + 1)),
+            _ => None,
+        }
+    }
 
-        // println!("{}, {}, {}, {}", op_1, op_2, op_3, op_4);
+    fn process_opcode(&mut self, opcode: u16) -> Result<(), CpuError> {
+        let address = self.pc;
+        let instruction = self.decode(opcode);
+        let result = match instruction {
+            Some(instruction) => self.execute(instruction),
+            None => Err(CpuError::UnknownOpcode { pc: address, opcode }),
+        };
+        if result.is_err() {
+            self.pc = address;
+        }
+        result
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), CpuError> {
+        use instruction::Instruction::*;
+
+        // increment the program counter
+        let address = self.pc;
+        self.pc = self.pc.wrapping_add(2);
 
-        match (op_1, op_2, op_3, op_4) {
-            // CLS
-            (0, 0, 0xE, 0) => self.display.cls(),
-            // RET
-            (0, 0, 0xE, 0xE) => {
-                self.sp = self.sp - 1;
+        match instruction {
+            ScrollDown(n) => self.display.scroll_down(n as usize),
+            ScrollUp(n) => self.display.scroll_up(n as usize),
+            Cls => self.display.cls(),
+            Ret => {
+                if self.sp == 0 {
+                    return Err(CpuError::StackUnderflow { pc: address });
+                }
+                self.sp -= 1;
                 self.pc = self.stack[self.sp as usize];
             },
-            // JP
-            (0x1, _, _, _) => self.pc = nnn,
-            // CALL
-            (0x2, _, _, _) => {
+            ScrollRight => self.display.scroll_right(4),
+            ScrollLeft => self.display.scroll_left(4),
+            Exit => {
+                self.pc = address;
+                self.halted = true;
+            },
+            Low => self.display.set_hires(false),
+            High => self.display.set_hires(true),
+            Jp(nnn) => self.pc = nnn,
+            Call(nnn) => {
+                if self.sp as usize >= self.stack.len() {
+                    return Err(CpuError::StackOverflow { pc: address });
+                }
                 self.stack[self.sp as usize] = self.pc;
-                self.sp = self.sp + 1;
+                self.sp += 1;
                 self.pc = nnn;
             },
-            // SE Vx KK
-            (0x3, _, _, _) => self.pc += if vx == kk { 2 } else { 0 },
-            // SNE Vx KK
-            (0x4, _, _, _) => self.pc += if vx != kk { 2 } else { 0 },
-            // SE Vx Vy
-            (0x5, _, _, _) => self.pc += if vx == vy { 2 } else { 0 },
-            // LD Vx
-            (0x6, _, _, _) => self.v[x] = kk,
-            // ADD Vx, byte
-            (0x7, _, _, _) => self.v[x] += kk,
-            // LD Vx, Vy
-            (0x8, _, _, 0x0) => self.v[x] = self.v[y],
-            // OR Vx, Vy
-            (0x8, _, _, 0x1) => self.v[x] = self.v[x] | self.v[y],
-            // AND Vx, Vy
-            (0x8, _, _, 0x2) => self.v[x] = self.v[x] & self.v[y],
-            // XOR Vx, Vy
-            (0x8, _, _, 0x3) => self.v[x] = self.v[x] ^ self.v[y],
-            // ADD Vx, Vy
-            (0x8, _, _, 0x4) => {
-                let (res, overflow) = self.v[x].overflowing_add(self.v[y]);
-                match overflow {
-                    true => self.v[0xF] = 1,
-                    false => self.v[0xF] = 0,
-                }
-                self.v[x] = res;
-            }
-            // SUB Vx, Vy
-            (0x8, _, _, 0x5) => {
-                let (res, overflow) = self.v[x].overflowing_sub(self.v[y]);
-                match overflow {
-                    true => self.v[0xF] = 0,
-                    false => self.v[0xF] = 1,
+            SeByte(x, kk) => if self.v[x as usize] == kk { self.skip() },
+            SneByte(x, kk) => if self.v[x as usize] != kk { self.skip() },
+            SeReg(x, y) => if self.v[x as usize] == self.v[y as usize] { self.skip() },
+            SaveRange(x, y) => {
+                let registers = register_range(x as usize, y as usize);
+                let dest = self.memory_range(address, self.i, registers.len())?;
+                for (offset, r) in registers.into_iter().enumerate() {
+                    self.memory[dest.start + offset] = self.v[r];
                 }
-                self.v[x] = res;
-            }
-            // SHR Vx 
-            (0x8, _, _, 0x6) => {
-                self.v[0xF] = self.v[x] & 0x1;
-                self.v[x] >>= 1;
-            }
-            // SUBN Vx, Vy
-            (0x8, _, _, 0x7) => {
-                let (res, overflow) = self.v[y].overflowing_sub(self.v[x]);
-                match overflow {
-                    true => self.v[0xF] = 0,
-                    false => self.v[0xF] = 1,
+            },
+            LoadRange(x, y) => {
+                let registers = register_range(x as usize, y as usize);
+                let src = self.memory_range(address, self.i, registers.len())?;
+                for (offset, r) in registers.into_iter().enumerate() {
+                    self.v[r] = self.memory[src.start + offset];
                 }
-                self.v[x] = res;
             },
-            // SHL Vx
-            (0x8, _, _, 0xE) => {
-                self.v[0xF] = self.v[x] & 0x80;
-                self.v[x] <<= 1;
-            }
-            // SNE Vx Vy
-            (0x9, _, _, _) => self.pc += if vx != vy { 2 } else { 0 },
-            // LD I
-            (0xA, _, _, _) => self.i = nnn,
-            // JP V0
-            (0xB, _, _, _) => self.pc = nnn + self.v[0] as u16,
-            // RND
-            (0xC, _, _, _) => self.v[x] = self.rand.random() as u8 & kk,
-            // DRW
-            (0xD, _, _, _) => {
-                let collision = self.display.draw(vx as usize, vy as usize,
-                    &self.memory[self.i as usize .. (self.i + n as u16) as usize]);
+            LdByte(x, kk) => self.v[x as usize] = kk,
+            AddByte(x, kk) => self.v[x as usize] = self.v[x as usize].wrapping_add(kk),
+            LdReg(x, y) => self.v[x as usize] = self.v[y as usize],
+            Or(x, y) => {
+                self.v[x as usize] |= self.v[y as usize];
+                self.reset_vf_after_logic();
+            },
+            And(x, y) => {
+                self.v[x as usize] &= self.v[y as usize];
+                self.reset_vf_after_logic();
+            },
+            Xor(x, y) => {
+                self.v[x as usize] ^= self.v[y as usize];
+                self.reset_vf_after_logic();
+            },
+            AddReg(x, y) => {
+                let (res, overflow) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+                self.v[0xF] = if overflow { 1 } else { 0 };
+                self.v[x as usize] = res;
+            },
+            Sub(x, y) => {
+                let (res, overflow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+                self.v[0xF] = if overflow { 0 } else { 1 };
+                self.v[x as usize] = res;
+            },
+            Shr(x, y) => {
+                let value = self.shift_operand(x, y);
+                self.v[x as usize] = value >> 1;
+                self.v[0xF] = value & 0x1;
+            },
+            Subn(x, y) => {
+                let (res, overflow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+                self.v[0xF] = if overflow { 0 } else { 1 };
+                self.v[x as usize] = res;
+            },
+            Shl(x, y) => {
+                let value = self.shift_operand(x, y);
+                self.v[x as usize] = value << 1;
+                self.v[0xF] = value >> 7;
+            },
+            SneReg(x, y) => if self.v[x as usize] != self.v[y as usize] { self.skip() },
+            LdI(nnn) => self.i = nnn,
+            JpV0(nnn) => {
+                let offset = if self.quirks.jump_uses_vx {
+                    self.v[(nnn >> 8) as usize]
+                } else {
+                    self.v[0]
+                };
+                self.pc = nnn + offset as u16;
+            },
+            Rnd(x, kk) => self.v[x as usize] = self.rand.random() as u8 & kk,
+            Drw(x, y, n) => {
+                let (vx, vy) = (self.v[x as usize] as usize, self.v[y as usize] as usize);
+                let (rows, large) = match self.sprite_rows(n) {
+                    Some(rows) => rows,
+                    None => {
+                        self.v[0xF] = 0;
+                        return Ok(());
+                    },
+                };
+                let sprite = self.memory_range(address, self.i, rows * self.display.plane_count())?;
+                let collision = if large {
+                    self.display.draw_large(vx, vy, &self.memory[sprite], &self.quirks)
+                } else {
+                    self.display.draw(vx, vy, &self.memory[sprite], &self.quirks)
+                };
                 self.v[0xF] = if collision { 1 } else { 0 };
-            }
-            // SKP Vx
-            (0xE, _, 0x9, 0xE) => self.pc += if self.keypad.is_key_down(vx) { 2 } else { 0 },
-            // SKNP Vx
-            (0xE, _, 0xA, 0x1) => self.pc += if self.keypad.is_key_down(vx) { 0 } else { 2 },
-            // LD Vx, DT
-            (0xF, _, 0x0, 0x7) => self.v[x] = self.dt,
-            // LD Vx, K
-            (0xF, _, 0x0, 0xA) => {
-                self.pc -= 2;
-                for (i, key) in self.keypad.keys.iter().enumerate() {
-                    if *key == true {
-                        self.v[x] = i as u8;
-                        self.pc +=2;
-                    }
+            },
+            Skp(x) => if self.keypad.is_key_down(self.v[x as usize] & 0xF) { self.skip() },
+            Sknp(x) => if !self.keypad.is_key_down(self.v[x as usize] & 0xF) { self.skip() },
+            LdILong => {
+                // the operand follows the instruction, and must not wrap round
+                if address as usize + 3 >= self.memory_size() {
+                    return Err(CpuError::PcOutOfRange { pc: address });
                 }
+                self.i = read_word(&self.memory, self.pc);
+                self.pc = self.pc.wrapping_add(2);
+            },
+            Plane(n) => self.display.planes = n,
+            Audio => {
+                let src = self.memory_range(address, self.i, 16)?;
+                self.audio_pattern.copy_from_slice(&self.memory[src]);
+            },
+            LdVxDt(x) => self.v[x as usize] = self.dt,
+            LdVxK(x) => {
+                // wait, by re-executing this instruction, until a key is pressed
+                match self.keypad.keys.iter().position(|key| *key) {
+                    Some(key) => self.v[x as usize] = key as u8,
+                    None => self.pc = address,
+                }
+            },
+            LdDtVx(x) => self.dt = self.v[x as usize],
+            LdStVx(x) => self.st = self.v[x as usize],
+            AddIVx(x) => {
+                self.i = self.i.wrapping_add(self.v[x as usize] as u16);
+                if self.quirks.add_i_sets_vf {
+                    self.v[0xF] = if self.i > 0x0FFF { 1 } else { 0 };
+                }
+            },
+            LdFVx(x) => self.i = self.v[x as usize] as u16 * 5,
+            LdHfVx(x) => self.i = (BIG_FONT_ADDRESS + (self.v[x as usize] & 0xF) as usize * 10) as u16,
+            LdBVx(x) => {
+                let vx = self.v[x as usize];
+                let bcd = self.memory_range(address, self.i, 3)?;
+                self.memory[bcd].copy_from_slice(&[vx / 100, (vx / 10) % 10, vx % 10]);
             },
-            // LD DT, Vx
-            (0xF, _, 0x1, 0x5) => self.dt = self.v[x],
-            // ADD I, Vx
-            (0xF, _, 0x1, 0xE) => self.i = self.i + self.v[x] as u16,
-            // LD F, Vx
-            (0xF, _, 0x2, 0x9) => self.i = vx as u16 * 5,
-            // LD B, Vx
-            (0xF, _, 0x3, 0x3) => {
-                self.memory[self.i as usize] = vx / 100;
-                self.memory[self.i as usize + 1] = (vx / 10) % 10;
-                self.memory[self.i as usize + 2] = (vx % 100) % 10;
+            Pitch(x) => self.pitch = self.v[x as usize],
+            LdIVx(x) => {
+                let x = x as usize;
+                let dest = self.memory_range(address, self.i, x + 1)?;
+                self.memory[dest].copy_from_slice(&self.v[0..(x + 1)]);
+                self.increment_i_after_load_store(x);
+            },
+            LdVxI(x) => {
+                let x = x as usize;
+                let src = self.memory_range(address, self.i, x + 1)?;
+                self.v[0..(x + 1)].copy_from_slice(&self.memory[src]);
+                self.increment_i_after_load_store(x);
+            },
+            LdRVx(x) => {
+                let x = x as usize;
+                self.rpl[0..(x + 1)].copy_from_slice(&self.v[0..(x + 1)]);
+            },
+            LdVxR(x) => {
+                let x = x as usize;
+                self.v[0..(x + 1)].copy_from_slice(&self.rpl[0..(x + 1)]);
             },
-            // LD [I], Vx
-            (0xF, _, 0x5, 0x5) => self.memory[(self.i as usize)..(self.i + x as u16 + 1) as usize]
-                        .copy_from_slice(&self.v[0..(x as usize + 1)]),
-            // LD Vx, [I]          
-            (0xF, _, 0x6, 0x5) =>  self.v[0..(x as usize + 1)]
-                        .copy_from_slice(&self.memory[(self.i as usize)..(self.i + x as u16 + 1) as usize]),
-            (_, _, _, _) => ()
         }
+        Ok(())
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::Cpu;
+    use super::{Cpu, CpuError, LoadError, MemoryAccess};
+    use instruction::Instruction;
+    use quirks::{Platform, Quirks};
+
+    fn xo_chip() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.set_platform(Platform::XoChip);
+        cpu
+    }
+
+    #[test]
+    fn load_rom() {
+        let mut cpu = Cpu::new();
+        cpu.v[3] = 9;
+        cpu.load_rom(&[0x12, 0x00]).unwrap();
+        assert_eq!(cpu.v[3], 0, "the machine is reset");
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(&cpu.memory[0x200..0x203], &[0x12, 0x00, 0x00]);
+        assert_eq!(cpu.memory[0], 0xF0, "the font is loaded");
+
+        let rom = vec![0xAA; 0xE01];
+        assert_eq!(cpu.load_rom(&rom), Err(LoadError::TooLarge { size: 0xE01, available: 0xE00 }));
+        assert_eq!(cpu.memory[0x200], 0x12, "a rejected ROM leaves memory untouched");
+        assert_eq!(cpu.load_rom(&rom).unwrap_err().to_string(), "ROM is 3585 bytes, but only 3584 bytes fit in memory");
+        assert_eq!(cpu.load_rom(&[]), Err(LoadError::Empty));
+
+        cpu.set_platform(Platform::XoChip);
+        cpu.load_rom(&rom).unwrap();
+        assert_eq!(cpu.memory[0x1000], 0xAA);
+    }
+
+    #[test]
+    fn memory_accesses() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.i = 0x300;
+        cpu.memory[0x200..0x202].copy_from_slice(&[0xF3, 0x55]);
+        let instruction = cpu.current_instruction().unwrap();
+        assert_eq!(cpu.memory_access(instruction), Some(MemoryAccess::Write(0x300..0x304)));
+        assert_eq!(cpu.memory_access(Instruction::Drw(0, 0, 5)), Some(MemoryAccess::Read(0x300..0x305)));
+        assert_eq!(cpu.memory_access(Instruction::LoadRange(4, 2)), Some(MemoryAccess::Read(0x300..0x303)));
+        assert_eq!(cpu.memory_access(Instruction::Cls), None);
+
+        // XO-CHIP instructions are not valid on other platforms
+        cpu.memory[0x200..0x202].copy_from_slice(&[0xF0, 0x02]);
+        assert_eq!(cpu.current_instruction(), None);
+    }
 
     #[test]
     fn opcode_jp() {
         let mut cpu = Cpu::new();
-        cpu.process_opcode(0x1A2A);
+        cpu.process_opcode(0x1A2A).unwrap();
         assert_eq!(cpu.pc, 0x0A2A, "the program counter is updated");
     }
 
@@ -236,7 +618,7 @@ mod tests {
         let addr = 0x23;
         cpu.pc = addr;
 
-        cpu.process_opcode(0x2ABC);
+        cpu.process_opcode(0x2ABC).unwrap();
 
         assert_eq!(cpu.pc, 0x0ABC, "the program counter is updated to the new address");
         assert_eq!(cpu.sp, 1, "the stack pointer is incremented");
@@ -249,11 +631,11 @@ mod tests {
         cpu.v[1] = 0xFE;
         
         // vx == kk
-        cpu.process_opcode(0x31FE);
+        cpu.process_opcode(0x31FE).unwrap();
         assert_eq!(cpu.pc, 4, "the stack pointer skips");
 
         // vx != kk
-        cpu.process_opcode(0x31FA);
+        cpu.process_opcode(0x31FA).unwrap();
         assert_eq!(cpu.pc, 6, "the stack pointer is incremented");
     }
 
@@ -263,11 +645,11 @@ mod tests {
         cpu.v[1] = 0xFE;
         
         // vx == kk
-        cpu.process_opcode(0x41FE);
+        cpu.process_opcode(0x41FE).unwrap();
         assert_eq!(cpu.pc, 2, "the stack pointer is incremented");
 
         // vx != kk
-        cpu.process_opcode(0x41FA);
+        cpu.process_opcode(0x41FA).unwrap();
         assert_eq!(cpu.pc, 6, "the stack pointer skips");
     }
 
@@ -279,11 +661,11 @@ mod tests {
         cpu.v[3] = 3;
         
         // vx == vy
-        cpu.process_opcode(0x5230);
+        cpu.process_opcode(0x5230).unwrap();
         assert_eq!(cpu.pc, 4, "the stack pointer skips");
 
         // vx != vy
-        cpu.process_opcode(0x5130);
+        cpu.process_opcode(0x5130).unwrap();
         assert_eq!(cpu.pc, 6, "the stack pointer is incremented");
     }
 
@@ -295,11 +677,11 @@ mod tests {
         cpu.v[3] = 3;
         
         // vx == vy
-        cpu.process_opcode(0x9230);
+        cpu.process_opcode(0x9230).unwrap();
         assert_eq!(cpu.pc, 2, "the stack pointer is incremented");
 
         // vx != vy
-        cpu.process_opcode(0x9130);
+        cpu.process_opcode(0x9130).unwrap();
         assert_eq!(cpu.pc, 6, "the stack pointer skips");
     }
 
@@ -308,7 +690,7 @@ mod tests {
         let mut cpu = Cpu::new();
         cpu.v[1] = 3;
         
-        cpu.process_opcode(0x7101);
+        cpu.process_opcode(0x7101).unwrap();
         assert_eq!(cpu.v[1], 4, "Vx was incremented by one");
     }
 
@@ -318,7 +700,7 @@ mod tests {
         cpu.v[1] = 3;
         cpu.v[0] = 0;
         
-        cpu.process_opcode(0x8010);
+        cpu.process_opcode(0x8010).unwrap();
         assert_eq!(cpu.v[0], 3, "Vx was loaded with vy");
     }
 
@@ -328,7 +710,7 @@ mod tests {
         cpu.v[2] = 0b01101100;
         cpu.v[3] = 0b11001110;
         
-        cpu.process_opcode(0x8231);
+        cpu.process_opcode(0x8231).unwrap();
         assert_eq!(cpu.v[2], 0b11101110, "Vx was loaded with vx OR vy");
     }
 
@@ -338,7 +720,7 @@ mod tests {
         cpu.v[2] = 0b01101100;
         cpu.v[3] = 0b11001110;
         
-        cpu.process_opcode(0x8232);
+        cpu.process_opcode(0x8232).unwrap();
         assert_eq!(cpu.v[2], 0b01001100, "Vx was loaded with vx AND vy");
     }
 
@@ -348,7 +730,7 @@ mod tests {
         cpu.v[2] = 0b01101100;
         cpu.v[3] = 0b11001110;
         
-        cpu.process_opcode(0x8233);
+        cpu.process_opcode(0x8233).unwrap();
         assert_eq!(cpu.v[2], 0b10100010, "Vx was loaded with vx XOR vy");
     }
 
@@ -359,11 +741,11 @@ mod tests {
         cpu.v[2] = 100;
         cpu.v[3] = 250;
         
-        cpu.process_opcode(0x8124);
+        cpu.process_opcode(0x8124).unwrap();
         assert_eq!(cpu.v[1], 110, "Vx was loaded with vx + vy");
         assert_eq!(cpu.v[0xF], 0, "no overflow occured");
 
-        cpu.process_opcode(0x8134);
+        cpu.process_opcode(0x8134).unwrap();
         assert_eq!(cpu.v[1], 0x68, "Vx was loaded with vx + vy");
         assert_eq!(cpu.v[0xF], 1, "overflow occured");
     }
@@ -378,7 +760,7 @@ mod tests {
         cpu.i = 0x300;
         
         // load v0 - v2 into memory at i
-        cpu.process_opcode(0xF255);
+        cpu.process_opcode(0xF255).unwrap();
         assert_eq!(cpu.memory[cpu.i as usize], 5, "V0 was loaded into memory at i");
         assert_eq!(cpu.memory[cpu.i as usize + 1], 4, "V1 was loaded into memory at i + 1");
         assert_eq!(cpu.memory[cpu.i as usize + 2], 3, "V2 was loaded into memory at i + 2");
@@ -392,7 +774,7 @@ mod tests {
         cpu.v[2] = 234;
         
         // load v0 - v2 from memory at i
-        cpu.process_opcode(0xF233);
+        cpu.process_opcode(0xF233).unwrap();
         assert_eq!(cpu.memory[cpu.i as usize], 2, "hundreds");
         assert_eq!(cpu.memory[cpu.i as usize + 1], 3, "tens");
         assert_eq!(cpu.memory[cpu.i as usize + 2], 4, "digits");
@@ -409,13 +791,41 @@ mod tests {
         
         
         // load v0 - v2 from memory at i
-        cpu.process_opcode(0xF265);
+        cpu.process_opcode(0xF265).unwrap();
         assert_eq!(cpu.v[0], 5, "V0 was loaded from memory at i");
         assert_eq!(cpu.v[1], 4, "V1 was loaded from memory at i + 1");
         assert_eq!(cpu.v[2], 3, "V2 was loaded from memory at i + 2");
         assert_eq!(cpu.v[3], 0, "i + 3 was not loaded");
     }
 
+    #[test]
+    fn opcode_ld_st_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[4] = 2;
+
+        cpu.process_opcode(0xF418).unwrap();
+        assert_eq!(cpu.st, 2, "the sound timer is loaded from Vx");
+        assert!(cpu.is_sound_on(), "the buzzer sounds while ST is non-zero");
+    }
+
+    #[test]
+    fn decrement_timers() {
+        let mut cpu = Cpu::new();
+        cpu.dt = 1;
+        cpu.st = 2;
+
+        cpu.decrement_timers();
+        assert_eq!(cpu.dt, 0, "the delay timer is decremented");
+        assert_eq!(cpu.st, 1, "the sound timer is decremented");
+        assert!(cpu.is_sound_on(), "the buzzer is still sounding");
+        assert_eq!(cpu.frame, 1, "the frame is counted");
+
+        cpu.decrement_timers();
+        assert_eq!(cpu.dt, 0, "the delay timer stops at zero");
+        assert_eq!(cpu.st, 0, "the sound timer reaches zero");
+        assert!(!cpu.is_sound_on(), "the buzzer is off");
+    }
+
     #[test]
     fn opcode_ret() {
         let mut cpu = Cpu::new();
@@ -423,9 +833,9 @@ mod tests {
         cpu.pc = addr;
 
         // jump to 0x0ABC
-        cpu.process_opcode(0x2ABC); 
+        cpu.process_opcode(0x2ABC).unwrap(); 
         // return
-        cpu.process_opcode(0x00EE);
+        cpu.process_opcode(0x00EE).unwrap();
 
         assert_eq!(cpu.pc, 0x25, "the program counter is updated to the new address");
         assert_eq!(cpu.sp, 0, "the stack pointer is decremented");
@@ -436,15 +846,15 @@ mod tests {
     fn opcode_ld_i_addr() {
         let mut cpu = Cpu::new();
 
-        cpu.process_opcode(0x61AA);
+        cpu.process_opcode(0x61AA).unwrap();
         assert_eq!(cpu.v[1], 0xAA, "V1 is set");
         assert_eq!(cpu.pc, 2, "the program counter is advanced two bytes");
 
-        cpu.process_opcode(0x621A);
+        cpu.process_opcode(0x621A).unwrap();
         assert_eq!(cpu.v[2], 0x1A, "V2 is set");
         assert_eq!(cpu.pc, 4, "the program counter is advanced two bytes");
 
-        cpu.process_opcode(0x6A15);
+        cpu.process_opcode(0x6A15).unwrap();
         assert_eq!(cpu.v[10], 0x15, "V10 is set");
         assert_eq!(cpu.pc, 6, "the program counter is advanced two bytes");
     }
@@ -452,10 +862,359 @@ mod tests {
     #[test]
     fn opcode_axxx() {
         let mut cpu = Cpu::new();
-        cpu.process_opcode(0xAFAF);
+        cpu.process_opcode(0xAFAF).unwrap();
 
         assert_eq!(cpu.i, 0x0FAF, "the 'i' register is updated");
         assert_eq!(cpu.pc, 2, "the program counter is advanced two bytes");
     }
 
+    #[test]
+    fn ret_with_empty_stack_is_a_fault() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x200;
+
+        assert_eq!(cpu.process_opcode(0x00EE), Err(CpuError::StackUnderflow { pc: 0x200 }));
+        assert_eq!(cpu.pc, 0x200, "the program counter is not advanced");
+    }
+
+    #[test]
+    fn call_with_full_stack_is_a_fault() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x200;
+        for _ in 0..16 {
+            cpu.process_opcode(0x2200).unwrap();
+        }
+
+        assert_eq!(cpu.process_opcode(0x2200), Err(CpuError::StackOverflow { pc: 0x200 }));
+        assert_eq!(cpu.sp, 16, "the stack pointer is unchanged");
+    }
+
+    #[test]
+    fn memory_access_past_the_end_is_a_fault() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x200;
+        cpu.i = 0xFFE;
+
+        assert_eq!(cpu.process_opcode(0xF233),
+            Err(CpuError::MemoryOutOfBounds { pc: 0x200, address: 0x1000 }));
+        assert_eq!(cpu.process_opcode(0xD005),
+            Err(CpuError::MemoryOutOfBounds { pc: 0x200, address: 0x1000 }));
+        assert_eq!(cpu.process_opcode(0xF155), Ok(()), "the last two bytes can be written");
+    }
+
+    #[test]
+    fn unknown_opcode_is_a_fault() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x204;
+
+        assert_eq!(cpu.process_opcode(0x5121), Err(CpuError::UnknownOpcode { pc: 0x204, opcode: 0x5121 }));
+        assert_eq!(cpu.pc, 0x204, "the program counter is not advanced");
+    }
+
+    #[test]
+    fn pc_out_of_range_is_a_fault() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0xFFF;
+
+        assert_eq!(cpu.execute_cycle(), Err(CpuError::PcOutOfRange { pc: 0xFFF }));
+    }
+
+    #[test]
+    fn opcode_add_vx_kk_wraps() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0xFF;
+
+        cpu.process_opcode(0x7102).unwrap();
+        assert_eq!(cpu.v[1], 1, "Vx wraps around");
+        assert_eq!(cpu.v[0xF], 0, "VF is not affected");
+    }
+
+    #[test]
+    fn opcode_shr_shl() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0b1000_0001;
+        cpu.v[2] = 0b0100_0010;
+
+        cpu.process_opcode(0x8126).unwrap();
+        assert_eq!(cpu.v[1], 0b0100_0000, "Vx is shifted right");
+        assert_eq!(cpu.v[0xF], 1, "VF holds the bit shifted out");
+
+        cpu.v[1] = 0b1000_0001;
+        cpu.process_opcode(0x812E).unwrap();
+        assert_eq!(cpu.v[1], 0b0000_0010, "Vx is shifted left");
+        assert_eq!(cpu.v[0xF], 1, "VF holds the bit shifted out");
+    }
+
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let mut cpu = Cpu::new();
+        cpu.quirks.shift_uses_vy = true;
+        cpu.v[1] = 0xFF;
+        cpu.v[2] = 0b0100_0010;
+
+        cpu.process_opcode(0x8126).unwrap();
+        assert_eq!(cpu.v[1], 0b0010_0001, "Vy is shifted into Vx");
+        assert_eq!(cpu.v[0xF], 0, "VF holds the bit shifted out of Vy");
+    }
+
+    #[test]
+    fn quirk_load_store_increments_i() {
+        let mut cpu = Cpu::new();
+        cpu.i = 0x300;
+
+        cpu.process_opcode(0xF255).unwrap();
+        assert_eq!(cpu.i, 0x300, "I is unchanged by default");
+
+        cpu.quirks.load_store_increments_i = true;
+        cpu.process_opcode(0xF255).unwrap();
+        assert_eq!(cpu.i, 0x303, "I points past the stored registers");
+        cpu.process_opcode(0xF165).unwrap();
+        assert_eq!(cpu.i, 0x305, "I points past the loaded registers");
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 0x10;
+        cpu.v[2] = 0x20;
+
+        cpu.process_opcode(0xB200).unwrap();
+        assert_eq!(cpu.pc, 0x210, "the jump is offset by V0");
+
+        cpu.quirks.jump_uses_vx = true;
+        cpu.process_opcode(0xB200).unwrap();
+        assert_eq!(cpu.pc, 0x220, "the jump is offset by Vx");
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let mut cpu = Cpu::new();
+        cpu.v[0xF] = 1;
+
+        cpu.process_opcode(0x8121).unwrap();
+        assert_eq!(cpu.v[0xF], 1, "VF is unchanged by default");
+
+        cpu.set_platform(Platform::CosmacVip);
+        cpu.process_opcode(0x8122).unwrap();
+        assert_eq!(cpu.v[0xF], 0, "VF is reset");
+    }
+
+    #[test]
+    fn quirk_add_i_sets_vf() {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Quirks { add_i_sets_vf: true, ..Quirks::NONE };
+        cpu.i = 0x0FFF;
+        cpu.v[1] = 1;
+
+        cpu.process_opcode(0xF11E).unwrap();
+        assert_eq!(cpu.i, 0x1000, "I is incremented");
+        assert_eq!(cpu.v[0xF], 1, "VF flags the overflow");
+    }
+
+    #[test]
+    fn opcode_high_low() {
+        let mut cpu = Cpu::new();
+
+        cpu.process_opcode(0x00FF).unwrap();
+        assert!(cpu.display.hires, "high resolution is enabled");
+        assert_eq!(cpu.display.width(), 128);
+
+        cpu.process_opcode(0x00FE).unwrap();
+        assert!(!cpu.display.hires, "low resolution is restored");
+    }
+
+    #[test]
+    fn opcode_scroll() {
+        let mut cpu = Cpu::new();
+        cpu.display.set_pixel(8, 8, true);
+
+        cpu.process_opcode(0x00C2).unwrap();
+        assert!(cpu.display.get_pixel(8, 10), "the display scrolls down");
+        cpu.process_opcode(0x00FB).unwrap();
+        assert!(cpu.display.get_pixel(12, 10), "the display scrolls right");
+        cpu.process_opcode(0x00FC).unwrap();
+        assert!(cpu.display.get_pixel(8, 10), "the display scrolls left");
+    }
+
+    #[test]
+    fn opcode_drw_large() {
+        let mut cpu = Cpu::new();
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0xFF;
+        cpu.memory[0x301] = 0xFF;
+
+        cpu.process_opcode(0xD010).unwrap();
+        assert!(cpu.display.get_pixel(15, 0), "16 pixel wide rows are drawn");
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn opcode_drw_large_by_platform() {
+        let sprite = |platform: Platform, hires: bool| {
+            let mut cpu = Cpu::new();
+            cpu.set_platform(platform);
+            cpu.display.set_hires(hires);
+            cpu.i = 0x300;
+            cpu.memory[0x300..0x320].copy_from_slice(&[0xFF; 32]);
+            cpu.process_opcode(0xD010).unwrap();
+            let width = (0..16).filter(|&x| cpu.display.get_pixel(x, 0)).count();
+            let height = (0..32).filter(|&y| cpu.display.get_pixel(0, y)).count();
+            (width, height)
+        };
+        assert_eq!(sprite(Platform::CosmacVip, false), (0, 0), "the VIP draws nothing");
+        assert_eq!(sprite(Platform::Chip48, false), (8, 16));
+        assert_eq!(sprite(Platform::SuperChip, false), (8, 16));
+        assert_eq!(sprite(Platform::SuperChip, true), (16, 16));
+        assert_eq!(sprite(Platform::XoChip, false), (16, 16));
+    }
+
+    #[test]
+    fn drw_large_memory_access_by_platform() {
+        let access = |platform: Platform, hires: bool| {
+            let mut cpu = Cpu::new();
+            cpu.set_platform(platform);
+            cpu.display.set_hires(hires);
+            cpu.i = 0x300;
+            cpu.memory_access(Instruction::Drw(0, 1, 0))
+        };
+        assert_eq!(access(Platform::CosmacVip, false), None, "the VIP reads nothing");
+        assert_eq!(access(Platform::Chip48, false), Some(MemoryAccess::Read(0x300..0x310)));
+        assert_eq!(access(Platform::SuperChip, false), Some(MemoryAccess::Read(0x300..0x310)));
+        assert_eq!(access(Platform::SuperChip, true), Some(MemoryAccess::Read(0x300..0x320)));
+        assert_eq!(access(Platform::XoChip, false), Some(MemoryAccess::Read(0x300..0x320)));
+    }
+
+    #[test]
+    fn opcode_ld_hf_vx() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.v[3] = 2;
+
+        cpu.process_opcode(0xF330).unwrap();
+        assert_eq!(cpu.i, 80 + 20, "I points at the big digit");
+        assert_eq!(cpu.memory[cpu.i as usize + 2], 0x03);
+    }
+
+    #[test]
+    fn opcode_ld_r_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 1;
+        cpu.v[1] = 2;
+
+        cpu.process_opcode(0xF175).unwrap();
+        cpu.v = [0; 16];
+        cpu.process_opcode(0xF085).unwrap();
+        assert_eq!(cpu.v[0], 1, "V0 is restored");
+        assert_eq!(cpu.v[1], 0, "V1 is not restored");
+    }
+
+    #[test]
+    fn opcode_exit() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.memory[0x200] = 0x00;
+        cpu.memory[0x201] = 0xFD;
+
+        cpu.execute_cycle().unwrap();
+        assert!(cpu.halted, "the interpreter has exited");
+        cpu.execute_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x200, "no further instructions are executed");
+
+        cpu.reset();
+        assert!(!cpu.halted);
+    }
+
+    #[test]
+    fn xo_chip_instructions_are_gated() {
+        let mut cpu = Cpu::new();
+
+        assert!(cpu.process_opcode(0xF000).is_err(), "F000 is unknown outside XO-CHIP");
+        assert!(cpu.process_opcode(0xF201).is_err(), "Fn01 is unknown outside XO-CHIP");
+        assert!(cpu.process_opcode(0x5122).is_err(), "5xy2 is unknown outside XO-CHIP");
+    }
+
+    #[test]
+    fn opcode_ld_i_long() {
+        let mut cpu = xo_chip();
+        cpu.memory[0x200..0x204].copy_from_slice(&[0xF0, 0x00, 0xAB, 0xCD]);
+
+        cpu.execute_cycle().unwrap();
+        assert_eq!(cpu.i, 0xABCD, "I is loaded with the following word");
+        assert_eq!(cpu.pc, 0x204, "the program counter skips the operand");
+    }
+
+    #[test]
+    fn ld_i_long_at_top_of_memory() {
+        let mut cpu = xo_chip();
+        cpu.memory[0xFFFE..].copy_from_slice(&[0xF0, 0x00]);
+        cpu.memory[0..2].copy_from_slice(&[0xAB, 0xCD]);
+        cpu.pc = 0xFFFE;
+
+        assert_eq!(cpu.execute_cycle(), Err(CpuError::PcOutOfRange { pc: 0xFFFE }));
+        assert_eq!(cpu.i, 0, "the operand is not read from the bottom of memory");
+    }
+
+    #[test]
+    fn skip_over_ld_i_long() {
+        let mut cpu = xo_chip();
+        cpu.memory[0x202..0x206].copy_from_slice(&[0xF0, 0x00, 0xAB, 0xCD]);
+
+        cpu.process_opcode(0x3000).unwrap();
+        assert_eq!(cpu.pc, 0x206, "the skip steps over all four bytes");
+    }
+
+    #[test]
+    fn opcode_save_load_range() {
+        let mut cpu = xo_chip();
+        cpu.i = 0x300;
+        cpu.v[2] = 1;
+        cpu.v[3] = 2;
+        cpu.v[4] = 3;
+
+        cpu.process_opcode(0x5242).unwrap();
+        assert_eq!(&cpu.memory[0x300..0x303], &[1, 2, 3]);
+        assert_eq!(cpu.i, 0x300, "I is unchanged");
+
+        cpu.process_opcode(0x5A83).unwrap();
+        assert_eq!(&cpu.v[8..11], &[3, 2, 1], "registers are loaded in reverse when x > y");
+    }
+
+    #[test]
+    fn opcode_plane_and_drw() {
+        let mut cpu = xo_chip();
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0x80;
+        cpu.memory[0x301] = 0x80;
+
+        cpu.process_opcode(0xF301).unwrap();
+        assert_eq!(cpu.display.planes, 3, "both planes are selected");
+        cpu.process_opcode(0xD001).unwrap();
+        assert_eq!(cpu.display.memory[0], 3, "a row is drawn on each plane");
+    }
+
+    #[test]
+    fn opcode_audio_and_pitch() {
+        let mut cpu = xo_chip();
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0xAA;
+        cpu.v[1] = 100;
+
+        cpu.process_opcode(0xF002).unwrap();
+        cpu.process_opcode(0xF13A).unwrap();
+        assert_eq!(cpu.audio_pattern[0], 0xAA, "the pattern is loaded from I");
+        assert_eq!(cpu.pitch, 100, "the pitch is loaded from Vx");
+    }
+
+    #[test]
+    fn xo_chip_memory() {
+        let mut cpu = xo_chip();
+        cpu.i = 0xFFF0;
+        cpu.v[0] = 7;
+
+        cpu.process_opcode(0xF055).unwrap();
+        assert_eq!(cpu.memory[0xFFF0], 7, "memory beyond 4 KiB is addressable");
+
+        cpu.set_platform(Platform::SuperChip);
+        assert!(cpu.process_opcode(0xF055).is_err(), "but not on other platforms");
+    }
 }
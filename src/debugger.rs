@@ -0,0 +1,661 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+use cpu::{Cpu, CpuError, MemoryAccess};
+use expr::{Context, Expr, ExprError, Message};
+use instruction::Instruction;
+use journal::Journal;
+use machine::{FramePosition, Machine};
+use symbols::Symbols;
+
+// a register which can be inspected and watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn get(&self, cpu: &Cpu) -> u16 {
+        match *self {
+            Register::V(x) => cpu.v[x as usize] as u16,
+            Register::I => cpu.i,
+            Register::Pc => cpu.pc,
+            Register::Sp => cpu.sp as u16,
+            Register::Dt => cpu.dt as u16,
+            Register::St => cpu.st as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    // either a read or a write
+    Access,
+}
+
+impl Watch {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        matches!((*self, access),
+                 (Watch::Access, _) | (Watch::Read, &MemoryAccess::Read(_)) | (Watch::Write, &MemoryAccess::Write(_)))
+    }
+}
+
+// What happens when a breakpoint or watchpoint is reached: by default
+// execution stops, but a condition restricts that to when it is true, and a
+// logpoint appends its message to the debugger's log instead of stopping.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trigger {
+    pub condition: Option<Expr>,
+    pub message: Option<Message>,
+    // the number of times reached, whether or not the condition held
+    pub hit_count: u64,
+}
+
+impl Trigger {
+    // stops when the condition is true, e.g. "V3 == 5 && hit_count > 40"
+    pub fn when(condition: &str) -> Result<Trigger, ExprError> {
+        Ok(Trigger { condition: Some(Expr::parse(condition)?), ..Trigger::default() })
+    }
+
+    // logs the message rather than stopping, e.g. "score {V3}"
+    pub fn log(message: &str) -> Result<Trigger, ExprError> {
+        Ok(Trigger { message: Some(Message::parse(message)?), ..Trigger::default() })
+    }
+
+    // counts a hit, returning true if execution should stop
+    fn hit(&mut self, cpu: &Cpu, log: &mut Vec<String>) -> bool {
+        self.hit_count += 1;
+        let context = Context { cpu, hit_count: self.hit_count };
+        if self.condition.as_ref().is_some_and(|condition| !condition.is_true(&context)) {
+            return false;
+        }
+        match self.message {
+            Some(ref message) => {
+                log.push(message.format(&context));
+                false
+            },
+            None => true,
+        }
+    }
+}
+
+// why a run returned control to the caller. Watchpoints stop after the
+// instruction which triggered them has executed, everything else stops
+// before the next instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // the requested step, step over or step out completed
+    Step,
+    // the program counter reached a breakpoint
+    Breakpoint(u16),
+    // the instruction at pc read the watched address
+    ReadWatchpoint { pc: u16, address: usize },
+    // the instruction at pc wrote the watched address
+    WriteWatchpoint { pc: u16, address: usize },
+    // the instruction at pc changed a watched register
+    RegisterChanged { pc: u16, register: Register, old: u16, new: u16 },
+    // the requested frame has started
+    Frame(u64),
+    // the program executed the SUPER-CHIP exit instruction
+    Halted,
+    // the instruction faulted, and was not executed
+    Fault(CpuError),
+    // the run executed its maximum number of instructions
+    InstructionLimit,
+}
+
+// what the debugger is to run, for run_command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Step,
+    StepOver,
+    StepOut,
+    // runs until stopped, executing at most the given number of instructions
+    Run(u64),
+    // runs until the frame starts, executing at most the given number of
+    // instructions
+    RunUntilFrame(u64, u64),
+}
+
+// Runs a machine under the control of a debugger. Instructions are executed
+// one Machine::step at a time, so frames end and the timers are decremented
+// exactly where they would be for the host. The most recent instructions are
+// journalled, so that they can be stepped back through.
+pub struct Debugger {
+    pub machine: Machine,
+    // names for addresses, used for breakpoints and call stacks
+    pub symbols: Symbols,
+    // the machine's place in its frame before each journalled instruction
+    positions: VecDeque<FramePosition>,
+    breakpoints: BTreeMap<u16, Trigger>,
+    watchpoints: Vec<(Range<usize>, Watch, Trigger)>,
+    registers: BTreeMap<Register, Trigger>,
+    // messages from logpoints, oldest first
+    log: Vec<String>,
+    journal: Journal,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Debugger {
+        Debugger {
+            machine,
+            symbols: Symbols::new(),
+            positions: VecDeque::new(),
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            registers: BTreeMap::new(),
+            log: Vec::new(),
+            journal: Journal::new(10_000),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.set_breakpoint(address, Trigger::default());
+    }
+
+    // adds a breakpoint, or replaces the one at the address
+    pub fn set_breakpoint(&mut self, address: u16, trigger: Trigger) {
+        self.breakpoints.insert(address, trigger);
+    }
+
+    // adds a breakpoint at a location such as "draw_paddle+4" or "0x2A4",
+    // returning its address, or None if the location is unknown
+    pub fn add_breakpoint_at(&mut self, location: &str) -> Option<u16> {
+        let address = self.symbols.resolve(location)?;
+        self.add_breakpoint(address);
+        Some(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        self.breakpoints.keys().cloned().collect()
+    }
+
+    pub fn breakpoint(&self, address: u16) -> Option<&Trigger> {
+        self.breakpoints.get(&address)
+    }
+
+    // stops when an instruction accesses memory in range as per watch
+    pub fn add_watchpoint(&mut self, range: Range<usize>, watch: Watch) {
+        self.set_watchpoint(range, watch, Trigger::default());
+    }
+
+    pub fn set_watchpoint(&mut self, range: Range<usize>, watch: Watch, trigger: Trigger) {
+        self.remove_watchpoint(range.clone(), watch);
+        self.watchpoints.push((range, watch, trigger));
+    }
+
+    pub fn remove_watchpoint(&mut self, range: Range<usize>, watch: Watch) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| (&w.0, w.1) != (&range, watch));
+        self.watchpoints.len() != before
+    }
+
+    // stops when an instruction changes the register's value
+    pub fn watch_register(&mut self, register: Register) {
+        self.set_register_watch(register, Trigger::default());
+    }
+
+    pub fn set_register_watch(&mut self, register: Register, trigger: Trigger) {
+        self.registers.insert(register, trigger);
+    }
+
+    pub fn unwatch_register(&mut self, register: Register) -> bool {
+        self.registers.remove(&register).is_some()
+    }
+
+    // the pc, then the address of each call in progress, innermost first
+    pub fn call_stack(&self) -> Vec<u16> {
+        let calls = &self.machine.cpu.stack[..(self.machine.cpu.sp as usize).min(self.machine.cpu.stack.len())];
+        // the stack holds the address of the instruction after each call
+        let mut stack = vec![self.machine.cpu.pc];
+        stack.extend(calls.iter().rev().map(|&address| address.wrapping_sub(2)));
+        stack
+    }
+
+    // the call stack described with the symbols, e.g.
+    // "#0 0x02A4 draw_paddle+4 (pong.asm:42)"
+    pub fn backtrace(&self) -> Vec<String> {
+        self.call_stack().iter().enumerate()
+            .map(|(depth, &address)| format!("#{} {}", depth, self.symbols.describe(address)))
+            .collect()
+    }
+
+    // removes and returns the messages logged by logpoints
+    pub fn take_log(&mut self) -> Vec<String> {
+        self.log.split_off(0)
+    }
+
+    // forgets the instructions journalled, for when the CPU has been changed
+    // other than by the debugger, say by a save state
+    pub fn clear_journal(&mut self) {
+        self.journal.clear();
+        self.positions.clear();
+    }
+
+    // the number of instructions which can be stepped back through
+    pub fn journal_depth(&self) -> usize {
+        self.journal.depth()
+    }
+
+    // limits the instructions journalled, 0 disables stepping back
+    pub fn set_journal_depth(&mut self, depth: usize) {
+        self.journal.set_depth(depth);
+        while self.positions.len() > depth {
+            self.positions.pop_front();
+        }
+    }
+
+    // Undoes the most recently executed instruction, restoring the CPU to its
+    // exact state before it, or returns false if the journal is exhausted.
+    // Hit counts and logged messages are not undone.
+    pub fn step_back(&mut self) -> bool {
+        if !self.journal.undo(&mut self.machine.cpu) {
+            return false;
+        }
+        if let Some(position) = self.positions.pop_back() {
+            self.machine.set_position(position);
+        }
+        true
+    }
+
+    // executes a single instruction, ignoring any breakpoint at the pc
+    pub fn step(&mut self) -> StopReason {
+        self.run_command(Command::Step, &mut |_| {})
+    }
+
+    // steps, running a called subroutine through to its return
+    pub fn step_over(&mut self) -> StopReason {
+        self.run_command(Command::StepOver, &mut |_| {})
+    }
+
+    // runs until the current subroutine returns, or steps if there is none
+    pub fn step_out(&mut self) -> StopReason {
+        self.run_command(Command::StepOut, &mut |_| {})
+    }
+
+    // runs until something stops execution, or max_instructions have run
+    pub fn run(&mut self, max_instructions: u64) -> StopReason {
+        self.run_command(Command::Run(max_instructions), &mut |_| {})
+    }
+
+    // runs until the given frame starts
+    pub fn run_until_frame(&mut self, frame: u64, max_instructions: u64) -> StopReason {
+        self.run_command(Command::RunUntilFrame(frame, max_instructions), &mut |_| {})
+    }
+
+    // Runs the command, calling end_frame after each frame completes, for
+    // the host to record rewind history and the like as Machine::advance_with
+    // does.
+    pub fn run_command(&mut self, command: Command, end_frame: &mut dyn FnMut(&mut Cpu)) -> StopReason {
+        match command {
+            Command::Step => self.run_until(u64::MAX, end_frame, |_| Some(StopReason::Step)),
+            Command::StepOver => match self.machine.cpu.current_instruction() {
+                Some(call @ Instruction::Call(_)) => {
+                    let (sp, next) = (self.machine.cpu.sp, self.machine.cpu.pc.wrapping_add(call.size()));
+                    self.run_until(u64::MAX, end_frame, |cpu| {
+                        if cpu.pc == next && cpu.sp == sp { Some(StopReason::Step) } else { None }
+                    })
+                },
+                _ => self.run_command(Command::Step, end_frame),
+            },
+            Command::StepOut => {
+                let sp = self.machine.cpu.sp;
+                if sp == 0 {
+                    return self.run_command(Command::Step, end_frame);
+                }
+                self.run_until(u64::MAX, end_frame, |cpu| if cpu.sp < sp { Some(StopReason::Step) } else { None })
+            },
+            Command::Run(max_instructions) => self.run_until(max_instructions, end_frame, |_| None),
+            Command::RunUntilFrame(frame, max_instructions) => {
+                if self.machine.cpu.frame >= frame {
+                    return StopReason::Frame(self.machine.cpu.frame);
+                }
+                self.run_until(max_instructions, end_frame, |cpu| {
+                    if cpu.frame >= frame { Some(StopReason::Frame(cpu.frame)) } else { None }
+                })
+            },
+        }
+    }
+
+    // executes instructions until done returns a reason to stop, or a
+    // breakpoint, watchpoint, fault or the exit instruction stops execution.
+    // A breakpoint at the starting pc is stepped past.
+    fn run_until<F>(&mut self, max_instructions: u64, end_frame: &mut dyn FnMut(&mut Cpu), done: F) -> StopReason
+        where F: Fn(&Cpu) -> Option<StopReason> {
+        let mut executed = 0;
+        loop {
+            if executed > 0 {
+                if let Some(trigger) = self.breakpoints.get_mut(&self.machine.cpu.pc) {
+                    if trigger.hit(&self.machine.cpu, &mut self.log) {
+                        return StopReason::Breakpoint(self.machine.cpu.pc);
+                    }
+                }
+            }
+            if self.machine.cpu.halted {
+                return StopReason::Halted;
+            }
+            if executed >= max_instructions {
+                return StopReason::InstructionLimit;
+            }
+            if let Some(reason) = self.execute(end_frame) {
+                return reason;
+            }
+            executed += 1;
+            if let Some(reason) = done(&self.machine.cpu) {
+                return reason;
+            }
+        }
+    }
+
+    // executes one instruction, returning a reason to stop if it faulted or
+    // triggered a watchpoint
+    fn execute(&mut self, end_frame: &mut dyn FnMut(&mut Cpu)) -> Option<StopReason> {
+        let pc = self.machine.cpu.pc;
+        let access = self.machine.cpu.current_instruction().and_then(|i| self.machine.cpu.memory_access(i));
+        let before: Vec<u16> = self.registers.keys().map(|r| r.get(&self.machine.cpu)).collect();
+        let pending = if self.journal.depth() > 0 { Some(self.journal.begin(&self.machine.cpu)) } else { None };
+        let position = self.machine.position();
+
+        match self.machine.step() {
+            Ok(true) => end_frame(&mut self.machine.cpu),
+            Ok(false) => {},
+            Err(fault) => return Some(StopReason::Fault(fault)),
+        }
+        if let Some(pending) = pending {
+            self.journal.end(pending, &self.machine.cpu);
+            if self.positions.len() == self.journal.depth() {
+                self.positions.pop_front();
+            }
+            self.positions.push_back(position);
+        }
+
+        if let Some(access) = access {
+            let range = match access {
+                MemoryAccess::Read(ref range) | MemoryAccess::Write(ref range) => range.clone(),
+            };
+            let mut hit = None;
+            for &mut (ref watched, watch, ref mut trigger) in self.watchpoints.iter_mut() {
+                let start = range.start.max(watched.start);
+                if watch.matches(&access) && start < range.end.min(watched.end)
+                    && trigger.hit(&self.machine.cpu, &mut self.log) {
+                    hit = Some(hit.map_or(start, |hit: usize| hit.min(start)));
+                }
+            }
+            if let Some(address) = hit {
+                return Some(match access {
+                    MemoryAccess::Read(_) => StopReason::ReadWatchpoint { pc, address },
+                    MemoryAccess::Write(_) => StopReason::WriteWatchpoint { pc, address },
+                });
+            }
+        }
+        let mut changed = None;
+        for ((&register, trigger), old) in self.registers.iter_mut().zip(before) {
+            let new = register.get(&self.machine.cpu);
+            if new != old && trigger.hit(&self.machine.cpu, &mut self.log) && changed.is_none() {
+                changed = Some(StopReason::RegisterChanged { pc, register, old, new });
+            }
+        }
+        changed
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Debugger, Register, StopReason, Trigger, Watch};
+    use cpu::{Cpu, CpuError};
+    use expr::Expr;
+    use machine::{Machine, Timing};
+    use quirks::Platform;
+    use symbols::Symbols;
+
+    fn debugger() -> Debugger {
+        let mut cpu = Cpu::new();
+        let program = [
+            0x60, 0x00, // 200: LD V0, 0
+            0xA3, 0x00, // 202: LD I, 0x300
+            0x61, 0x10, // 204: LD V1, 0x10
+            0x22, 0x10, // 206: CALL 0x210
+            0xF0, 0x55, // 208: LD [I], V0
+            0xF1, 0x65, // 20A: LD V1, [I]
+            0x12, 0x06, // 20C: JP 0x206
+            0x00, 0x00, // 20E: padding
+            0x70, 0x01, // 210: ADD V0, 1
+            0x00, 0xEE, // 212: RET
+        ];
+        cpu.load_rom(&program).unwrap();
+        Debugger::new(Machine::new(cpu))
+    }
+
+    #[test]
+    fn steps() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.machine.cpu.pc, 0x202);
+
+        for _ in 0..3 {
+            debugger.step();
+        }
+        assert_eq!(debugger.machine.cpu.pc, 0x210, "step enters subroutines");
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!(debugger.machine.cpu.pc, 0x208);
+        assert_eq!(debugger.machine.cpu.v[0], 1);
+
+        debugger.machine.cpu.pc = 0x206;
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.sp), (0x208, 0));
+        assert_eq!(debugger.machine.cpu.v[0], 2);
+    }
+
+    #[test]
+    fn steps_over_call_at_top_of_memory() {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.set_platform(Platform::XoChip);
+        // CALL 0x200 at 0xFFFE returns to 0x0000, as the CPU wraps
+        cpu.memory[0xFFFE..].copy_from_slice(&[0x22, 0x00]);
+        cpu.memory[0x200..0x202].copy_from_slice(&[0x00, 0xEE]);
+        cpu.pc = 0xFFFE;
+        let mut debugger = Debugger::new(Machine::new(cpu));
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.sp), (0x0000, 0));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(0x212);
+        assert_eq!(debugger.run(1000), StopReason::Breakpoint(0x212));
+        // continuing steps past the breakpoint the pc is at
+        assert_eq!(debugger.run(1000), StopReason::Breakpoint(0x212));
+        assert_eq!(debugger.machine.cpu.v[0], 2);
+
+        // step over stops at breakpoints inside the subroutine
+        debugger.machine.cpu.pc = 0x206;
+        assert_eq!(debugger.step_over(), StopReason::Breakpoint(0x212));
+
+        assert!(debugger.remove_breakpoint(0x212));
+        assert!(debugger.breakpoints().is_empty());
+        assert_eq!(debugger.run(1000), StopReason::InstructionLimit);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = debugger();
+        debugger.add_watchpoint(0x300..0x301, Watch::Write);
+        assert_eq!(debugger.run(1000), StopReason::WriteWatchpoint { pc: 0x208, address: 0x300 });
+        assert_eq!(debugger.machine.cpu.memory[0x300], 1, "the write has happened");
+
+        debugger.add_watchpoint(0x301..0x302, Watch::Read);
+        assert_eq!(debugger.run(1000), StopReason::ReadWatchpoint { pc: 0x20A, address: 0x301 });
+        assert!(debugger.remove_watchpoint(0x301..0x302, Watch::Read));
+        assert!(!debugger.remove_watchpoint(0x301..0x302, Watch::Read));
+
+        debugger.machine.cpu.v[1] = 0x10;
+        debugger.watch_register(Register::V(1));
+        assert_eq!(debugger.run(1000),
+                   StopReason::WriteWatchpoint { pc: 0x208, address: 0x300 });
+        assert_eq!(debugger.run(1000),
+                   StopReason::RegisterChanged { pc: 0x20A, register: Register::V(1), old: 0x10, new: 0 });
+    }
+
+    #[test]
+    fn frames_faults_and_halts() {
+        let mut debugger = debugger();
+        debugger.machine.instructions_per_frame = 7;
+        assert_eq!(debugger.run_until_frame(3, 1000), StopReason::Frame(3));
+        assert_eq!(debugger.machine.cpu.frame, 3);
+        assert_eq!(debugger.run_until_frame(2, 1000), StopReason::Frame(3));
+
+        // RET with an empty stack
+        debugger.machine.cpu.pc = 0x212;
+        debugger.machine.cpu.sp = 0;
+        assert_eq!(debugger.run(1000), StopReason::Fault(CpuError::StackUnderflow { pc: 0x212 }));
+
+        debugger.machine.cpu.memory[0x20E..0x210].copy_from_slice(&[0x00, 0xFD]);
+        debugger.machine.cpu.pc = 0x20E;
+        assert_eq!(debugger.run(1000), StopReason::Halted);
+        assert_eq!(debugger.step(), StopReason::Halted);
+    }
+
+    #[test]
+    fn frames_end_where_the_machine_ends_them() {
+        let mut debugger = debugger();
+        debugger.machine.timing = Timing::CosmacVip;
+        let mut machine = Machine::new(Cpu::new());
+        machine.cpu.load_state(&debugger.machine.cpu.save_state()).unwrap();
+        machine.timing = Timing::CosmacVip;
+        for _ in 0..3 {
+            machine.run_frame().unwrap();
+        }
+        debugger.set_journal_depth(10);
+        assert_eq!(debugger.run_until_frame(3, 10_000), StopReason::Frame(3));
+        assert!(debugger.machine.cpu.save_state() == machine.cpu.save_state());
+        assert_eq!(debugger.machine.position(), machine.position());
+
+        // stepping back over the end of a frame restores the place in it
+        assert!(debugger.step_back());
+        assert_eq!(debugger.machine.cpu.frame, 2);
+        assert_ne!(debugger.machine.position(), machine.position());
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.machine.cpu.frame, 3);
+        assert_eq!(debugger.machine.position(), machine.position());
+    }
+
+    #[test]
+    fn commands_call_end_frame() {
+        let mut debugger = debugger();
+        let mut ended = Vec::new();
+        let reason = debugger.run_command(Command::RunUntilFrame(3, 10_000), &mut |cpu| ended.push(cpu.frame));
+        assert_eq!(reason, StopReason::Frame(3));
+        assert_eq!(ended, vec![1, 2, 3]);
+
+        // nine more instructions complete the next frame of ten
+        for _ in 0..9 {
+            assert_eq!(debugger.run_command(Command::Step, &mut |cpu| ended.push(cpu.frame)), StopReason::Step);
+        }
+        assert_eq!(ended, vec![1, 2, 3]);
+        debugger.run_command(Command::Step, &mut |cpu| ended.push(cpu.frame));
+        assert_eq!(ended, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut debugger = debugger();
+        debugger.symbols = Symbols::parse("main = 0x200\nloop = 0x206\nincrement = 0x210\n0x212 = test.asm:12").unwrap();
+        assert_eq!(debugger.add_breakpoint_at("increment+2"), Some(0x212));
+        assert_eq!(debugger.add_breakpoint_at("decrement"), None);
+        assert_eq!(debugger.breakpoints(), vec![0x212]);
+
+        assert_eq!(debugger.run(1000), StopReason::Breakpoint(0x212));
+        assert_eq!(debugger.call_stack(), vec![0x212, 0x206]);
+        assert_eq!(debugger.backtrace(), vec!["#0 0x0212 increment+2 (test.asm:12)", "#1 0x0206 loop"]);
+    }
+
+    #[test]
+    fn steps_back() {
+        let mut debugger = debugger();
+        debugger.machine.instructions_per_frame = 3;
+        debugger.set_journal_depth(5);
+        let start = debugger.machine.cpu.save_state();
+        assert!(!debugger.step_back());
+
+        for _ in 0..4 {
+            debugger.step();
+        }
+        assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.sp, debugger.machine.cpu.frame), (0x210, 1, 1));
+        for _ in 0..4 {
+            assert!(debugger.step_back());
+        }
+        assert!(debugger.machine.cpu.save_state() == start);
+        assert!(!debugger.step_back());
+
+        // the frame is stepped back too, so the timers run at the same point
+        assert_eq!(debugger.run_until_frame(2, 1000), StopReason::Frame(2));
+        assert!(debugger.step_back());
+        assert_eq!(debugger.machine.cpu.frame, 1);
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_eq!(debugger.machine.cpu.frame, 2);
+
+        for _ in 0..10 {
+            debugger.step();
+        }
+        assert_eq!((0..10).filter(|_| debugger.step_back()).count(), 5, "the journal is limited to its depth");
+    }
+
+    #[test]
+    fn conditional_breakpoints_and_logpoints() {
+        let mut debugger = debugger();
+        debugger.set_breakpoint(0x212, Trigger::when("V0 == 3").unwrap());
+        assert_eq!(debugger.run(1000), StopReason::Breakpoint(0x212));
+        assert_eq!(debugger.machine.cpu.v[0], 3);
+        assert_eq!(debugger.breakpoint(0x212).unwrap().hit_count, 3);
+
+        debugger.set_breakpoint(0x212, Trigger::when("hit_count > 2").unwrap());
+        assert_eq!(debugger.run(1000), StopReason::Breakpoint(0x212));
+        assert_eq!(debugger.machine.cpu.v[0], 6);
+
+        let mut logpoint = Trigger::log("V0={V0} [I]={[I]}").unwrap();
+        logpoint.condition = Some(Expr::parse("V0 & 1").unwrap());
+        debugger.set_breakpoint(0x20A, logpoint);
+        debugger.remove_breakpoint(0x212);
+        assert_eq!(debugger.run(24), StopReason::InstructionLimit);
+        assert_eq!(debugger.take_log(), vec!["V0=7 [I]=7", "V0=9 [I]=9"]);
+        assert!(debugger.take_log().is_empty());
+
+        // conditions apply to watchpoints too
+        debugger.set_watchpoint(0x300..0x301, Watch::Write, Trigger::when("[0x300] == 12").unwrap());
+        assert_eq!(debugger.run(1000), StopReason::WriteWatchpoint { pc: 0x208, address: 0x300 });
+        assert_eq!(debugger.machine.cpu.memory[0x300], 12);
+        debugger.set_register_watch(Register::V(0), Trigger::when("V0 > 13").unwrap());
+        assert_eq!(debugger.run(1000),
+                   StopReason::RegisterChanged { pc: 0x210, register: Register::V(0), old: 13, new: 14 });
+
+        assert!(Trigger::when("V0 ==").is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use cpu::{Cpu, PROGRAM_START};
+use instruction::Instruction;
+use instruction::Instruction::*;
+use symbols::Symbols;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    // the mnemonics from Cowgod's technical reference, e.g. LD V0, 0x12
+    Cowgod,
+    // the Octo assembly language, e.g. v0 := 0x12
+    Octo,
+}
+
+// a single disassembled instruction, or bytes which do not decode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+    // the label defined at this address, if anything jumps to or references it
+    pub label: Option<String>,
+    pub text: String,
+}
+
+// what is known of a byte of memory, e.g. from coverage::Coverage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    // an executed instruction starts here
+    Code,
+    // read or written as data, and never executed
+    Data,
+    Unknown,
+}
+
+pub fn label_name(address: u16) -> String {
+    format!("L{:03X}", address)
+}
+
+// disassembles a ROM, or any other block of memory, loaded at origin. A
+// linear sweep is used, so data embedded in code is decoded as instructions
+// where possible and emitted as data bytes otherwise.
+pub fn disassemble(bytes: &[u8], origin: u16, syntax: Syntax) -> Vec<Line> {
+    disassemble_with(bytes, origin, syntax, &|_| Region::Unknown, &Symbols::new())
+}
+
+// Disassembles as above, but with what is known of each address separating
+// code from data: data is never decoded as instructions, and executed
+// instructions are decoded wherever they start. The linear sweep only fills
+// in the unknown addresses between. Addresses with symbols are labelled
+// with them, whether or not they are referenced.
+pub fn disassemble_with(bytes: &[u8], origin: u16, syntax: Syntax, region: &dyn Fn(u16) -> Region,
+                        symbols: &Symbols) -> Vec<Line> {
+    sweep(bytes, origin, syntax, region, symbols, &Instruction::decode)
+}
+
+// disassembles the program area of a CPU's memory, decoding only the
+// instructions its platform supports
+pub fn disassemble_memory(cpu: &Cpu, syntax: Syntax) -> Vec<Line> {
+    let bytes = &cpu.memory[PROGRAM_START..cpu.memory_size()];
+    let decode_opcode = |opcode| cpu.decode(opcode);
+    sweep(bytes, PROGRAM_START as u16, syntax, &|_| Region::Unknown, &Symbols::new(), &decode_opcode)
+}
+
+// disassembles as disassemble_with, with decode_opcode turning opcodes into
+// instructions
+fn sweep(bytes: &[u8], origin: u16, syntax: Syntax, region: &dyn Fn(u16) -> Region, symbols: &Symbols,
+         decode_opcode: &dyn Fn(u16) -> Option<Instruction>) -> Vec<Line> {
+    let decoded = decode(bytes, origin, region, decode_opcode);
+
+    // every line referenced by a decoded instruction gets a label
+    let starts: BTreeSet<u16> = decoded.iter().map(|&(address, _, _, _)| address).collect();
+    let targets: BTreeSet<u16> = decoded.iter()
+        .filter_map(|&(_, _, instruction, operand)| instruction.and_then(|i| target(i, operand)))
+        .filter(|address| starts.contains(address))
+        .collect();
+    let label = |address: u16| match symbols.label(address) {
+        Some(name) => Some(name.to_string()),
+        None if targets.contains(&address) => Some(label_name(address)),
+        None => None,
+    };
+
+    decoded.into_iter().map(|(address, size, instruction, operand)| {
+        let start = (address - origin) as usize;
+        let raw = bytes[start..start + size].to_vec();
+        let text = match instruction {
+            Some(instruction) => format_instruction(instruction, operand, syntax, &label),
+            None => format_data(&raw, syntax),
+        };
+        Line {
+            address,
+            bytes: raw,
+            instruction,
+            label: label(address),
+            text,
+        }
+    }).collect()
+}
+
+// renders lines as a listing of address, raw bytes and the instruction, with
+// labels on lines of their own
+pub fn listing(lines: &[Line], syntax: Syntax) -> String {
+    let mut out = String::new();
+    for line in lines {
+        if let Some(ref label) = line.label {
+            match syntax {
+                Syntax::Cowgod => writeln!(out, "{:14}{}:", "", label).unwrap(),
+                Syntax::Octo => writeln!(out, "{:14}: {}", "", label).unwrap(),
+            }
+        }
+        let raw: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "{:04X}  {:8}    {}", line.address, raw, line.text).unwrap();
+    }
+    out
+}
+
+// (address, size, instruction, operand of LD I, long) for each step of a
+// linear sweep, with a size of 1 or 2 for data
+fn decode(bytes: &[u8], origin: u16, region: &dyn Fn(u16) -> Region,
+          decode_opcode: &dyn Fn(u16) -> Option<Instruction>) -> Vec<(u16, usize, Option<Instruction>, Option<u16>)> {
+    let address = |offset: usize| origin.wrapping_add(offset as u16);
+    // the bytes from offset, up to size, which can make up a line starting
+    // there, as those after the first must all be within the region
+    let span = |offset: usize, size: usize, within: Region| {
+        1 + (1..size).take_while(|&n| offset + n < bytes.len() && region(address(offset + n)) == within).count()
+    };
+
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let data = region(address(offset)) == Region::Data;
+        if data || span(offset, 2, Region::Unknown) < 2 {
+            let size = if data { span(offset, 2, Region::Data) } else { 1 };
+            decoded.push((address(offset), size, None, None));
+            offset += size;
+            continue;
+        }
+        let opcode = (bytes[offset] as u16) << 8 | bytes[offset + 1] as u16;
+        let instruction = decode_opcode(opcode);
+        let operand = match instruction {
+            Some(LdILong) if span(offset, 4, Region::Unknown) == 4 =>
+                Some((bytes[offset + 2] as u16) << 8 | bytes[offset + 3] as u16),
+            _ => None,
+        };
+        match (instruction, operand) {
+            (Some(LdILong), None) => decoded.push((address(offset), 2, None, None)),
+            (Some(LdILong), _) => decoded.push((address(offset), 4, instruction, operand)),
+            _ => decoded.push((address(offset), 2, instruction, operand)),
+        }
+        offset += if operand.is_some() { 4 } else { 2 };
+    }
+    decoded
+}
+
+// the address an instruction jumps to, calls or points I at
+fn target(instruction: Instruction, operand: Option<u16>) -> Option<u16> {
+    match instruction {
+        Jp(addr) | Call(addr) | LdI(addr) | JpV0(addr) => Some(addr),
+        LdILong => operand,
+        _ => None,
+    }
+}
+
+fn format_data(bytes: &[u8], syntax: Syntax) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+    match syntax {
+        Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+        Syntax::Octo => bytes.join(" "),
+    }
+}
+
+// formats a single instruction, with label returning the name to use for an
+// address in place of a number. operand is the address following LD I, long.
+pub fn format_instruction(instruction: Instruction, operand: Option<u16>, syntax: Syntax,
+                          label: &dyn Fn(u16) -> Option<String>) -> String {
+    let addr = |a: u16| label(a).unwrap_or_else(|| format!("0x{:03X}", a));
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction, operand.map(&addr), &addr),
+        Syntax::Octo => octo(instruction, operand.map(&addr), &addr),
+    }
+}
+
+fn cowgod(instruction: Instruction, operand: Option<String>, addr: &dyn Fn(u16) -> String) -> String {
+    match instruction {
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        Cls => "CLS".to_string(),
+        Ret => "RET".to_string(),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        Low => "LOW".to_string(),
+        High => "HIGH".to_string(),
+        Jp(a) => format!("JP {}", addr(a)),
+        Call(a) => format!("CALL {}", addr(a)),
+        SeByte(x, kk) => format!("SE V{:X}, 0x{:02X}", x, kk),
+        SneByte(x, kk) => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        SeReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+        LoadRange(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+        LdByte(x, kk) => format!("LD V{:X}, 0x{:02X}", x, kk),
+        AddByte(x, kk) => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        LdReg(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        AddReg(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SneReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        LdI(a) => format!("LD I, {}", addr(a)),
+        JpV0(a) => format!("JP V0, {}", addr(a)),
+        Rnd(x, kk) => format!("RND V{:X}, 0x{:02X}", x, kk),
+        Drw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Skp(x) => format!("SKP V{:X}", x),
+        Sknp(x) => format!("SKNP V{:X}", x),
+        LdILong => format!("LD I, LONG {}", operand.unwrap_or_default()),
+        Plane(n) => format!("PLANE {}", n),
+        Audio => "AUDIO".to_string(),
+        LdVxDt(x) => format!("LD V{:X}, DT", x),
+        LdVxK(x) => format!("LD V{:X}, K", x),
+        LdDtVx(x) => format!("LD DT, V{:X}", x),
+        LdStVx(x) => format!("LD ST, V{:X}", x),
+        AddIVx(x) => format!("ADD I, V{:X}", x),
+        LdFVx(x) => format!("LD F, V{:X}", x),
+        LdHfVx(x) => format!("LD HF, V{:X}", x),
+        LdBVx(x) => format!("LD B, V{:X}", x),
+        Pitch(x) => format!("PITCH V{:X}", x),
+        LdIVx(x) => format!("LD [I], V{:X}", x),
+        LdVxI(x) => format!("LD V{:X}, [I]", x),
+        LdRVx(x) => format!("LD R, V{:X}", x),
+        LdVxR(x) => format!("LD V{:X}, R", x),
+    }
+}
+
+fn octo(instruction: Instruction, operand: Option<String>, addr: &dyn Fn(u16) -> String) -> String {
+    match instruction {
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        Cls => "clear".to_string(),
+        Ret => "return".to_string(),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Low => "lores".to_string(),
+        High => "hires".to_string(),
+        Jp(a) => format!("jump {}", addr(a)),
+        Call(a) => format!(":call {}", addr(a)),
+        // Octo's conditionals name the case in which the next instruction runs
+        SeByte(x, kk) => format!("if v{:x} != 0x{:02X} then", x, kk),
+        SneByte(x, kk) => format!("if v{:x} == 0x{:02X} then", x, kk),
+        SeReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        LdByte(x, kk) => format!("v{:x} := 0x{:02X}", x, kk),
+        AddByte(x, kk) => format!("v{:x} += 0x{:02X}", x, kk),
+        LdReg(x, y) => format!("v{:x} := v{:x}", x, y),
+        Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        AddReg(x, y) => format!("v{:x} += v{:x}", x, y),
+        Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Shr(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Subn(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Shl(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        SneReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        LdI(a) => format!("i := {}", addr(a)),
+        JpV0(a) => format!("jump0 {}", addr(a)),
+        Rnd(x, kk) => format!("v{:x} := random 0x{:02X}", x, kk),
+        Drw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Skp(x) => format!("if v{:x} -key then", x),
+        Sknp(x) => format!("if v{:x} key then", x),
+        LdILong => format!("i := long {}", operand.unwrap_or_default()),
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        LdVxDt(x) => format!("v{:x} := delay", x),
+        LdVxK(x) => format!("v{:x} := key", x),
+        LdDtVx(x) => format!("delay := v{:x}", x),
+        LdStVx(x) => format!("buzzer := v{:x}", x),
+        AddIVx(x) => format!("i += v{:x}", x),
+        LdFVx(x) => format!("i := hex v{:x}", x),
+        LdHfVx(x) => format!("i := bighex v{:x}", x),
+        LdBVx(x) => format!("bcd v{:x}", x),
+        Pitch(x) => format!("pitch := v{:x}", x),
+        LdIVx(x) => format!("save v{:x}", x),
+        LdVxI(x) => format!("load v{:x}", x),
+        LdRVx(x) => format!("saveflags v{:x}", x),
+        LdVxR(x) => format!("loadflags v{:x}", x),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, disassemble_memory, disassemble_with, listing, Region, Syntax};
+    use cpu::Cpu;
+    use instruction::Instruction;
+    use quirks::Platform;
+    use symbols::Symbols;
+
+    // jumps back to itself after drawing a digit
+    static ROM: [u8; 10] = [0x00, 0xE0, 0xA2, 0x08, 0xD0, 0x15, 0x12, 0x02, 0xF0, 0x00];
+
+    #[test]
+    fn disassemble_cowgod() {
+        let lines = disassemble(&ROM, 0x200, Syntax::Cowgod);
+        let text: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+
+        assert_eq!(text, vec!["CLS", "LD I, L208", "DRW V0, V1, 5", "JP L202", "DB 0xF0, 0x00"]);
+        assert_eq!(lines[1].label, Some("L202".to_string()), "jump targets are labelled");
+        assert_eq!(lines[4].label, Some("L208".to_string()), "I targets are labelled");
+        assert_eq!(lines[4].instruction, None, "a truncated LD I, long is data");
+        assert_eq!(lines[2].bytes, vec![0xD0, 0x15]);
+    }
+
+    #[test]
+    fn disassemble_octo() {
+        let lines = disassemble(&ROM[..8], 0x200, Syntax::Octo);
+        let text: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+
+        assert_eq!(text, vec!["clear", "i := 0x208", "sprite v0 v1 5", "jump L202"]);
+    }
+
+    #[test]
+    fn disassemble_long() {
+        let rom = [0xF0, 0x00, 0x02, 0x04, 0x00, 0xEE, 0x01];
+        let lines = disassemble(&rom, 0x200, Syntax::Cowgod);
+
+        assert_eq!(lines[0].instruction, Some(Instruction::LdILong));
+        assert_eq!(lines[0].text, "LD I, LONG L204");
+        assert_eq!(lines[1].address, 0x204);
+        assert_eq!(lines[2].text, "DB 0x01", "an odd trailing byte is data");
+    }
+
+    #[test]
+    fn disassemble_memory_for_platform() {
+        let rom = [0xF0, 0x00, 0x02, 0x04, 0x00, 0xE0];
+        let disassemble_on = |platform: Platform| {
+            let mut cpu = Cpu::new();
+            cpu.set_platform(platform);
+            cpu.load_rom(&rom).unwrap();
+            disassemble_memory(&cpu, Syntax::Cowgod)
+        };
+
+        let lines = disassemble_on(Platform::XoChip);
+        assert_eq!(lines[0].instruction, Some(Instruction::LdILong));
+        assert_eq!((lines[1].address, lines[1].text.as_str()), (0x204, "CLS"));
+
+        let lines = disassemble_on(Platform::SuperChip);
+        assert_eq!(lines[0].instruction, None, "LD I, long is XO-CHIP only");
+        assert_eq!(lines[0].bytes, vec![0xF0, 0x00]);
+        assert_eq!((lines[2].address, lines[2].text.as_str()), (0x204, "CLS"));
+    }
+
+    #[test]
+    fn disassemble_symbols() {
+        let symbols = Symbols::parse("start = 0x200\nloop = 0x202\ndigit = 0x208").unwrap();
+        let lines = disassemble_with(&ROM, 0x200, Syntax::Cowgod, &|_| Region::Unknown, &symbols);
+        let text: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+
+        assert_eq!(text, vec!["CLS", "LD I, digit", "DRW V0, V1, 5", "JP loop", "DB 0xF0, 0x00"]);
+        assert_eq!(lines[0].label, Some("start".to_string()), "symbols label lines nothing references");
+    }
+
+    #[test]
+    fn render_listing() {
+        let lines = disassemble(&ROM[..8], 0x200, Syntax::Cowgod);
+
+        assert_eq!(listing(&lines, Syntax::Cowgod), "\
+0200  00E0        CLS
+              L202:
+0202  A208        LD I, 0x208
+0204  D015        DRW V0, V1, 5
+0206  1202        JP L202
+");
+    }
+}
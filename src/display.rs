@@ -1,52 +1,166 @@
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+use quirks::Quirks;
 
+const LORES_WIDTH: usize = 64;
+const LORES_HEIGHT: usize = 32;
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+
+// the buffer is sized for the SUPER-CHIP high resolution mode, in low
+// resolution only the first 64 x 32 pixels are used. Either way pixels are
+// stored row by row, with rows the width of the active resolution. Each pixel
+// holds one bit per XO-CHIP bitplane, giving four colours with both planes.
 pub struct Display {
-  pub memory: [u8; 2048],
+  pub memory: [u8; MAX_WIDTH * MAX_HEIGHT],
+  pub hires: bool,
+  // bitmask of the planes that drawing, clearing and scrolling apply to
+  pub planes: u8,
+}
+
+impl Default for Display {
+  fn default() -> Display {
+    Display::new()
+  }
 }
 
 impl Display {
   pub fn new() -> Display {
     Display {
-        memory: [0; 2048]
+        memory: [0; MAX_WIDTH * MAX_HEIGHT],
+        hires: false,
+        planes: 1
+    }
+  }
+
+  pub fn width(&self) -> usize {
+    if self.hires { MAX_WIDTH } else { LORES_WIDTH }
+  }
+
+  pub fn height(&self) -> usize {
+    if self.hires { MAX_HEIGHT } else { LORES_HEIGHT }
+  }
+
+  // the number of selected planes, each of which consumes its own sprite data
+  pub fn plane_count(&self) -> usize {
+    self.planes.count_ones() as usize
+  }
+
+  // switches resolution, which also clears every plane
+  pub fn set_hires(&mut self, hires: bool) {
+    self.hires = hires;
+    for pixel in self.memory.iter_mut() {
+      *pixel = 0;
     }
   }
 
   pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
-    self.memory[x + y * WIDTH] = on as u8;
+    let index = x + y * self.width();
+    if on {
+      self.memory[index] |= self.planes;
+    } else {
+      self.memory[index] &= !self.planes;
+    }
   }
 
-  pub fn get_pixel(&mut self, x: usize, y: usize) -> bool {
-    self.memory[x + y * WIDTH] == 1
+  pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+    self.memory[x + y * self.width()] & self.planes != 0
   }
 
   pub fn cls(&mut self) {
-    for x in 0..WIDTH {
-      for y in 0..HEIGHT {
-        self.set_pixel(x, y, false);
+    let planes = self.planes;
+    for pixel in self.memory.iter_mut() {
+      *pixel &= !planes;
+    }
+  }
+
+  pub fn scroll_down(&mut self, rows: usize) {
+    self.scroll(0, rows as isize);
+  }
+
+  pub fn scroll_up(&mut self, rows: usize) {
+    self.scroll(0, -(rows as isize));
+  }
+
+  pub fn scroll_right(&mut self, columns: usize) {
+    self.scroll(columns as isize, 0);
+  }
+
+  pub fn scroll_left(&mut self, columns: usize) {
+    self.scroll(-(columns as isize), 0);
+  }
+
+  // moves the selected planes by (dx, dy), pixels moved off the screen are lost
+  fn scroll(&mut self, dx: isize, dy: isize) {
+    let (width, height) = (self.width() as isize, self.height() as isize);
+    let planes = self.planes;
+    let source = self.memory;
+    for y in 0..height {
+      for x in 0..width {
+        let (sx, sy) = (x - dx, y - dy);
+        let moved = if sx >= 0 && sx < width && sy >= 0 && sy < height {
+          source[(sx + sy * width) as usize] & planes
+        } else {
+          0
+        };
+        let index = (x + y * width) as usize;
+        self.memory[index] = (self.memory[index] & !planes) | moved;
       }
     }
   }
 
-  pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-    let rows = sprite.len();
+  // draws an 8 pixel wide sprite, one byte per row, for each selected plane
+  pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], quirks: &Quirks) -> bool {
+    let rows: Vec<u16> = sprite.iter().map(|row| (*row as u16) << 8).collect();
+    self.draw_rows(x, y, &rows, quirks)
+  }
+
+  // draws a 16 x 16 SUPER-CHIP sprite, two bytes per row, for each selected plane
+  pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], quirks: &Quirks) -> bool {
+    let rows: Vec<u16> = sprite.chunks(2)
+      .map(|row| (row[0] as u16) << 8 | row.get(1).cloned().unwrap_or(0) as u16)
+      .collect();
+    self.draw_rows(x, y, &rows, quirks)
+  }
+
+  // the rows are split evenly between the selected planes, lowest plane first
+  fn draw_rows(&mut self, x: usize, y: usize, rows: &[u16], quirks: &Quirks) -> bool {
+    let count = self.plane_count();
+    if count == 0 || rows.is_empty() {
+      return false;
+    }
+    let planes = [1u8, 2].iter().filter(|plane| self.planes & **plane != 0).cloned();
+    let per_plane = (rows.len() / count).max(1);
+    let mut collision = false;
+    for (plane, rows) in planes.collect::<Vec<_>>().into_iter().zip(rows.chunks(per_plane)) {
+      collision |= self.draw_plane(x, y, rows, plane, quirks);
+    }
+    collision
+  }
+
+  // XORs rows of up to 16 pixels, most significant bit leftmost, onto a plane
+  fn draw_plane(&mut self, x: usize, y: usize, rows: &[u16], plane: u8, quirks: &Quirks) -> bool {
+    let (width, height) = (self.width(), self.height());
+    // the origin always wraps, the sprite itself is either clipped or wrapped
+    let x = x % width;
+    let y = y % height;
     let mut collision = false;
-    for j in 0..rows {
-      let row = sprite[j];
-      for i in 0..8 {
-        let new_value = row >> (7 - i) & 0x01;
-        if new_value == 1 {
-          let xi = (x + i) % WIDTH;
-          let yj = (y + j) % HEIGHT;
-          let old_value = self.get_pixel(xi, yj);
-          if old_value {
+    for (j, row) in rows.iter().enumerate() {
+      if quirks.clip_sprites && y + j >= height {
+        break;
+      }
+      for i in 0..16 {
+        if quirks.clip_sprites && x + i >= width {
+          break;
+        }
+        if row >> (15 - i) & 0x01 == 1 {
+          let index = (x + i) % width + (y + j) % height * width;
+          if self.memory[index] & plane != 0 {
             collision = true;
           }
-          self.set_pixel(xi, yj, (new_value == 1) ^ old_value);
+          self.memory[index] ^= plane;
         }
       }
     }
-    return collision;
+    collision
   }
 }
 
@@ -69,10 +183,32 @@ pub static FONT_SET: [u8; 80] = [
   0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
+// the SUPER-CHIP 8 x 10 font, loaded directly after FONT_SET
+pub static BIG_FONT_SET: [u8; 160] = [
+  0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+  0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+  0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+  0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+  0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+  0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+  0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+  0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+  0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+  0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
+
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
   use super::Display;
+  use quirks::Quirks;
 
   #[test]
   fn set_pixel() {
@@ -99,7 +235,7 @@ mod tests {
     
     let sprite: [u8; 2] = [0b00110011, 0b11001010];
 
-    display.draw(0, 0, &sprite);
+    display.draw(0, 0, &sprite, &Quirks::NONE);
 
     assert_eq!(false, display.get_pixel(0, 0));
     assert_eq!(false, display.get_pixel(1, 0));
@@ -125,15 +261,112 @@ mod tests {
     let mut display = Display::new();
     
     let mut sprite: [u8; 1] = [0b00110000];
-    let mut collision = display.draw(0, 0, &sprite);
+    let mut collision = display.draw(0, 0, &sprite, &Quirks::NONE);
     assert_eq!(false, collision);
 
     sprite = [0b00000011];
-    collision = display.draw(0, 0, &sprite);
+    collision = display.draw(0, 0, &sprite, &Quirks::NONE);
     assert_eq!(false, collision);
 
     sprite = [0b00000001];
-    collision = display.draw(0, 0, &sprite);
+    collision = display.draw(0, 0, &sprite, &Quirks::NONE);
     assert_eq!(true, collision);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn draw_wraps_or_clips() {
+    let mut display = Display::new();
+    let sprite: [u8; 2] = [0b11000000, 0b11000000];
+
+    display.draw(63, 31, &sprite, &Quirks::NONE);
+    assert!(display.get_pixel(63, 31));
+    assert!(display.get_pixel(0, 31));
+    assert!(display.get_pixel(63, 0));
+    assert!(display.get_pixel(0, 0));
+
+    display.cls();
+    display.draw(63 + 64, 31, &sprite, &Quirks::COSMAC_VIP);
+    assert!(display.get_pixel(63, 31), "the origin wraps");
+    assert!(!display.get_pixel(0, 31), "the sprite is clipped horizontally");
+    assert!(!display.get_pixel(63, 0), "the sprite is clipped vertically");
+  }
+
+  #[test]
+  fn hires() {
+    let mut display = Display::new();
+    assert_eq!((display.width(), display.height()), (64, 32));
+
+    display.set_pixel(1, 1, true);
+    display.set_hires(true);
+    assert_eq!((display.width(), display.height()), (128, 64));
+    assert!(!display.get_pixel(1, 1), "switching resolution clears the screen");
+
+    display.set_pixel(127, 63, true);
+    assert!(display.get_pixel(127, 63));
+  }
+
+  #[test]
+  fn draw_large() {
+    let mut display = Display::new();
+    display.set_hires(true);
+    let mut sprite = [0u8; 32];
+    sprite[0] = 0x80;
+    sprite[31] = 0x01;
+
+    assert!(!display.draw_large(100, 40, &sprite, &Quirks::NONE));
+    assert!(display.get_pixel(100, 40));
+    assert!(display.get_pixel(115, 55));
+    assert!(display.draw_large(100, 40, &sprite, &Quirks::NONE));
+  }
+
+  #[test]
+  fn scroll() {
+    let mut display = Display::new();
+    display.set_pixel(10, 10, true);
+
+    display.scroll_down(3);
+    assert!(!display.get_pixel(10, 10));
+    assert!(display.get_pixel(10, 13));
+
+    display.scroll_right(4);
+    assert!(display.get_pixel(14, 13));
+
+    display.scroll_left(4);
+    display.scroll_left(4);
+    assert!(display.get_pixel(6, 13));
+
+    display.scroll_left(8);
+    assert!(!display.get_pixel(0, 13), "pixels scrolled off the screen are lost");
+  }
+
+  #[test]
+  fn planes() {
+    let mut display = Display::new();
+    let sprite: [u8; 2] = [0b10000000, 0b01000000];
+
+    // with both planes selected the first row goes to plane 1, the second to plane 2
+    display.planes = 3;
+    display.draw(0, 0, &sprite, &Quirks::NONE);
+    assert_eq!(display.memory[0], 1);
+    assert_eq!(display.memory[1], 2);
+
+    display.planes = 2;
+    display.cls();
+    assert_eq!(display.memory[0], 1, "only the selected plane is cleared");
+    assert_eq!(display.memory[1], 0);
+
+    display.planes = 0;
+    assert!(!display.draw(0, 0, &sprite, &Quirks::NONE), "nothing is drawn without a plane");
+  }
+
+  #[test]
+  fn scroll_up_selected_plane() {
+    let mut display = Display::new();
+    display.memory[2 * 64] = 3;
+
+    display.planes = 2;
+    display.scroll_up(2);
+    assert_eq!(display.memory[0], 2, "the selected plane is scrolled");
+    assert_eq!(display.memory[2 * 64], 1, "the other plane is left in place");
+  }
+}
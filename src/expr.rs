@@ -0,0 +1,379 @@
+use std::error::Error;
+use std::fmt;
+
+use cpu::Cpu;
+use debugger::Register;
+
+// Expressions over the machine state, used as breakpoint conditions and in
+// logpoint messages, e.g.
+//
+//     V3 == 5 && I > 0x300
+//     [0x3F0] != 0            the byte of memory at an address
+//     DT == 0 || !(SP < 2)
+//     hit_count > 40          the times the breakpoint has been reached
+//
+// The registers are V0-VF, I, PC, SP, DT and ST, and frame is the frame
+// counter. Values are integers, with comparisons and logical operators
+// giving 1 for true and 0 for false. Numbers are decimal, 0x hex or 0b binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Expr>),
+    HitCount,
+    Frame,
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    // the 1-based column at which the error was found
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Error for ExprError {}
+
+// the state an expression is evaluated against
+pub struct Context<'a> {
+    pub cpu: &'a Cpu,
+    pub hit_count: u64,
+}
+
+// binary operators from the loosest binding to the tightest
+const PRECEDENCE: &[&[(&str, Op)]] = &[
+    &[("||", Op::LogicalOr)],
+    &[("&&", Op::LogicalAnd)],
+    &[("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("|", Op::Or)],
+    &[("^", Op::Xor)],
+    &[("&", Op::And)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+];
+
+struct Parser<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: String) -> Result<T, ExprError> {
+        Err(ExprError { column: self.offset + 1, message })
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.offset..]
+    }
+
+    // consumes the token if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let rest = self.rest();
+        if !rest.starts_with(token) {
+            return false;
+        }
+        // || and && must not be read as | and &
+        if (token == "|" || token == "&") && rest[1..].starts_with(token) {
+            return false;
+        }
+        self.offset += token.len();
+        true
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, ExprError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for &(token, op) in PRECEDENCE[level] {
+                if self.eat(token) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(Box::new(left), op, Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            if !self.eat(")") {
+                return self.error("expected ')'".to_string());
+            }
+            return Ok(expr);
+        }
+        if self.eat("[") {
+            let address = self.binary(0)?;
+            if !self.eat("]") {
+                return self.error("expected ']'".to_string());
+            }
+            return Ok(Expr::Memory(Box::new(address)));
+        }
+
+        self.skip_whitespace();
+        let len = self.rest().find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return match self.rest().chars().next() {
+                Some(c) => self.error(format!("unexpected '{}'", c)),
+                None => self.error("expected a value".to_string()),
+            };
+        }
+        let word = &self.rest()[..len];
+        let expr = match parse_number(word) {
+            Some(value) => Expr::Number(value),
+            None => match parse_register(word) {
+                Some(register) => Expr::Register(register),
+                None if word == "hit_count" => Expr::HitCount,
+                None if word == "frame" => Expr::Frame,
+                None => return self.error(format!("unknown name '{}'", word)),
+            },
+        };
+        self.offset += len;
+        Ok(expr)
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// a register name, in any case
+pub fn parse_register(text: &str) -> Option<Register> {
+    let upper = text.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Some(Register::I),
+        "PC" => Some(Register::Pc),
+        "SP" => Some(Register::Sp),
+        "DT" => Some(Register::Dt),
+        "ST" => Some(Register::St),
+        _ if upper.len() == 2 && upper.starts_with('V') =>
+            u8::from_str_radix(&upper[1..], 16).ok().map(Register::V),
+        _ => None,
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        let mut parser = Parser { text, offset: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.rest().chars().next() {
+            return parser.error(format!("unexpected '{}'", c));
+        }
+        Ok(expr)
+    }
+
+    pub fn evaluate(&self, context: &Context) -> i64 {
+        match *self {
+            Expr::Number(value) => value,
+            Expr::Register(register) => register.get(context.cpu) as i64,
+            // addresses outside of memory read as zero
+            Expr::Memory(ref address) => {
+                let address = address.evaluate(context);
+                if address >= 0 && (address as usize) < context.cpu.memory_size() {
+                    context.cpu.memory[address as usize] as i64
+                } else {
+                    0
+                }
+            },
+            Expr::HitCount => context.hit_count as i64,
+            Expr::Frame => context.cpu.frame as i64,
+            Expr::Not(ref expr) => (expr.evaluate(context) == 0) as i64,
+            Expr::Negate(ref expr) => expr.evaluate(context).wrapping_neg(),
+            Expr::Binary(ref left, op, ref right) => {
+                let left = left.evaluate(context);
+                // the logical operators short circuit
+                match op {
+                    Op::LogicalAnd if left == 0 => return 0,
+                    Op::LogicalOr if left != 0 => return 1,
+                    _ => {},
+                }
+                let right = right.evaluate(context);
+                match op {
+                    Op::Add => left.wrapping_add(right),
+                    Op::Sub => left.wrapping_sub(right),
+                    Op::And => left & right,
+                    Op::Or => left | right,
+                    Op::Xor => left ^ right,
+                    Op::Eq => (left == right) as i64,
+                    Op::Ne => (left != right) as i64,
+                    Op::Lt => (left < right) as i64,
+                    Op::Le => (left <= right) as i64,
+                    Op::Gt => (left > right) as i64,
+                    Op::Ge => (left >= right) as i64,
+                    Op::LogicalAnd | Op::LogicalOr => (right != 0) as i64,
+                }
+            },
+        }
+    }
+
+    pub fn is_true(&self, context: &Context) -> bool {
+        self.evaluate(context) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    // an expression and whether to format it in hex
+    Value(Expr, bool),
+}
+
+// A logpoint message, text with expressions in braces which are replaced by
+// their values, e.g. "score {V3}, sprite at {I:x}". A :x suffix formats the
+// value in hex, and {{ and }} are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    parts: Vec<Part>,
+}
+
+impl Message {
+    pub fn parse(text: &str) -> Result<Message, ExprError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let column = text.len() - rest.len() + 1;
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push(c);
+                rest = &rest[2..];
+            } else if c == '{' {
+                let end = match rest.find('}') {
+                    Some(end) => end,
+                    None => return Err(ExprError { column, message: "expected '}'".to_string() }),
+                };
+                let (source, hex) = match rest[1..end].strip_suffix(":x") {
+                    Some(source) => (source, true),
+                    None => (&rest[1..end], false),
+                };
+                let expr = Expr::parse(source).map_err(|error| {
+                    ExprError { column: column + error.column, ..error }
+                })?;
+                if !literal.is_empty() {
+                    parts.push(Part::Text(literal.split_off(0)));
+                }
+                parts.push(Part::Value(expr, hex));
+                rest = &rest[end + 1..];
+            } else if c == '}' {
+                return Err(ExprError { column, message: "unmatched '}'".to_string() });
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Message { parts })
+    }
+
+    pub fn format(&self, context: &Context) -> String {
+        self.parts.iter().map(|part| match *part {
+            Part::Text(ref text) => text.clone(),
+            Part::Value(ref expr, true) => format!("0x{:X}", expr.evaluate(context)),
+            Part::Value(ref expr, false) => expr.evaluate(context).to_string(),
+        }).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, Expr, ExprError, Message};
+    use cpu::Cpu;
+
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.v[3] = 5;
+        cpu.i = 0x310;
+        cpu.memory[0x3F0] = 7;
+        cpu.dt = 0;
+        cpu.st = 4;
+        cpu
+    }
+
+    fn evaluate(text: &str, hit_count: u64) -> i64 {
+        Expr::parse(text).unwrap().evaluate(&Context { cpu: &cpu(), hit_count })
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        assert_eq!(evaluate("V3 == 5 && I > 0x300", 0), 1);
+        assert_eq!(evaluate("v3 == 5 && I > 0x310", 0), 0);
+        assert_eq!(evaluate("[0x3F0] != 0", 0), 1);
+        assert_eq!(evaluate("[0x3E0 + 0x10] + 1", 0), 8);
+        assert_eq!(evaluate("DT == 0", 0), 1);
+        assert_eq!(evaluate("hit_count > 40", 40), 0);
+        assert_eq!(evaluate("hit_count > 40", 41), 1);
+        assert_eq!(evaluate("ST & 0b110 | 1", 0), 5);
+        assert_eq!(evaluate("!(V3 < 2) || [0x10000] == 1", 0), 1);
+        assert_eq!(evaluate("-V3 + 10 - 2", 0), 3);
+        assert_eq!(evaluate("V0 == 1 || V3 == 5 && ST == 4", 0), 1);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text| Expr::parse(text).unwrap_err();
+        assert_eq!(error("V3 == "), ExprError { column: 7, message: "expected a value".to_string() });
+        assert_eq!(error("VG > 1").message, "unknown name 'VG'");
+        assert_eq!(error("(V1 > 1").column, 8);
+        assert_eq!(error("[I").message, "expected ']'");
+        assert_eq!(error("V1 V2").column, 4);
+    }
+
+    #[test]
+    fn formats_messages() {
+        let message = Message::parse("V3={V3}, I={I:x} {{hit {hit_count}}}").unwrap();
+        assert_eq!(message.format(&Context { cpu: &cpu(), hit_count: 2 }), "V3=5, I=0x310 {hit 2}");
+
+        assert_eq!(Message::parse("{V3").unwrap_err().message, "expected '}'");
+        assert_eq!(Message::parse("ab {V3 +}").unwrap_err().column, 9);
+    }
+}
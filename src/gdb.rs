@@ -0,0 +1,512 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use cpu::CpuError;
+use debugger::{Debugger, Register, StopReason};
+
+// A stub for the GDB remote serial protocol, so that GDB or any other client
+// of the protocol can debug a ROM. The registers are described to the client
+// by target.xml, in the order below, with 16 bit registers little-endian:
+//
+//     0-15    V0-VF    8 bits
+//     16      I        16 bits
+//     17      PC       16 bits
+//     18      SP       8 bits, the number of stack frames in use
+//     19      DT       8 bits
+//     20      ST       8 bits
+//
+// Memory reads and writes, software breakpoints (Z0 / z0), single step,
+// reverse single step (bs) through the debugger's journal and continue are
+// supported. Continuing runs until a breakpoint, a fault, the
+// exit instruction, or the client interrupts. Monitor commands (qRcmd) work
+// with the debugger's symbols:
+//
+//     monitor break draw_paddle+4     sets a breakpoint by name
+//     monitor delete draw_paddle+4    removes it
+//     monitor bt                      the call stack, named
+const REGISTERS: [Register; 21] = [
+    Register::V(0), Register::V(1), Register::V(2), Register::V(3),
+    Register::V(4), Register::V(5), Register::V(6), Register::V(7),
+    Register::V(8), Register::V(9), Register::V(10), Register::V(11),
+    Register::V(12), Register::V(13), Register::V(14), Register::V(15),
+    Register::I, Register::Pc, Register::Sp, Register::Dt, Register::St,
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// instructions run between checks for an interrupt from the client
+const CONTINUE_SLICE: u64 = 10_000;
+
+fn register_size(register: Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+fn set_register(debugger: &mut Debugger, register: Register, value: u16) {
+    let cpu = &mut debugger.machine.cpu;
+    match register {
+        Register::V(x) => cpu.v[x as usize] = value as u8,
+        Register::I => cpu.i = value,
+        Register::Pc => cpu.pc = value,
+        Register::Sp => cpu.sp = (value as u8).min(cpu.stack.len() as u8),
+        Register::Dt => cpu.dt = value as u8,
+        Register::St => cpu.st = value as u8,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn encode_register(debugger: &Debugger, register: Register) -> String {
+    let value = register.get(&debugger.machine.cpu);
+    hex(&value.to_le_bytes()[..register_size(register)])
+}
+
+// the stop reply for why execution stopped
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Halted => "W00".to_string(),
+        StopReason::Fault(CpuError::UnknownOpcode { .. }) => "S04".to_string(),
+        StopReason::Fault(_) => "S0b".to_string(),
+        StopReason::InstructionLimit => "S02".to_string(),
+        StopReason::ReadWatchpoint { address, .. } => format!("T05rwatch:{:x};", address),
+        StopReason::WriteWatchpoint { address, .. } => format!("T05watch:{:x};", address),
+        _ => "S05".to_string(),
+    }
+}
+
+// the address and length of "addr,length"
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, len))
+}
+
+enum Input {
+    Packet(String),
+    Interrupt,
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    debugger: &'a mut Debugger,
+}
+
+impl<'a> Session<'a> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // the next packet or interrupt, skipping acknowledgements, or None at the
+    // end of the stream. Packets with a bad checksum are asked for again.
+    fn read_input(&mut self) -> io::Result<Option<Input>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Input::Interrupt)),
+                Some(b'$') => {},
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = String::from_utf8_lossy(&checksum).into_owned();
+            let actual = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            if u8::from_str_radix(&expected, 16).ok() != Some(actual) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(Input::Packet(String::from_utf8_lossy(&data).into_owned())));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())
+    }
+
+    // whether the client has sent an interrupt, without waiting for one
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let byte = self.read_byte();
+        self.stream.set_nonblocking(false)?;
+        match byte {
+            Ok(byte) => Ok(byte == Some(0x03)),
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn resume(&mut self) -> io::Result<StopReason> {
+        loop {
+            match self.debugger.run(CONTINUE_SLICE) {
+                StopReason::InstructionLimit => {},
+                reason => return Ok(reason),
+            }
+            if self.interrupted()? {
+                return Ok(StopReason::InstructionLimit);
+            }
+        }
+    }
+
+    fn read_memory(&self, text: &str) -> Option<String> {
+        let (address, len) = parse_range(text)?;
+        let end = address.checked_add(len).filter(|&end| end <= self.debugger.machine.cpu.memory_size())?;
+        Some(hex(&self.debugger.machine.cpu.memory[address..end]))
+    }
+
+    fn write_memory(&mut self, text: &str) -> Option<()> {
+        let mut parts = text.splitn(2, ':');
+        let (address, len) = parse_range(parts.next()?)?;
+        let data = unhex(parts.next()?).filter(|data| data.len() == len)?;
+        let end = address.checked_add(len).filter(|&end| end <= self.debugger.machine.cpu.memory_size())?;
+        self.debugger.machine.cpu.memory[address..end].copy_from_slice(&data);
+        Some(())
+    }
+
+    fn write_registers(&mut self, text: &str) -> Option<()> {
+        let data = unhex(text)?;
+        let total: usize = REGISTERS.iter().map(|&r| register_size(r)).sum();
+        if data.len() != total {
+            return None;
+        }
+        let mut offset = 0;
+        for &register in REGISTERS.iter() {
+            let size = register_size(register);
+            let value = data[offset..offset + size].iter().rev().fold(0, |value, &b| value << 8 | b as u16);
+            set_register(self.debugger, register, value);
+            offset += size;
+        }
+        Some(())
+    }
+
+    fn write_register(&mut self, text: &str) -> Option<()> {
+        let mut parts = text.splitn(2, '=');
+        let register = *REGISTERS.get(usize::from_str_radix(parts.next()?, 16).ok()?)?;
+        let data = unhex(parts.next()?).filter(|data| data.len() == register_size(register))?;
+        let value = data.iter().rev().fold(0, |value, &b| value << 8 | b as u16);
+        set_register(self.debugger, register, value);
+        Some(())
+    }
+
+    fn breakpoint(&mut self, text: &str, insert: bool) -> Option<()> {
+        let mut parts = text.splitn(3, ',');
+        if parts.next()? != "0" {
+            return None;
+        }
+        let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+        if insert {
+            self.debugger.add_breakpoint(address);
+        } else {
+            self.debugger.remove_breakpoint(address);
+        }
+        Some(())
+    }
+
+    // runs a monitor command, returning its output
+    fn monitor(&mut self, text: &str) -> Option<String> {
+        let command = String::from_utf8(unhex(text)?).ok()?;
+        let mut words = command.trim().splitn(2, ' ');
+        let location = |words: &mut dyn Iterator<Item = &str>| words.next().map(|location| location.trim().to_string());
+        match words.next()? {
+            "break" => {
+                let location = location(&mut words)?;
+                Some(match self.debugger.add_breakpoint_at(&location) {
+                    Some(address) => format!("breakpoint at {}\n", self.debugger.symbols.describe(address)),
+                    None => format!("unknown location '{}'\n", location),
+                })
+            },
+            "delete" => {
+                let location = location(&mut words)?;
+                Some(match self.debugger.symbols.resolve(&location) {
+                    Some(address) if self.debugger.remove_breakpoint(address) => "OK\n".to_string(),
+                    _ => format!("no breakpoint at '{}'\n", location),
+                })
+            },
+            "bt" => Some(self.debugger.backtrace().iter().map(|line| format!("{}\n", line)).collect()),
+            _ => None,
+        }
+    }
+
+    fn features(&self, text: &str) -> Option<String> {
+        let (offset, len) = parse_range(text.strip_prefix("target.xml:")?)?;
+        let xml = TARGET_XML.as_bytes();
+        let start = offset.min(xml.len());
+        let end = offset.saturating_add(len).min(xml.len());
+        let marker = if end == xml.len() { 'l' } else { 'm' };
+        Some(format!("{}{}", marker, String::from_utf8_lossy(&xml[start..end])))
+    }
+
+    // handles a packet, returning the reply, or None to end the session
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let ok_or_error = |result: Option<()>| result.map_or("E01".to_string(), |_| "OK".to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => REGISTERS.iter().map(|&r| encode_register(self.debugger, r)).collect(),
+            "G" => ok_or_error(self.write_registers(args)),
+            "p" => usize::from_str_radix(args, 16).ok()
+                .and_then(|n| REGISTERS.get(n))
+                .map_or("E01".to_string(), |&r| encode_register(self.debugger, r)),
+            "P" => ok_or_error(self.write_register(args)),
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => ok_or_error(self.write_memory(args)),
+            "Z" => ok_or_error(self.breakpoint(args, true)),
+            "z" => ok_or_error(self.breakpoint(args, false)),
+            "s" => stop_reply(self.debugger.step()),
+            // at the start of the journal there is nothing to step back to
+            "b" if args == "s" => if self.debugger.step_back() { "S05" } else { "T05replaylog:begin;" }.to_string(),
+            "c" => stop_reply(self.resume()?),
+            "H" => "OK".to_string(),
+            "D" => {
+                self.send("OK")?;
+                return Ok(None);
+            },
+            "k" => return Ok(None),
+            _ if packet.starts_with("qSupported") => "PacketSize=1000;qXfer:features:read+;ReverseStep+".to_string(),
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet.starts_with("qRcmd,") => self.monitor(&packet["qRcmd,".len()..])
+                .map_or("E01".to_string(), |output| hex(output.as_bytes())),
+            _ => match packet.strip_prefix("qXfer:features:read:") {
+                Some(args) => self.features(args).unwrap_or_else(|| "E00".to_string()),
+                // an empty reply tells the client the packet is not supported
+                None => String::new(),
+            },
+        };
+        Ok(Some(reply))
+    }
+}
+
+// runs a debugging session with the client on the stream, until it detaches,
+// kills the target or disconnects
+pub fn serve(stream: TcpStream, debugger: &mut Debugger) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut session = Session { stream, debugger };
+    while let Some(input) = session.read_input()? {
+        let packet = match input {
+            Input::Packet(packet) => packet,
+            // stopped already, so an interrupt just reports the stop
+            Input::Interrupt => "?".to_string(),
+        };
+        match session.handle(&packet)? {
+            Some(reply) => session.send(&reply)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// waits for a client to connect at address, e.g. "127.0.0.1:9000", then
+// serves it
+pub fn listen(address: &str, debugger: &mut Debugger) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    serve(stream, debugger)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::serve;
+    use cpu::Cpu;
+    use debugger::Debugger;
+    use machine::Machine;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes()).unwrap();
+        }
+
+        // reads the acknowledgement then the reply to a packet
+        fn reply(&mut self) -> String {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            let mut data = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            let expected = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            assert_eq!(format!("{:02x}", expected).as_bytes(), &checksum);
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+    }
+
+    // serves a debugger for a small program in another thread
+    fn connect() -> (Client, thread::JoinHandle<Debugger>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cpu = Cpu::new();
+            let program = [
+                0x60, 0x05, // 200: LD V0, 5
+                0xA3, 0x00, // 202: LD I, 0x300
+                0x70, 0x01, // 204: ADD V0, 1
+                0x12, 0x04, // 206: JP 0x204
+            ];
+            cpu.load_rom(&program).unwrap();
+            let mut debugger = Debugger::new(Machine::new(cpu));
+            debugger.symbols.add_label("main", 0x200);
+            debugger.symbols.add_label("loop", 0x204);
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &mut debugger).unwrap();
+            debugger
+        });
+        (Client { stream: TcpStream::connect(address).unwrap() }, server)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let (mut client, server) = connect();
+        assert!(client.request("qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = client.request("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#));
+        assert!(client.request("qXfer:features:read:target.xml:0,10").starts_with('m'));
+        assert_eq!(client.request("?"), "S05");
+
+        let registers = client.request("g");
+        assert_eq!(registers.len(), (16 + 2 * 2 + 3) * 2);
+        assert_eq!(&registers[32..40], "00000002", "I then PC, little-endian");
+
+        assert_eq!(client.request("P10=3412"), "OK");
+        assert_eq!(client.request("p10"), "3412");
+        assert_eq!(client.request("P3=ff"), "OK");
+        assert_eq!(client.request("p3"), "ff");
+        assert_eq!(client.request("p15"), "E01");
+
+        assert_eq!(client.request("m200,4"), "6005a300");
+        assert_eq!(client.request("M300,3:0a0b0c"), "OK");
+        assert_eq!(client.request("m300,3"), "0a0b0c");
+        assert_eq!(client.request("mfff,2"), "E01", "beyond the 4 KiB address space");
+        assert_eq!(client.request("vMustReplyEmpty"), "");
+
+        client.send("D");
+        assert_eq!(client.reply(), "OK");
+        let debugger = server.join().unwrap();
+        assert_eq!(debugger.machine.cpu.i, 0x1234);
+        assert_eq!(debugger.machine.cpu.v[3], 0xFF);
+        assert_eq!(&debugger.machine.cpu.memory[0x300..0x303], &[0x0A, 0x0B, 0x0C]);
+    }
+
+    #[test]
+    fn breakpoints_step_and_continue() {
+        let (mut client, server) = connect();
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p11"), "0202");
+        assert_eq!(client.request("bs"), "S05");
+        assert_eq!(client.request("p11"), "0002");
+        assert_eq!(client.request("bs"), "T05replaylog:begin;");
+        assert_eq!(client.request("s"), "S05");
+
+        assert_eq!(client.request("Z0,206,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p11"), "0602");
+        assert_eq!(client.request("p0"), "06");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0"), "07");
+
+        // monitor commands name locations by their symbols
+        let monitor = |client: &mut Client, command: &str| {
+            let reply = client.request(&format!("qRcmd,{}", super::hex(command.as_bytes())));
+            String::from_utf8(super::unhex(&reply).unwrap()).unwrap()
+        };
+        assert_eq!(monitor(&mut client, "break main"), "breakpoint at 0x0200 main\n");
+        assert_eq!(monitor(&mut client, "break loop+2"), "breakpoint at 0x0206 loop+2\n");
+        assert_eq!(monitor(&mut client, "break nowhere"), "unknown location 'nowhere'\n");
+        assert_eq!(monitor(&mut client, "bt"), "#0 0x0206 loop+2\n");
+        assert_eq!(monitor(&mut client, "delete loop+2"), "OK\n");
+        assert_eq!(monitor(&mut client, "delete main"), "OK\n");
+        assert_eq!(client.request("qRcmd,6a756d70"), "E01");
+
+        // with the breakpoint removed, continue runs until interrupted
+        assert_eq!(client.request("z0,206,2"), "OK");
+        client.send("c");
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "S02");
+
+        // a corrupt packet is asked for again
+        client.stream.write_all(b"$g#00").unwrap();
+        let mut nak = [0];
+        client.stream.read_exact(&mut nak).unwrap();
+        assert_eq!(nak[0], b'-');
+
+        client.send("k");
+        let debugger = server.join().unwrap();
+        assert!(debugger.breakpoints().is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use display::Display;
+
+// the colours used for each combination of XO-CHIP planes, as in chip8.js
+pub const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [0x33, 0xff, 0x66], [0x11, 0x77, 0x33], [0xcc, 0xff, 0xdd]];
+
+// encodes the display as a binary PBM, with a pixel set if it is lit in any plane
+pub fn pbm(display: &Display) -> Vec<u8> {
+    let (width, height) = (display.width(), display.height());
+    let mut out = format!("P4\n{} {}\n", width, height).into_bytes();
+    for y in 0..height {
+        for x in (0..width).step_by(8) {
+            let mut byte = 0;
+            for bit in 0..8 {
+                if x + bit < width && display.memory[x + bit + y * width] != 0 {
+                    byte |= 0x80 >> bit;
+                }
+            }
+            out.push(byte);
+        }
+    }
+    out
+}
+
+// encodes the display as a PNG using the palette
+pub fn display_png(display: &Display) -> Vec<u8> {
+    let (width, height) = (display.width(), display.height());
+    let pixels: Vec<[u8; 3]> = display.memory[..width * height].iter()
+        .map(|&pixel| PALETTE[pixel as usize & 3])
+        .collect();
+    png(width, height, &pixels)
+}
+
+// encodes width * height RGB pixels, row by row, as a PNG. The image data is
+// stored uncompressed, which keeps the encoder small.
+pub fn png(width: usize, height: usize, pixels: &[[u8; 3]]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // each row is preceded by its filter type, none
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        for pixel in row {
+            raw.extend_from_slice(pixel);
+        }
+    }
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// wraps data in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32, display_png, pbm, png};
+    use display::Display;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn encode_pbm() {
+        let mut display = Display::new();
+        display.set_pixel(0, 0, true);
+        display.set_pixel(9, 1, true);
+        let image = pbm(&display);
+        let header = b"P4\n64 32\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + 8 * 32);
+        assert_eq!(image[header.len()], 0x80);
+        assert_eq!(image[header.len() + 8 + 1], 0x40);
+    }
+
+    #[test]
+    fn encode_png() {
+        let image = png(2, 1, &[[1, 2, 3], [4, 5, 6]]);
+        assert_eq!(&image[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&image[12..16], b"IHDR");
+        assert_eq!(&image[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&image[image.len() - 8..image.len() - 4], b"IEND");
+        // the stored block holds the filter byte then the pixels
+        let idat = 8 + 25 + 8;
+        assert_eq!(&image[idat + 7..idat + 14], &[0, 1, 2, 3, 4, 5, 6]);
+
+        let display = Display::new();
+        assert_eq!(&display_png(&display)[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+    }
+}
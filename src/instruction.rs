@@ -0,0 +1,366 @@
+// A decoded instruction, covering CHIP-8, SUPER-CHIP 1.1 and XO-CHIP.
+// Register operands are indices into V, and are always in the range 0 - 15.
+// Variants are named after the Cowgod mnemonics, with the operand types
+// disambiguating instructions which share a mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 00Cn - SCD nibble
+    ScrollDown(u8),
+    // 00Dn - SCU nibble (XO-CHIP)
+    ScrollUp(u8),
+    // 00E0 - CLS
+    Cls,
+    // 00EE - RET
+    Ret,
+    // 00FB - SCR
+    ScrollRight,
+    // 00FC - SCL
+    ScrollLeft,
+    // 00FD - EXIT
+    Exit,
+    // 00FE - LOW
+    Low,
+    // 00FF - HIGH
+    High,
+    // 1nnn - JP addr
+    Jp(u16),
+    // 2nnn - CALL addr
+    Call(u16),
+    // 3xkk - SE Vx, byte
+    SeByte(u8, u8),
+    // 4xkk - SNE Vx, byte
+    SneByte(u8, u8),
+    // 5xy0 - SE Vx, Vy
+    SeReg(u8, u8),
+    // 5xy2 - SAVE Vx - Vy (XO-CHIP)
+    SaveRange(u8, u8),
+    // 5xy3 - LOAD Vx - Vy (XO-CHIP)
+    LoadRange(u8, u8),
+    // 6xkk - LD Vx, byte
+    LdByte(u8, u8),
+    // 7xkk - ADD Vx, byte
+    AddByte(u8, u8),
+    // 8xy0 - LD Vx, Vy
+    LdReg(u8, u8),
+    // 8xy1 - OR Vx, Vy
+    Or(u8, u8),
+    // 8xy2 - AND Vx, Vy
+    And(u8, u8),
+    // 8xy3 - XOR Vx, Vy
+    Xor(u8, u8),
+    // 8xy4 - ADD Vx, Vy
+    AddReg(u8, u8),
+    // 8xy5 - SUB Vx, Vy
+    Sub(u8, u8),
+    // 8xy6 - SHR Vx {, Vy}
+    Shr(u8, u8),
+    // 8xy7 - SUBN Vx, Vy
+    Subn(u8, u8),
+    // 8xyE - SHL Vx {, Vy}
+    Shl(u8, u8),
+    // 9xy0 - SNE Vx, Vy
+    SneReg(u8, u8),
+    // Annn - LD I, addr
+    LdI(u16),
+    // Bnnn - JP V0, addr
+    JpV0(u16),
+    // Cxkk - RND Vx, byte
+    Rnd(u8, u8),
+    // Dxyn - DRW Vx, Vy, nibble, where a nibble of 0 draws a 16 x 16 sprite
+    Drw(u8, u8, u8),
+    // Ex9E - SKP Vx
+    Skp(u8),
+    // ExA1 - SKNP Vx
+    Sknp(u8),
+    // F000 nnnn - LD I, long nnnn (XO-CHIP), the address is the following word
+    LdILong,
+    // Fn01 - PLANE n (XO-CHIP)
+    Plane(u8),
+    // F002 - AUDIO (XO-CHIP)
+    Audio,
+    // Fx07 - LD Vx, DT
+    LdVxDt(u8),
+    // Fx0A - LD Vx, K
+    LdVxK(u8),
+    // Fx15 - LD DT, Vx
+    LdDtVx(u8),
+    // Fx18 - LD ST, Vx
+    LdStVx(u8),
+    // Fx1E - ADD I, Vx
+    AddIVx(u8),
+    // Fx29 - LD F, Vx
+    LdFVx(u8),
+    // Fx30 - LD HF, Vx
+    LdHfVx(u8),
+    // Fx33 - LD B, Vx
+    LdBVx(u8),
+    // Fx3A - PITCH Vx (XO-CHIP)
+    Pitch(u8),
+    // Fx55 - LD [I], Vx
+    LdIVx(u8),
+    // Fx65 - LD Vx, [I]
+    LdVxI(u8),
+    // Fx75 - LD R, Vx
+    LdRVx(u8),
+    // Fx85 - LD Vx, R
+    LdVxR(u8),
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        use self::Instruction::*;
+
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+
+        let instruction = match (opcode >> 12, x, y, n) {
+            (0x0, 0, 0xC, _) => ScrollDown(n),
+            (0x0, 0, 0xD, _) => ScrollUp(n),
+            (0x0, 0, 0xE, 0x0) => Cls,
+            (0x0, 0, 0xE, 0xE) => Ret,
+            (0x0, 0, 0xF, 0xB) => ScrollRight,
+            (0x0, 0, 0xF, 0xC) => ScrollLeft,
+            (0x0, 0, 0xF, 0xD) => Exit,
+            (0x0, 0, 0xF, 0xE) => Low,
+            (0x0, 0, 0xF, 0xF) => High,
+            (0x1, _, _, _) => Jp(nnn),
+            (0x2, _, _, _) => Call(nnn),
+            (0x3, _, _, _) => SeByte(x, kk),
+            (0x4, _, _, _) => SneByte(x, kk),
+            (0x5, _, _, 0x0) => SeReg(x, y),
+            (0x5, _, _, 0x2) => SaveRange(x, y),
+            (0x5, _, _, 0x3) => LoadRange(x, y),
+            (0x6, _, _, _) => LdByte(x, kk),
+            (0x7, _, _, _) => AddByte(x, kk),
+            (0x8, _, _, 0x0) => LdReg(x, y),
+            (0x8, _, _, 0x1) => Or(x, y),
+            (0x8, _, _, 0x2) => And(x, y),
+            (0x8, _, _, 0x3) => Xor(x, y),
+            (0x8, _, _, 0x4) => AddReg(x, y),
+            (0x8, _, _, 0x5) => Sub(x, y),
+            (0x8, _, _, 0x6) => Shr(x, y),
+            (0x8, _, _, 0x7) => Subn(x, y),
+            (0x8, _, _, 0xE) => Shl(x, y),
+            (0x9, _, _, 0x0) => SneReg(x, y),
+            (0xA, _, _, _) => LdI(nnn),
+            (0xB, _, _, _) => JpV0(nnn),
+            (0xC, _, _, _) => Rnd(x, kk),
+            (0xD, _, _, _) => Drw(x, y, n),
+            (0xE, _, 0x9, 0xE) => Skp(x),
+            (0xE, _, 0xA, 0x1) => Sknp(x),
+            (0xF, 0, 0x0, 0x0) => LdILong,
+            (0xF, _, 0x0, 0x1) => Plane(x),
+            (0xF, 0, 0x0, 0x2) => Audio,
+            (0xF, _, 0x0, 0x7) => LdVxDt(x),
+            (0xF, _, 0x0, 0xA) => LdVxK(x),
+            (0xF, _, 0x1, 0x5) => LdDtVx(x),
+            (0xF, _, 0x1, 0x8) => LdStVx(x),
+            (0xF, _, 0x1, 0xE) => AddIVx(x),
+            (0xF, _, 0x2, 0x9) => LdFVx(x),
+            (0xF, _, 0x3, 0x0) => LdHfVx(x),
+            (0xF, _, 0x3, 0x3) => LdBVx(x),
+            (0xF, _, 0x3, 0xA) => Pitch(x),
+            (0xF, _, 0x5, 0x5) => LdIVx(x),
+            (0xF, _, 0x6, 0x5) => LdVxI(x),
+            (0xF, _, 0x7, 0x5) => LdRVx(x),
+            (0xF, _, 0x8, 0x5) => LdVxR(x),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    pub fn encode(&self) -> u16 {
+        use self::Instruction::*;
+
+        fn xkk(op: u16, x: u8, kk: u8) -> u16 {
+            op << 12 | (x as u16 & 0xF) << 8 | kk as u16
+        }
+        fn xyn(op: u16, x: u8, y: u8, n: u8) -> u16 {
+            op << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (n as u16 & 0xF)
+        }
+        fn nnn(op: u16, nnn: u16) -> u16 {
+            op << 12 | (nnn & 0x0FFF)
+        }
+
+        match *self {
+            ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(addr) => nnn(0x1, addr),
+            Call(addr) => nnn(0x2, addr),
+            SeByte(x, kk) => xkk(0x3, x, kk),
+            SneByte(x, kk) => xkk(0x4, x, kk),
+            SeReg(x, y) => xyn(0x5, x, y, 0x0),
+            SaveRange(x, y) => xyn(0x5, x, y, 0x2),
+            LoadRange(x, y) => xyn(0x5, x, y, 0x3),
+            LdByte(x, kk) => xkk(0x6, x, kk),
+            AddByte(x, kk) => xkk(0x7, x, kk),
+            LdReg(x, y) => xyn(0x8, x, y, 0x0),
+            Or(x, y) => xyn(0x8, x, y, 0x1),
+            And(x, y) => xyn(0x8, x, y, 0x2),
+            Xor(x, y) => xyn(0x8, x, y, 0x3),
+            AddReg(x, y) => xyn(0x8, x, y, 0x4),
+            Sub(x, y) => xyn(0x8, x, y, 0x5),
+            Shr(x, y) => xyn(0x8, x, y, 0x6),
+            Subn(x, y) => xyn(0x8, x, y, 0x7),
+            Shl(x, y) => xyn(0x8, x, y, 0xE),
+            SneReg(x, y) => xyn(0x9, x, y, 0x0),
+            LdI(addr) => nnn(0xA, addr),
+            JpV0(addr) => nnn(0xB, addr),
+            Rnd(x, kk) => xkk(0xC, x, kk),
+            Drw(x, y, n) => xyn(0xD, x, y, n),
+            Skp(x) => xkk(0xE, x, 0x9E),
+            Sknp(x) => xkk(0xE, x, 0xA1),
+            LdILong => 0xF000,
+            Plane(n) => xkk(0xF, n, 0x01),
+            Audio => 0xF002,
+            LdVxDt(x) => xkk(0xF, x, 0x07),
+            LdVxK(x) => xkk(0xF, x, 0x0A),
+            LdDtVx(x) => xkk(0xF, x, 0x15),
+            LdStVx(x) => xkk(0xF, x, 0x18),
+            AddIVx(x) => xkk(0xF, x, 0x1E),
+            LdFVx(x) => xkk(0xF, x, 0x29),
+            LdHfVx(x) => xkk(0xF, x, 0x30),
+            LdBVx(x) => xkk(0xF, x, 0x33),
+            Pitch(x) => xkk(0xF, x, 0x3A),
+            LdIVx(x) => xkk(0xF, x, 0x55),
+            LdVxI(x) => xkk(0xF, x, 0x65),
+            LdRVx(x) => xkk(0xF, x, 0x75),
+            LdVxR(x) => xkk(0xF, x, 0x85),
+        }
+    }
+
+    // the opcode pattern, e.g. "Dxyn", identifying the kind of instruction
+    // whatever its operands
+    pub fn kind(&self) -> &'static str {
+        use self::Instruction::*;
+
+        match *self {
+            ScrollDown(_) => "00Cn",
+            ScrollUp(_) => "00Dn",
+            Cls => "00E0",
+            Ret => "00EE",
+            ScrollRight => "00FB",
+            ScrollLeft => "00FC",
+            Exit => "00FD",
+            Low => "00FE",
+            High => "00FF",
+            Jp(_) => "1nnn",
+            Call(_) => "2nnn",
+            SeByte(..) => "3xkk",
+            SneByte(..) => "4xkk",
+            SeReg(..) => "5xy0",
+            SaveRange(..) => "5xy2",
+            LoadRange(..) => "5xy3",
+            LdByte(..) => "6xkk",
+            AddByte(..) => "7xkk",
+            LdReg(..) => "8xy0",
+            Or(..) => "8xy1",
+            And(..) => "8xy2",
+            Xor(..) => "8xy3",
+            AddReg(..) => "8xy4",
+            Sub(..) => "8xy5",
+            Shr(..) => "8xy6",
+            Subn(..) => "8xy7",
+            Shl(..) => "8xyE",
+            SneReg(..) => "9xy0",
+            LdI(_) => "Annn",
+            JpV0(_) => "Bnnn",
+            Rnd(..) => "Cxkk",
+            Drw(..) => "Dxyn",
+            Skp(_) => "Ex9E",
+            Sknp(_) => "ExA1",
+            LdILong => "F000",
+            Plane(_) => "Fn01",
+            Audio => "F002",
+            LdVxDt(_) => "Fx07",
+            LdVxK(_) => "Fx0A",
+            LdDtVx(_) => "Fx15",
+            LdStVx(_) => "Fx18",
+            AddIVx(_) => "Fx1E",
+            LdFVx(_) => "Fx29",
+            LdHfVx(_) => "Fx30",
+            LdBVx(_) => "Fx33",
+            Pitch(_) => "Fx3A",
+            LdIVx(_) => "Fx55",
+            LdVxI(_) => "Fx65",
+            LdRVx(_) => "Fx75",
+            LdVxR(_) => "Fx85",
+        }
+    }
+
+    // instructions which only exist on XO-CHIP
+    pub fn is_xo_chip(&self) -> bool {
+        use self::Instruction::*;
+
+        matches!(*self, ScrollUp(_) | SaveRange(..) | LoadRange(..) | LdILong | Plane(_) | Audio | Pitch(_))
+    }
+
+    // the size in bytes, including the operand word of LD I, long nnnn
+    pub fn size(&self) -> u16 {
+        if *self == Instruction::LdILong { 4 } else { 2 }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+    use super::Instruction::*;
+
+    #[test]
+    fn decode_encode_round_trip() {
+        let mut decoded = 0;
+        for opcode in 0..=0xFFFF {
+            if let Some(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{:?} round trips", instruction);
+                decoded += 1;
+            }
+        }
+        assert_eq!(decoded, 44_585, "every opcode in the instruction set decodes");
+    }
+
+    #[test]
+    fn decode_every_instruction() {
+        let cases = [
+            (0x00C3, ScrollDown(3)), (0x00D4, ScrollUp(4)), (0x00E0, Cls), (0x00EE, Ret),
+            (0x00FB, ScrollRight), (0x00FC, ScrollLeft), (0x00FD, Exit), (0x00FE, Low),
+            (0x00FF, High), (0x1234, Jp(0x234)), (0x2345, Call(0x345)),
+            (0x3A12, SeByte(0xA, 0x12)), (0x4B34, SneByte(0xB, 0x34)), (0x5120, SeReg(1, 2)),
+            (0x5122, SaveRange(1, 2)), (0x5123, LoadRange(1, 2)), (0x6C56, LdByte(0xC, 0x56)),
+            (0x7D78, AddByte(0xD, 0x78)), (0x8120, LdReg(1, 2)), (0x8121, Or(1, 2)),
+            (0x8122, And(1, 2)), (0x8123, Xor(1, 2)), (0x8124, AddReg(1, 2)),
+            (0x8125, Sub(1, 2)), (0x8126, Shr(1, 2)), (0x8127, Subn(1, 2)),
+            (0x812E, Shl(1, 2)), (0x9120, SneReg(1, 2)), (0xA456, LdI(0x456)),
+            (0xB567, JpV0(0x567)), (0xC1FF, Rnd(1, 0xFF)), (0xD125, Drw(1, 2, 5)),
+            (0xD120, Drw(1, 2, 0)), (0xE19E, Skp(1)), (0xE1A1, Sknp(1)), (0xF000, LdILong),
+            (0xF201, Plane(2)), (0xF002, Audio), (0xF107, LdVxDt(1)), (0xF10A, LdVxK(1)),
+            (0xF115, LdDtVx(1)), (0xF118, LdStVx(1)), (0xF11E, AddIVx(1)), (0xF129, LdFVx(1)),
+            (0xF130, LdHfVx(1)), (0xF133, LdBVx(1)), (0xF13A, Pitch(1)), (0xF155, LdIVx(1)),
+            (0xF165, LdVxI(1)), (0xF175, LdRVx(1)), (0xF185, LdVxR(1)),
+        ];
+        for &(opcode, instruction) in cases.iter() {
+            assert_eq!(Instruction::decode(opcode), Some(instruction));
+            assert_eq!(instruction.encode(), opcode);
+            let matches = format!("{:04X}", opcode).chars().zip(instruction.kind().chars())
+                .all(|(digit, pattern)| pattern.is_lowercase() || pattern == digit);
+            assert!(matches, "0x{:04X} is not of kind {}", opcode, instruction.kind());
+        }
+    }
+
+    #[test]
+    fn decode_unknown() {
+        for opcode in &[0x0000, 0x0123, 0x00E1, 0x5121, 0x8128, 0x9121, 0xE100, 0xF1FF, 0xF100] {
+            assert_eq!(Instruction::decode(*opcode), None, "0x{:04X} is not an instruction", opcode);
+        }
+    }
+}
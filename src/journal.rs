@@ -0,0 +1,280 @@
+use std::collections::VecDeque;
+
+use cpu::{Cpu, MemoryAccess};
+use display::{MAX_HEIGHT, MAX_WIDTH};
+use instruction::Instruction;
+use rand::CMWC_CYCLE;
+
+// the prior value of one piece of state an instruction changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    V(u8, u8),
+    I(u16),
+    Pc(u16),
+    Sp(u8),
+    Dt(u8),
+    St(u8),
+    Stack(u8, u16),
+    Rpl(u8, u8),
+    Memory(u16, u8),
+    // an index into the display memory, and the planes lit there
+    Pixel(u16, u8),
+    Hires(bool),
+    Planes(u8),
+    Audio(u8, u8),
+    Pitch(u8),
+    Halted(bool),
+    Frame(u64),
+    // the generator's carry and index, and the value it overwrote
+    Random { c: u32, i: usize, q: u32 },
+}
+
+// The state before an instruction, captured by Journal::begin. Only the
+// memory the instruction writes is copied, and the display only if it draws.
+pub struct Pending {
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    sp: u8,
+    dt: u8,
+    st: u8,
+    stack: [u16; 16],
+    rpl: [u8; 16],
+    memory: Option<(usize, Vec<u8>)>,
+    display: Option<Box<[u8; MAX_WIDTH * MAX_HEIGHT]>>,
+    hires: bool,
+    planes: u8,
+    audio: [u8; 16],
+    pitch: u8,
+    halted: bool,
+    frame: u64,
+    random: (u32, usize, u32),
+}
+
+// whether the instruction may change the display
+fn draws(instruction: Instruction) -> bool {
+    use instruction::Instruction::*;
+
+    matches!(instruction, Cls | Drw(..) | ScrollDown(_) | ScrollUp(_) | ScrollRight | ScrollLeft | Low | High)
+}
+
+// Records, for each instruction executed, the prior values of the state it
+// changed, so that instructions can be undone one at a time. Only the last
+// depth instructions are kept.
+pub struct Journal {
+    depth: usize,
+    // the changes made by each instruction, oldest first
+    entries: VecDeque<Vec<Change>>,
+}
+
+impl Journal {
+    pub fn new(depth: usize) -> Journal {
+        Journal { depth, entries: VecDeque::new() }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // changes the number of instructions kept, discarding the oldest
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.entries.len() > depth {
+            self.entries.pop_front();
+        }
+    }
+
+    // the number of instructions which can be undone
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // captures the state the instruction at the pc may change, to be passed
+    // to end once it, and anything else making up the step, has run
+    pub fn begin(&self, cpu: &Cpu) -> Pending {
+        let instruction = cpu.current_instruction();
+        let memory = match instruction.and_then(|instruction| cpu.memory_access(instruction)) {
+            Some(MemoryAccess::Write(range)) => {
+                let range = range.start.min(cpu.memory_size())..range.end.min(cpu.memory_size());
+                Some((range.start, cpu.memory[range].to_vec()))
+            },
+            _ => None,
+        };
+        Pending {
+            v: cpu.v,
+            i: cpu.i,
+            pc: cpu.pc,
+            sp: cpu.sp,
+            dt: cpu.dt,
+            st: cpu.st,
+            stack: cpu.stack,
+            rpl: cpu.rpl,
+            memory,
+            display: instruction.filter(|&instruction| draws(instruction)).map(|_| Box::new(cpu.display.memory)),
+            hires: cpu.display.hires,
+            planes: cpu.display.planes,
+            audio: cpu.audio_pattern,
+            pitch: cpu.pitch,
+            halted: cpu.halted,
+            frame: cpu.frame,
+            // the generator advances its index, then overwrites the value there
+            random: (cpu.rand.c, cpu.rand.i, cpu.rand.q[(cpu.rand.i + 1) % CMWC_CYCLE]),
+        }
+    }
+
+    // records what changed since begin
+    pub fn end(&mut self, before: Pending, cpu: &Cpu) {
+        if self.depth == 0 {
+            return;
+        }
+        let mut changes = Vec::new();
+        for x in 0..16 {
+            if before.v[x] != cpu.v[x] {
+                changes.push(Change::V(x as u8, before.v[x]));
+            }
+            if before.stack[x] != cpu.stack[x] {
+                changes.push(Change::Stack(x as u8, before.stack[x]));
+            }
+            if before.rpl[x] != cpu.rpl[x] {
+                changes.push(Change::Rpl(x as u8, before.rpl[x]));
+            }
+            if before.audio[x] != cpu.audio_pattern[x] {
+                changes.push(Change::Audio(x as u8, before.audio[x]));
+            }
+        }
+        if let Some((start, ref bytes)) = before.memory {
+            for (address, &byte) in (start..).zip(bytes) {
+                if cpu.memory[address] != byte {
+                    changes.push(Change::Memory(address as u16, byte));
+                }
+            }
+        }
+        if let Some(ref display) = before.display {
+            for (index, &pixel) in display.iter().enumerate() {
+                if cpu.display.memory[index] != pixel {
+                    changes.push(Change::Pixel(index as u16, pixel));
+                }
+            }
+        }
+        let (c, i, q) = before.random;
+        if (c, i) != (cpu.rand.c, cpu.rand.i) {
+            changes.push(Change::Random { c, i, q });
+        }
+        let registers = [
+            (before.i != cpu.i, Change::I(before.i)),
+            (before.pc != cpu.pc, Change::Pc(before.pc)),
+            (before.sp != cpu.sp, Change::Sp(before.sp)),
+            (before.dt != cpu.dt, Change::Dt(before.dt)),
+            (before.st != cpu.st, Change::St(before.st)),
+            (before.hires != cpu.display.hires, Change::Hires(before.hires)),
+            (before.planes != cpu.display.planes, Change::Planes(before.planes)),
+            (before.pitch != cpu.pitch, Change::Pitch(before.pitch)),
+            (before.halted != cpu.halted, Change::Halted(before.halted)),
+            (before.frame != cpu.frame, Change::Frame(before.frame)),
+        ];
+        changes.extend(registers.iter().filter(|&&(changed, _)| changed).map(|&(_, change)| change));
+
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(changes);
+    }
+
+    // undoes the most recent instruction, returning false if there is none
+    pub fn undo(&mut self, cpu: &mut Cpu) -> bool {
+        let changes = match self.entries.pop_back() {
+            Some(changes) => changes,
+            None => return false,
+        };
+        for change in changes {
+            match change {
+                Change::V(x, value) => cpu.v[x as usize] = value,
+                Change::I(value) => cpu.i = value,
+                Change::Pc(value) => cpu.pc = value,
+                Change::Sp(value) => cpu.sp = value,
+                Change::Dt(value) => cpu.dt = value,
+                Change::St(value) => cpu.st = value,
+                Change::Stack(x, value) => cpu.stack[x as usize] = value,
+                Change::Rpl(x, value) => cpu.rpl[x as usize] = value,
+                Change::Memory(address, value) => cpu.memory[address as usize] = value,
+                Change::Pixel(index, value) => cpu.display.memory[index as usize] = value,
+                Change::Hires(value) => cpu.display.hires = value,
+                Change::Planes(value) => cpu.display.planes = value,
+                Change::Audio(x, value) => cpu.audio_pattern[x as usize] = value,
+                Change::Pitch(value) => cpu.pitch = value,
+                Change::Halted(value) => cpu.halted = value,
+                Change::Frame(value) => cpu.frame = value,
+                Change::Random { c, i, q } => {
+                    cpu.rand.q[(i + 1) % CMWC_CYCLE] = q;
+                    cpu.rand.c = c;
+                    cpu.rand.i = i;
+                },
+            }
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Journal;
+    use cpu::Cpu;
+    use quirks::Platform;
+
+    #[test]
+    fn undoes_instructions_exactly() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::XoChip);
+        let program = [
+            0x60, 0x05, // 200: LD V0, 5
+            0xA3, 0x00, // 202: LD I, 0x300
+            0xC0, 0xFF, // 204: RND V0, 0xFF
+            0xF0, 0x55, // 206: LD [I], V0
+            0x00, 0xFF, // 208: HIGH
+            0xD0, 0x15, // 20A: DRW V0, V1, 5
+            0x22, 0x12, // 20C: CALL 0x212
+            0x00, 0xE0, // 20E: CLS
+            0x12, 0x00, // 210: JP 0x200
+            0xF0, 0x15, // 212: LD DT, V0
+            0xF0, 0x75, // 214: LD R, V0
+            0x00, 0xC2, // 216: SCD 2
+            0x00, 0xEE, // 218: RET
+        ];
+        cpu.load_rom(&program).unwrap();
+
+        let mut journal = Journal::new(30);
+        let mut states = Vec::new();
+        for step in 0..40 {
+            states.push(cpu.save_state());
+            let pending = journal.begin(&cpu);
+            cpu.execute_cycle().unwrap();
+            if step % 3 == 2 {
+                cpu.decrement_timers();
+            }
+            journal.end(pending, &cpu);
+        }
+        assert_eq!(journal.len(), 30);
+
+        for state in states.iter().rev().take(30) {
+            assert!(journal.undo(&mut cpu));
+            assert!(cpu.save_state() == *state);
+        }
+        assert!(!journal.undo(&mut cpu), "older instructions are forgotten");
+        assert!(journal.is_empty());
+
+        journal.set_depth(0);
+        let pending = journal.begin(&cpu);
+        cpu.execute_cycle().unwrap();
+        journal.end(pending, &cpu);
+        assert!(journal.is_empty(), "a depth of 0 disables the journal");
+    }
+}
@@ -2,4 +2,24 @@ pub mod cpu;
 pub mod wasm;
 pub mod display;
 pub mod rand;
-pub mod keypad;
\ No newline at end of file
+pub mod keypad;
+pub mod quirks;
+pub mod instruction;
+pub mod disasm;
+pub mod asm;
+pub mod image;
+pub mod state;
+pub mod rewind;
+pub mod movie;
+pub mod debugger;
+pub mod expr;
+pub mod gdb;
+pub mod trace;
+pub mod journal;
+pub mod profile;
+pub mod coverage;
+pub mod symbols;
+pub mod machine;
+pub mod timing;
+pub mod sha1;
+pub mod romdb;
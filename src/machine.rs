@@ -0,0 +1,237 @@
+use cpu::{Cpu, CpuError};
+use instruction::Instruction;
+use timing::{self, VIP_FRAME_CYCLES};
+
+// the rate at which the timers count down, and frames are run
+pub const FRAME_RATE: u64 = 60;
+
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+// The most frames advance runs in one call. A host which was suspended, say
+// in a background tab, skips the time it missed rather than running it all
+// at once.
+pub const MAX_FRAMES_PER_ADVANCE: u32 = 6;
+
+// how many instructions make up a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    // a fixed instructions_per_frame
+    Instructions,
+    // As many as the COSMAC VIP would run, with each instruction costing
+    // the machine cycles its interpreter takes. DRW waits for the vertical
+    // blank, ending the frame, and its drawing is paid for in the next.
+    CosmacVip,
+}
+
+// where a machine is in its current frame, for a debugger stepping back to
+// restore along with the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramePosition {
+    cycle: u32,
+    machine_cycles: u32,
+}
+
+// Runs a CPU in real time. Each frame executes instructions as the timing
+// dictates then decrements the timers, and advance runs as many frames as
+// the time elapsed calls for, exactly 60 a second however often it is
+// called, so the host need only call it once per animation frame.
+pub struct Machine {
+    pub cpu: Cpu,
+    pub timing: Timing,
+    pub instructions_per_frame: u32,
+    // instructions executed so far in the current frame
+    cycle: u32,
+    // VIP machine cycles used so far in the current frame
+    machine_cycles: u32,
+    // time elapsed but not yet run, in millionths of a frame so that the
+    // 16666.67 microseconds of a frame are counted exactly
+    pending: u64,
+}
+
+impl Machine {
+    pub fn new(cpu: Cpu) -> Machine {
+        Machine { cpu, timing: Timing::Instructions, instructions_per_frame: 10, cycle: 0, machine_cycles: 0, pending: 0 }
+    }
+
+    // instructions executed so far in the current frame
+    pub fn cycle(&self) -> u32 {
+        self.cycle
+    }
+
+    pub fn position(&self) -> FramePosition {
+        FramePosition { cycle: self.cycle, machine_cycles: self.machine_cycles }
+    }
+
+    pub fn set_position(&mut self, position: FramePosition) {
+        self.cycle = position.cycle;
+        self.machine_cycles = position.machine_cycles;
+    }
+
+    // Starts the next frame afresh, discarding any time not yet run. Called
+    // whenever the machine's state is replaced, by a reset or a save state.
+    pub fn restart_frame(&mut self) {
+        self.cycle = 0;
+        self.machine_cycles = 0;
+        self.pending = 0;
+    }
+
+    // executes one instruction, returning true if it completed the frame and
+    // the timers were decremented
+    pub fn step(&mut self) -> Result<bool, CpuError> {
+        let instruction = self.cpu.current_instruction();
+        let cost = match (self.timing, instruction) {
+            (Timing::CosmacVip, Some(instruction)) => timing::vip_cycles(instruction, &self.cpu),
+            _ => 0,
+        };
+        self.cpu.execute_cycle()?;
+        self.cycle += 1;
+        let ended = match self.timing {
+            Timing::Instructions => self.cycle >= self.instructions_per_frame,
+            // the rest of the frame is spent waiting, the drawing in the next
+            Timing::CosmacVip if matches!(instruction, Some(Instruction::Drw(..))) => {
+                self.machine_cycles = cost;
+                true
+            },
+            Timing::CosmacVip => {
+                self.machine_cycles += cost;
+                let ended = self.machine_cycles >= VIP_FRAME_CYCLES;
+                if ended {
+                    self.machine_cycles -= VIP_FRAME_CYCLES;
+                }
+                ended
+            },
+        };
+        if !ended {
+            return Ok(false);
+        }
+        self.cycle = 0;
+        self.cpu.decrement_timers();
+        Ok(true)
+    }
+
+    // Runs the rest of the current frame. On a fault the frame is left part
+    // run, with the faulting instruction not executed.
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        while !self.step()? {}
+        Ok(())
+    }
+
+    // runs the frames due after elapsed_micros more microseconds, returning
+    // the number completed
+    pub fn advance(&mut self, elapsed_micros: u64) -> Result<u32, CpuError> {
+        self.advance_with(elapsed_micros, &mut |_| {})
+    }
+
+    // As advance, calling end_frame after each frame completes, for the host
+    // to record rewind history and the like. A fault discards the time not
+    // yet run.
+    pub fn advance_with(&mut self, elapsed_micros: u64, end_frame: &mut dyn FnMut(&mut Cpu))
+        -> Result<u32, CpuError> {
+        self.pending = self.pending.saturating_add(elapsed_micros.saturating_mul(FRAME_RATE));
+        let due = (self.pending / MICROS_PER_SECOND).min(MAX_FRAMES_PER_ADVANCE as u64) as u32;
+        self.pending = if due == MAX_FRAMES_PER_ADVANCE { 0 } else { self.pending % MICROS_PER_SECOND };
+        for _ in 0..due {
+            if let Err(fault) = self.run_frame() {
+                self.pending = 0;
+                return Err(fault);
+            }
+            end_frame(&mut self.cpu);
+        }
+        Ok(due)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Machine, Timing, MAX_FRAMES_PER_ADVANCE};
+    use cpu::{Cpu, CpuError};
+
+    // counts in V0 forever, with the delay timer set to 0xFF once
+    fn machine() -> Machine {
+        let mut cpu = Cpu::new();
+        let program = [
+            0x60, 0xFF, // 200: LD V0, 0xFF
+            0xF0, 0x15, // 202: LD DT, V0
+            0x60, 0x00, // 204: LD V0, 0
+            0x70, 0x01, // 206: ADD V0, 1
+            0x12, 0x06, // 208: JP 0x206
+        ];
+        cpu.load_rom(&program).unwrap();
+        Machine::new(cpu)
+    }
+
+    #[test]
+    fn runs_frames() {
+        let mut machine = machine();
+        machine.instructions_per_frame = 7;
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu.frame, 1);
+        assert_eq!(machine.cpu.dt, 0xFE, "the timers are decremented at the end of the frame");
+        // LD V0, 0 then two passes round the loop
+        assert_eq!(machine.cpu.v[0], 2);
+
+        assert_eq!(machine.step(), Ok(false));
+        assert_eq!(machine.cycle(), 1);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cycle(), 0, "a part run frame is completed");
+        assert_eq!(machine.cpu.frame, 2);
+        assert_eq!(machine.cpu.v[0], 6);
+    }
+
+    #[test]
+    fn advances_at_60_hz() {
+        let mut machine = machine();
+        assert_eq!(machine.advance(16_666), Ok(0));
+        assert_eq!(machine.advance(1), Ok(1));
+
+        // a second in odd steps is exactly 60 frames
+        let mut frames = 0;
+        for _ in 0..(1_000_000 / 7_000) {
+            frames += machine.advance(7_000).unwrap();
+        }
+        frames += machine.advance(1_000_000 % 7_000).unwrap();
+        assert_eq!(frames, 60);
+        assert_eq!(machine.cpu.frame, 61);
+
+        let mut ends = Vec::new();
+        assert_eq!(machine.advance_with(50_000, &mut |cpu| ends.push(cpu.frame)), Ok(3));
+        assert_eq!(ends, vec![62, 63, 64]);
+
+        // time missed while suspended is skipped
+        assert_eq!(machine.advance(10_000_000), Ok(MAX_FRAMES_PER_ADVANCE));
+        assert_eq!(machine.advance(16_000), Ok(0));
+    }
+
+    #[test]
+    fn stops_on_fault() {
+        let mut machine = machine();
+        machine.cpu.memory[0x208] = 0x00;
+        machine.cpu.memory[0x209] = 0xEE;
+        assert_eq!(machine.advance(40_000), Err(CpuError::StackUnderflow { pc: 0x208 }));
+        assert_eq!(machine.cycle(), 4);
+        assert_eq!(machine.cpu.frame, 0);
+        assert_eq!(machine.advance(10_000), Ok(0), "the time up to the fault is discarded");
+    }
+
+    #[test]
+    fn times_as_the_vip() {
+        let mut machine = machine();
+        machine.timing = Timing::CosmacVip;
+        machine.run_frame().unwrap();
+        // 46 + 50 + 46 cycles then 50 + 52 for each pass round the loop,
+        // until the 2598 cycles of the frame are spent
+        assert_eq!(machine.cycle(), 0);
+        assert_eq!(machine.cpu.v[0], 25);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu.v[0], 50, "the 42 cycles over are carried into the next frame");
+
+        // DRW waits for the next frame, with the drawing paid for in it
+        machine.cpu.memory[0x20A..0x20E].copy_from_slice(&[0xD1, 0x15, 0x12, 0x0A]);
+        machine.cpu.pc = 0x20A;
+        assert_eq!(machine.step(), Ok(true));
+        assert_eq!(machine.cpu.frame, 3);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu.frame, 4);
+    }
+}
@@ -0,0 +1,426 @@
+use std::error::Error;
+use std::fmt;
+
+use cpu::{Cpu, CpuError};
+use display::Display;
+use machine::{Machine, Timing};
+use state::{Reader, StateError};
+
+// A recording of a play session which replays bit-exactly: the machine's save
+// state when recording started, every key press and release along with the
+// frame it happened in, and a hash of the display at the end of each frame so
+// that a replay which diverges is caught at the frame it first differs. Frames
+// are counted from the start of the recording. The binary format, big-endian:
+//
+//     magic                   4 bytes, "C8MV"
+//     version                 u16, currently 1
+//     instructions per frame  u32
+//     timing                  u8, 0 - instructions per frame, 1 - COSMAC VIP
+//     initial state           u32 length, then a save state
+//     events                  u32 count, then for each a u32 frame and a u8
+//                             key, with the top bit set for a press
+//     display hashes          u32 count, then a u64 per frame
+const MAGIC: &[u8; 4] = b"C8MV";
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    // the frame whose instructions first see the change
+    pub frame: u32,
+    pub key: u8,
+    pub down: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub instructions_per_frame: u32,
+    pub timing: Timing,
+    pub initial_state: Vec<u8>,
+    pub events: Vec<KeyEvent>,
+    pub hashes: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingBytes,
+    // an event is for a key outside 0-F, or out of frame order
+    InvalidEvent,
+    // the timing is not one this version knows
+    InvalidTiming,
+    // the initial state does not load
+    InvalidState(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::BadMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::TrailingBytes => write!(f, "movie has trailing bytes"),
+            MovieError::InvalidEvent => write!(f, "movie has an invalid key event"),
+            MovieError::InvalidTiming => write!(f, "movie has an invalid timing"),
+            MovieError::InvalidState(ref error) => write!(f, "movie has an invalid initial state, {}", error),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(error: StateError) -> MovieError {
+        match error {
+            StateError::Truncated => MovieError::Truncated,
+            error => MovieError::InvalidState(error),
+        }
+    }
+}
+
+// the first frame at which a replay's display differs from the recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Desync {
+    pub frame: u32,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "desync at frame {}, display hash {:016X} expected {:016X}",
+               self.frame, self.actual, self.expected)
+    }
+}
+
+impl Error for Desync {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackError {
+    Desync(Desync),
+    Fault(CpuError),
+    Movie(MovieError),
+}
+
+impl fmt::Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlaybackError::Desync(ref desync) => desync.fmt(f),
+            PlaybackError::Fault(ref fault) => fault.fmt(f),
+            PlaybackError::Movie(ref error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for PlaybackError {}
+
+// FNV-1a over the resolution and the visible pixels
+pub fn display_hash(display: &Display) -> u64 {
+    let mut hash = 0xCBF2_9CE4_8422_2325u64;
+    let pixels = &display.memory[..display.width() * display.height()];
+    for &byte in [display.hires as u8].iter().chain(pixels) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    hash
+}
+
+impl Movie {
+    // the number of frames recorded
+    pub fn frames(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_be_bytes());
+        out.extend_from_slice(&self.instructions_per_frame.to_be_bytes());
+        out.push(match self.timing {
+            Timing::Instructions => 0,
+            Timing::CosmacVip => 1,
+        });
+        out.extend_from_slice(&(self.initial_state.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.initial_state);
+        out.extend_from_slice(&(self.events.len() as u32).to_be_bytes());
+        for event in &self.events {
+            out.extend_from_slice(&event.frame.to_be_bytes());
+            out.push(event.key | (event.down as u8) << 7);
+        }
+        out.extend_from_slice(&(self.hashes.len() as u32).to_be_bytes());
+        for hash in &self.hashes {
+            out.extend_from_slice(&hash.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = Reader { data };
+        if reader.bytes(4).map_err(|_| MovieError::BadMagic)? != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let instructions_per_frame = reader.u32()?;
+        let timing = match reader.u8()? {
+            0 => Timing::Instructions,
+            1 => Timing::CosmacVip,
+            _ => return Err(MovieError::InvalidTiming),
+        };
+        let len = reader.u32()? as usize;
+        let initial_state = reader.bytes(len)?.to_vec();
+        Cpu::new().load_state(&initial_state).map_err(MovieError::InvalidState)?;
+
+        let mut events: Vec<KeyEvent> = Vec::new();
+        for _ in 0..reader.u32()? {
+            let frame = reader.u32()?;
+            let key = reader.u8()?;
+            if key & 0x70 != 0 || events.last().is_some_and(|last| last.frame > frame) {
+                return Err(MovieError::InvalidEvent);
+            }
+            events.push(KeyEvent { frame, key: key & 0x0F, down: key & 0x80 != 0 });
+        }
+        let mut hashes = Vec::new();
+        for _ in 0..reader.u32()? {
+            hashes.push(reader.u64()?);
+        }
+        if !reader.data.is_empty() {
+            return Err(MovieError::TrailingBytes);
+        }
+        Ok(Movie { instructions_per_frame, timing, initial_state, events, hashes })
+    }
+
+    // replays the whole movie from its initial state, at the speed it was
+    // recorded, checking the display after every frame
+    pub fn verify(&self, machine: &mut Machine) -> Result<(), PlaybackError> {
+        let mut player = Player::start(self.clone(), machine).map_err(PlaybackError::Movie)?;
+        while !player.is_finished() {
+            machine.run_frame().map_err(PlaybackError::Fault)?;
+            player.end_frame(&mut machine.cpu).map_err(PlaybackError::Desync)?;
+        }
+        Ok(())
+    }
+}
+
+// Records a movie. Key presses and releases are routed through the recorder
+// rather than the keypad, and end_frame is called once the frame's
+// instructions have run and the timers have been decremented.
+pub struct Recorder {
+    movie: Movie,
+    start_frame: u64,
+}
+
+impl Recorder {
+    // records from the start of the machine's next frame, at its speed
+    pub fn start(machine: &mut Machine) -> Recorder {
+        machine.restart_frame();
+        let cpu = &machine.cpu;
+        Recorder {
+            movie: Movie {
+                instructions_per_frame: machine.instructions_per_frame,
+                timing: machine.timing,
+                initial_state: cpu.save_state(),
+                events: Vec::new(),
+                hashes: Vec::new(),
+            },
+            start_frame: cpu.frame,
+        }
+    }
+
+    fn record(&mut self, cpu: &Cpu, key: u8, down: bool) {
+        let frame = (cpu.frame - self.start_frame) as u32;
+        self.movie.events.push(KeyEvent { frame, key, down });
+    }
+
+    pub fn key_down(&mut self, cpu: &mut Cpu, key: u8) {
+        if !cpu.keypad.is_key_down(key) {
+            self.record(cpu, key, true);
+        }
+        cpu.keypad.key_down(key);
+    }
+
+    pub fn key_up(&mut self, cpu: &mut Cpu, key: u8) {
+        if cpu.keypad.is_key_down(key) {
+            self.record(cpu, key, false);
+        }
+        cpu.keypad.key_up(key);
+    }
+
+    pub fn end_frame(&mut self, cpu: &Cpu) {
+        self.movie.hashes.push(display_hash(&cpu.display));
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Plays a movie back, injecting its key events at the frames they were
+// recorded in. end_frame is called at the same point as when recording.
+pub struct Player {
+    movie: Movie,
+    // the frame about to run
+    frame: u32,
+    next_event: usize,
+}
+
+impl Player {
+    // restores the movie's initial state and the speed it was recorded at,
+    // ready for its first frame
+    pub fn start(movie: Movie, machine: &mut Machine) -> Result<Player, MovieError> {
+        machine.cpu.load_state(&movie.initial_state)?;
+        machine.instructions_per_frame = movie.instructions_per_frame;
+        machine.timing = movie.timing;
+        machine.restart_frame();
+        let mut player = Player { movie, frame: 0, next_event: 0 };
+        player.apply_events(&mut machine.cpu);
+        Ok(player)
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames()
+    }
+
+    fn apply_events(&mut self, cpu: &mut Cpu) {
+        while let Some(event) = self.movie.events.get(self.next_event) {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.down {
+                cpu.keypad.key_down(event.key);
+            } else {
+                cpu.keypad.key_up(event.key);
+            }
+            self.next_event += 1;
+        }
+    }
+
+    // checks the frame just run against the recording, then applies the
+    // input for the next frame
+    pub fn end_frame(&mut self, cpu: &mut Cpu) -> Result<(), Desync> {
+        if let Some(&expected) = self.movie.hashes.get(self.frame as usize) {
+            let actual = display_hash(&cpu.display);
+            if actual != expected {
+                return Err(Desync { frame: self.frame, expected, actual });
+            }
+        }
+        self.frame += 1;
+        self.apply_events(cpu);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Desync, Movie, MovieError, PlaybackError, Player, Recorder};
+    use cpu::Cpu;
+    use machine::{Machine, Timing};
+
+    // draws a digit for the key held, at a random position each frame, with
+    // eight instructions to a frame
+    fn program() -> Machine {
+        let mut cpu = Cpu::new();
+        // CLS; LD V0, K; LD F, V0; RND V1, 0x3F; RND V2, 0x1F; DRW V1, V2, 5; JP 0x202
+        let program = [0x00, 0xE0, 0xF0, 0x0A, 0xF0, 0x29, 0xC1, 0x3F, 0xC2, 0x1F, 0xD1, 0x25, 0x12, 0x02];
+        cpu.load_rom(&program).unwrap();
+        let mut machine = Machine::new(cpu);
+        machine.instructions_per_frame = 8;
+        machine
+    }
+
+    fn record(timing: Timing) -> (Movie, Machine) {
+        let mut machine = program();
+        machine.timing = timing;
+        machine.run_frame().unwrap();
+        let mut recorder = Recorder::start(&mut machine);
+        for frame in 0..40 {
+            match frame {
+                3 => recorder.key_down(&mut machine.cpu, 0x5),
+                10 => recorder.key_up(&mut machine.cpu, 0x5),
+                20 => recorder.key_down(&mut machine.cpu, 0xA),
+                _ => {},
+            }
+            machine.run_frame().unwrap();
+            recorder.end_frame(&machine.cpu);
+        }
+        (recorder.finish(), machine)
+    }
+
+    #[test]
+    fn replays_recording() {
+        let (movie, recorded) = record(Timing::Instructions);
+        assert_eq!(movie.frames(), 40);
+        assert_eq!(movie.events.len(), 3);
+        assert_eq!((movie.events[1].frame, movie.events[1].key, movie.events[1].down), (10, 0x5, false));
+
+        let mut machine = Machine::new(Cpu::new());
+        movie.verify(&mut machine).unwrap();
+        assert_eq!(machine.instructions_per_frame, 8, "the movie sets the speed");
+        assert_eq!(machine.cpu.save_state(), recorded.cpu.save_state());
+    }
+
+    #[test]
+    fn replays_vip_timing() {
+        let (movie, recorded) = record(Timing::CosmacVip);
+        assert_eq!(movie.timing, Timing::CosmacVip);
+        let mut machine = Machine::new(Cpu::new());
+        movie.verify(&mut machine).unwrap();
+        assert_eq!(machine.timing, Timing::CosmacVip);
+        assert_eq!(machine.cpu.save_state(), recorded.cpu.save_state());
+
+        // replayed at a fixed rate the same input draws something else
+        let mut fixed = movie.clone();
+        fixed.timing = Timing::Instructions;
+        assert!(matches!(fixed.verify(&mut machine), Err(PlaybackError::Desync(_))));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let (movie, _) = record(Timing::CosmacVip);
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie.clone()));
+
+        assert_eq!(Movie::from_bytes(b"C8ST"), Err(MovieError::BadMagic));
+        assert_eq!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::Truncated));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Movie::from_bytes(&trailing), Err(MovieError::TrailingBytes));
+        let mut timing = bytes.clone();
+        timing[10] = 2;
+        assert_eq!(Movie::from_bytes(&timing), Err(MovieError::InvalidTiming));
+
+        let mut fast = movie.clone();
+        fast.instructions_per_frame = 100_000;
+        assert_eq!(Movie::from_bytes(&fast.to_bytes()), Ok(fast), "rates past a u16 are kept");
+    }
+
+    #[test]
+    fn reports_desync_frame() {
+        let (mut movie, _) = record(Timing::Instructions);
+        // pressing the key a frame later changes what is drawn from then on
+        movie.events[0].frame = 4;
+        let mut machine = Machine::new(Cpu::new());
+        match movie.verify(&mut machine) {
+            Err(PlaybackError::Desync(Desync { frame, .. })) => assert_eq!(frame, 3),
+            result => panic!("expected a desync, got {:?}", result),
+        }
+
+        // playing frame by frame stops at the same point
+        let mut player = Player::start(movie, &mut machine).unwrap();
+        for _ in 0..3 {
+            machine.run_frame().unwrap();
+            player.end_frame(&mut machine.cpu).unwrap();
+        }
+        machine.run_frame().unwrap();
+        assert_eq!(player.end_frame(&mut machine.cpu).map_err(|desync| desync.frame), Err(3));
+        assert_eq!(player.frame(), 3);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+
+use cpu::Cpu;
+use disasm::{self, Syntax};
+use instruction::Instruction;
+use symbols::Symbols;
+use trace::{TraceEvent, Tracer};
+
+// the most instructions between two reads of the delay timer at the same
+// address for them to count as a loop polling it, e.g. LD V0, DT; SE V0, 0;
+// JP back
+const POLL_LOOP_LENGTH: usize = 4;
+
+// the frame period in microseconds, the time unit of Chrome traces
+const FRAME_MICROS: f64 = 1_000_000.0 / 60.0;
+
+// what was executed in a frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameProfile {
+    pub instructions: u64,
+    // instructions spent waiting for a key with Fx0A, or polling the delay timer
+    pub key_wait: u64,
+    pub timer_wait: u64,
+}
+
+// executions of the instruction at an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotspot {
+    pub address: u16,
+    pub count: u64,
+    pub opcode: u16,
+    pub operand: Option<u16>,
+}
+
+// a subroutine call starting, or returning, at an instruction within a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CallEvent {
+    frame: usize,
+    instruction: u64,
+    call: Option<u16>,
+}
+
+// Counts what a ROM executes, as a Tracer: per address, per kind of
+// instruction and per frame, along with the time spent waiting for input or
+// the delay timer. Faulting instructions are not counted.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    hotspots: HashMap<u16, Hotspot>,
+    kinds: BTreeMap<&'static str, u64>,
+    // the first frame profiled, and each from then on
+    first_frame: Option<u64>,
+    frames: Vec<FrameProfile>,
+    // the address of the last LD Vx, DT, and the frame of each instruction
+    // since, including it
+    poll: Option<(u16, Vec<usize>)>,
+    calls: Vec<CallEvent>,
+    depth: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.frames.iter().map(|frame| frame.instructions).sum()
+    }
+
+    pub fn key_wait(&self) -> u64 {
+        self.frames.iter().map(|frame| frame.key_wait).sum()
+    }
+
+    pub fn timer_wait(&self) -> u64 {
+        self.frames.iter().map(|frame| frame.timer_wait).sum()
+    }
+
+    pub fn frames(&self) -> &[FrameProfile] {
+        &self.frames
+    }
+
+    // the addresses executed, most often first
+    pub fn hotspots(&self) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self.hotspots.values().cloned().collect();
+        hotspots.sort_by(|a, b| b.count.cmp(&a.count).then(a.address.cmp(&b.address)));
+        hotspots
+    }
+
+    // the kinds of instruction executed, as opcode patterns, most often first
+    pub fn kinds(&self) -> Vec<(&'static str, u64)> {
+        let mut kinds: Vec<(&'static str, u64)> = self.kinds.iter().map(|(&kind, &count)| (kind, count)).collect();
+        kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        kinds
+    }
+
+    // a plain text summary, listing at most top hotspots, located with the
+    // symbols if there are any
+    pub fn report(&self, top: usize, symbols: &Symbols) -> String {
+        let instructions = self.instructions();
+        let percent = |count: u64| if instructions == 0 { 0.0 } else { count as f64 * 100.0 / instructions as f64 };
+        let mut out = format!("instructions: {} in {} frames", instructions, self.frames.len());
+        if !self.frames.is_empty() {
+            let min = self.frames.iter().map(|frame| frame.instructions).min().unwrap_or(0);
+            let max = self.frames.iter().map(|frame| frame.instructions).max().unwrap_or(0);
+            out += &format!(", {:.1} per frame (min {}, max {})",
+                            instructions as f64 / self.frames.len() as f64, min, max);
+        }
+        out += &format!("\nwaiting for a key (Fx0A): {} ({:.1}%)\n", self.key_wait(), percent(self.key_wait()));
+        out += &format!("polling the delay timer: {} ({:.1}%)\n", self.timer_wait(), percent(self.timer_wait()));
+
+        out += "\nhotspots:\n     count       %  address  instruction\n";
+        for hotspot in self.hotspots().iter().take(top) {
+            let text = match Instruction::decode(hotspot.opcode) {
+                Some(instruction) => disasm::format_instruction(instruction, hotspot.operand, Syntax::Cowgod,
+                                                                 &|address| symbols.label(address).map(str::to_string)),
+                None => format!("0x{:04X}", hotspot.opcode),
+            };
+            let location = if symbols.is_empty() {
+                String::new()
+            } else {
+                format!("{:20} ", symbols.location(hotspot.address).unwrap_or_default())
+            };
+            out += &format!("{:>10}  {:5.1}%  0x{:04X}   {}{}\n",
+                            hotspot.count, percent(hotspot.count), hotspot.address, location, text);
+        }
+
+        out += "\ninstruction kinds:\n     count       %  kind\n";
+        for (kind, count) in self.kinds() {
+            out += &format!("{:>10}  {:5.1}%  {}\n", count, percent(count), kind);
+        }
+        out
+    }
+
+    // The profile in the Chrome trace event format, for chrome://tracing or
+    // Perfetto. Each frame is a span of 1/60 s with its instructions spread
+    // evenly across it, the counts are graphed per frame and subroutine calls
+    // are spans on a second track, named by the symbols where possible.
+    pub fn chrome_trace(&self, symbols: &Symbols) -> String {
+        let first = self.first_frame.unwrap_or(0);
+        let time = |frame: usize, instruction: u64| {
+            let count = self.frames[frame].instructions.max(1);
+            (frame as f64 + instruction as f64 / count as f64) * FRAME_MICROS
+        };
+        let mut events = Vec::new();
+        for (index, frame) in self.frames.iter().enumerate() {
+            events.push(format!(
+                r#"{{"name":"frame {}","cat":"frame","ph":"X","ts":{:.3},"dur":{:.3},"pid":1,"tid":1,"args":{{"instructions":{},"key_wait":{},"timer_wait":{}}}}}"#,
+                first + index as u64, time(index, 0), FRAME_MICROS, frame.instructions, frame.key_wait, frame.timer_wait));
+            events.push(format!(
+                r#"{{"name":"instructions","ph":"C","ts":{:.3},"pid":1,"args":{{"executed":{},"key_wait":{},"timer_wait":{}}}}}"#,
+                time(index, 0), frame.instructions - frame.key_wait - frame.timer_wait, frame.key_wait, frame.timer_wait));
+        }
+        for event in &self.calls {
+            let ts = time(event.frame, event.instruction);
+            events.push(match event.call {
+                Some(address) => format!(r#"{{"name":"{}","cat":"call","ph":"B","ts":{:.3},"pid":1,"tid":2}}"#,
+                                         symbols.label(address).map_or_else(|| disasm::label_name(address), str::to_string), ts),
+                None => format!(r#"{{"ph":"E","ts":{:.3},"pid":1,"tid":2}}"#, ts),
+            });
+        }
+        format!("{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ms\"}}\n", events.join(",\n"))
+    }
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, event: &TraceEvent, cpu: &Cpu) {
+        if event.fault.is_some() {
+            return;
+        }
+        let first = *self.first_frame.get_or_insert(event.frame);
+        let index = event.frame.saturating_sub(first) as usize;
+        if index >= self.frames.len() {
+            self.frames.resize(index + 1, FrameProfile::default());
+        }
+        let instruction = self.frames[index].instructions;
+        self.frames[index].instructions += 1;
+
+        self.hotspots.entry(event.pc)
+            .or_insert(Hotspot { address: event.pc, count: 0, opcode: event.opcode, operand: event.operand })
+            .count += 1;
+        let decoded = Instruction::decode(event.opcode);
+        if let Some(decoded) = decoded {
+            *self.kinds.entry(decoded.kind()).or_insert(0) += 1;
+        }
+
+        match decoded {
+            // Fx0A waits by executing again until a key is pressed
+            Some(Instruction::LdVxK(_)) if cpu.pc == event.pc => self.frames[index].key_wait += 1,
+            Some(Instruction::LdVxDt(_)) => {
+                if let Some((pc, ref since)) = self.poll {
+                    if pc == event.pc && since.len() <= POLL_LOOP_LENGTH {
+                        for &frame in since {
+                            self.frames[frame].timer_wait += 1;
+                        }
+                    }
+                }
+                self.poll = Some((event.pc, vec![index]));
+                return;
+            },
+            Some(Instruction::Call(_)) => {
+                self.depth += 1;
+                self.calls.push(CallEvent { frame: index, instruction, call: Some(cpu.pc) });
+            },
+            // returns from calls made before profiling started are ignored
+            Some(Instruction::Ret) if self.depth > 0 => {
+                self.depth -= 1;
+                self.calls.push(CallEvent { frame: index, instruction: instruction + 1, call: None });
+            },
+            _ => {},
+        }
+        if let Some((_, ref mut since)) = self.poll {
+            since.push(index);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameProfile, Profiler};
+    use cpu::Cpu;
+    use symbols::Symbols;
+    use trace::run_traced;
+
+    #[test]
+    fn counts_instructions_and_waits() {
+        let program = [
+            0x60, 0x04, // 200: LD V0, 4
+            0xF0, 0x15, // 202: LD DT, V0
+            0xF1, 0x07, // 204: LD V1, DT
+            0x31, 0x00, // 206: SE V1, 0
+            0x12, 0x04, // 208: JP 0x204
+            0x22, 0x10, // 20A: CALL 0x210
+            0xF2, 0x0A, // 20C: LD V2, K
+            0x00, 0x00, // 20E: padding
+            0x00, 0xEE, // 210: RET
+        ];
+        let profiler = run_traced(&mut Cpu::new(), &program, 8, 6, Profiler::new());
+        assert_eq!(profiler.instructions(), 48);
+        assert_eq!(profiler.frames().len(), 8);
+        assert_eq!(profiler.frames()[0], FrameProfile { instructions: 6, key_wait: 0, timer_wait: 4 });
+
+        // the loop polls the delay timer until it runs out at the end of
+        // frame 3, then calls and returns, then waits for a key
+        let hotspots = profiler.hotspots();
+        assert_eq!((hotspots[0].address, hotspots[0].count), (0x20C, 18));
+        assert_eq!((hotspots[1].address, hotspots[1].count), (0x204, 9));
+        assert_eq!(profiler.key_wait(), 18);
+        assert_eq!(profiler.timer_wait(), 24);
+        assert_eq!(profiler.kinds()[0], ("Fx0A", 18));
+
+        let report = profiler.report(3, &Symbols::new());
+        assert!(report.starts_with("instructions: 48 in 8 frames, 6.0 per frame (min 6, max 6)\n"));
+        assert!(report.contains("        18   37.5%  0x020C   LD V2, K\n"));
+        assert!(report.contains("        18   37.5%  Fx0A\n"));
+
+        let symbols = Symbols::parse("start = 0x200\nwait_key = 0x20C").unwrap();
+        assert!(profiler.report(1, &symbols).contains("        18   37.5%  0x020C   wait_key             LD V2, K\n"));
+    }
+
+    #[test]
+    fn writes_chrome_traces() {
+        // CALL 0x204; JP 0x200; RET
+        let profiler = run_traced(&mut Cpu::new(), &[0x22, 0x04, 0x12, 0x00, 0x00, 0xEE], 2, 3, Profiler::new());
+        let trace = profiler.chrome_trace(&Symbols::new());
+        assert!(trace.starts_with("{\"traceEvents\":[\n"));
+        assert!(trace.contains(r#"{"name":"frame 1","cat":"frame","ph":"X","ts":16666.667,"dur":16666.667,"pid":1,"tid":1,"args":{"instructions":3,"key_wait":0,"timer_wait":0}}"#));
+        assert!(trace.contains(r#"{"name":"L204","cat":"call","ph":"B","ts":0.000,"pid":1,"tid":2}"#));
+        assert!(trace.contains(r#"{"ph":"E","ts":11111.111,"pid":1,"tid":2}"#));
+        assert_eq!(trace.matches("\"ph\":\"B\"").count(), 2);
+        assert!(trace.ends_with("],\"displayTimeUnit\":\"ms\"}\n"));
+
+        let symbols = Symbols::parse("ret = 0x204").unwrap();
+        assert!(profiler.chrome_trace(&symbols).contains(r#"{"name":"ret","cat":"call","ph":"B","ts":0.000,"pid":1,"tid":2}"#));
+    }
+}
@@ -0,0 +1,178 @@
+// The CHIP-8 family of interpreters disagree on the semantics of a handful of
+// instructions, and ROMs written for one platform often misbehave on another.
+// Each flag selects between the two behaviours seen in the wild.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy6 / 8xyE shift Vy into Vx, rather than shifting Vx in place
+    pub shift_uses_vy: bool,
+    // Fx55 / Fx65 leave I pointing one past the last register stored / loaded
+    pub load_store_increments_i: bool,
+    // Bnnn jumps to nnn + Vx, where x is the top nibble of nnn, rather than nnn + V0
+    pub jump_uses_vx: bool,
+    // 8xy1 / 8xy2 / 8xy3 reset VF to zero
+    pub logic_resets_vf: bool,
+    // sprites are clipped at the edges of the screen rather than wrapping around
+    pub clip_sprites: bool,
+    // Fx1E sets VF to 1 when I overflows past 0x0FFF, and 0 otherwise
+    pub add_i_sets_vf: bool,
+    // Dxy0 draws nothing, rather than a large sprite
+    pub zero_height_draws_nothing: bool,
+    // Dxy0 draws an 8 x 16 sprite in low resolution, rather than 16 x 16
+    pub lores_tall_sprites: bool,
+}
+
+impl Quirks {
+    // the behaviour of this emulator before quirks were configurable
+    pub const NONE: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        add_i_sets_vf: false,
+        zero_height_draws_nothing: false,
+        lores_tall_sprites: false,
+    };
+
+    // the original interpreter on the RCA COSMAC VIP
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        logic_resets_vf: true,
+        clip_sprites: true,
+        add_i_sets_vf: false,
+        zero_height_draws_nothing: true,
+        lores_tall_sprites: false,
+    };
+
+    // CHIP-48 on the HP-48 calculators
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        clip_sprites: true,
+        add_i_sets_vf: false,
+        zero_height_draws_nothing: false,
+        lores_tall_sprites: true,
+    };
+
+    // SUPER-CHIP 1.1, as most SCHIP games expect
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        clip_sprites: true,
+        add_i_sets_vf: false,
+        zero_height_draws_nothing: false,
+        lores_tall_sprites: true,
+    };
+
+    // XO-CHIP, as implemented by Octo
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        clip_sprites: false,
+        add_i_sets_vf: false,
+        zero_height_draws_nothing: false,
+        lores_tall_sprites: false,
+    };
+
+    // the names of the flags, as in a ROM database, in the order of bits
+    pub const NAMES: [&'static str; 8] = [
+        "shift_uses_vy", "load_store_increments_i", "jump_uses_vx", "logic_resets_vf", "clip_sprites",
+        "add_i_sets_vf", "zero_height_draws_nothing", "lores_tall_sprites",
+    ];
+
+    // packs the flags into a byte, in field order from the least significant bit
+    pub fn bits(&self) -> u8 {
+        self.shift_uses_vy as u8
+            | (self.load_store_increments_i as u8) << 1
+            | (self.jump_uses_vx as u8) << 2
+            | (self.logic_resets_vf as u8) << 3
+            | (self.clip_sprites as u8) << 4
+            | (self.add_i_sets_vf as u8) << 5
+            | (self.zero_height_draws_nothing as u8) << 6
+            | (self.lores_tall_sprites as u8) << 7
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 0x01 != 0,
+            load_store_increments_i: bits & 0x02 != 0,
+            jump_uses_vx: bits & 0x04 != 0,
+            logic_resets_vf: bits & 0x08 != 0,
+            clip_sprites: bits & 0x10 != 0,
+            add_i_sets_vf: bits & 0x20 != 0,
+            zero_height_draws_nothing: bits & 0x40 != 0,
+            lores_tall_sprites: bits & 0x80 != 0,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::NONE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    // the platform with the short name used on the command line and in ROM
+    // databases, e.g. "schip"
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "vip" => Some(Platform::CosmacVip),
+            "chip48" => Some(Platform::Chip48),
+            "schip" => Some(Platform::SuperChip),
+            "xo" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Platform::CosmacVip => "vip",
+            Platform::Chip48 => "chip48",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xo",
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match *self {
+            Platform::CosmacVip => Quirks::COSMAC_VIP,
+            Platform::Chip48 => Quirks::CHIP_48,
+            Platform::SuperChip => Quirks::SUPER_CHIP,
+            Platform::XoChip => Quirks::XO_CHIP,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Platform, Quirks};
+
+    #[test]
+    fn bits_round_trip() {
+        for platform in &[Platform::CosmacVip, Platform::Chip48, Platform::SuperChip, Platform::XoChip] {
+            let quirks = platform.quirks();
+            assert_eq!(Quirks::from_bits(quirks.bits()), quirks);
+            assert_eq!(Platform::from_name(platform.name()), Some(*platform));
+        }
+        assert_eq!(Quirks::NONE.bits(), 0);
+        assert_eq!(Quirks::from_bits(0xFF).bits(), 0xFF);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+
+use cpu::Cpu;
+
+// the bytes which differ between two states of the same length, as runs of
+// (offset, replacement bytes)
+type Delta = Vec<(usize, Vec<u8>)>;
+
+// A bounded history of the machine, for undoing the last few seconds of play.
+// A save state is captured every interval frames. Only the most recent is kept
+// in full, each older snapshot is stored as the changes which turn its newer
+// neighbour back into it, which for most games is a handful of bytes of memory
+// and display. The oldest snapshots are dropped once capacity is reached.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    // frames run since the most recent snapshot
+    frames_since: u32,
+    latest: Option<Vec<u8>>,
+    // deltas to older snapshots, the oldest first
+    deltas: VecDeque<Delta>,
+}
+
+fn delta(from: &[u8], to: &[u8]) -> Delta {
+    let mut runs = Vec::new();
+    let mut offset = 0;
+    while offset < to.len() {
+        if from[offset] == to[offset] {
+            offset += 1;
+            continue;
+        }
+        let start = offset;
+        while offset < to.len() && from[offset] != to[offset] {
+            offset += 1;
+        }
+        runs.push((start, to[start..offset].to_vec()));
+    }
+    runs
+}
+
+fn apply(state: &mut [u8], delta: &Delta) {
+    for &(offset, ref bytes) in delta {
+        state[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+impl Rewind {
+    // snapshots every interval frames, keeping up to capacity of them
+    pub fn new(interval: u32, capacity: usize) -> Rewind {
+        assert!(interval > 0 && capacity > 0);
+        Rewind {
+            interval,
+            capacity,
+            frames_since: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // the number of snapshots held
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // the bytes held by the snapshots, a measure of the memory used
+    pub fn size(&self) -> usize {
+        self.latest.as_ref().map_or(0, |latest| latest.len())
+            + self.deltas.iter().flat_map(|delta| delta.iter()).map(|run| run.1.len()).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames_since = 0;
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // called once at the end of each frame, capturing a snapshot when due
+    pub fn frame(&mut self, cpu: &Cpu) {
+        self.frames_since += 1;
+        if self.latest.is_none() || self.frames_since >= self.interval {
+            self.capture(cpu);
+        }
+    }
+
+    fn capture(&mut self, cpu: &Cpu) {
+        let state = cpu.save_state();
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(delta(&state, &previous));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+        self.frames_since = 0;
+    }
+
+    // restores the most recent snapshot at least frames old, or the oldest
+    // if the history is not that long, returning the number of frames
+    // actually rewound. Snapshots newer than the one restored are discarded,
+    // and rewinding no frames does nothing.
+    pub fn rewind(&mut self, cpu: &mut Cpu, frames: u32) -> u32 {
+        if frames == 0 {
+            return 0;
+        }
+        let mut state = match self.latest.take() {
+            Some(state) => state,
+            None => return 0,
+        };
+        let mut rewound = self.frames_since;
+        while rewound < frames {
+            match self.deltas.pop_back() {
+                Some(delta) => {
+                    apply(&mut state, &delta);
+                    rewound += self.interval;
+                },
+                None => break,
+            }
+        }
+        cpu.load_state(&state).expect("rewind snapshots are valid save states");
+        self.latest = Some(state);
+        self.frames_since = 0;
+        rewound
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Rewind;
+    use cpu::Cpu;
+
+    // a CPU counting frames in V0 and in memory at 0x300
+    fn counter() -> Cpu {
+        let mut cpu = Cpu::new();
+        // ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x200
+        let program = [0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00];
+        cpu.load_rom(&program).unwrap();
+        cpu
+    }
+
+    // runs a frame of four instructions, a full pass of the loop
+    fn run_frame(cpu: &mut Cpu, rewind: &mut Rewind) {
+        for _ in 0..4 {
+            cpu.execute_cycle().unwrap();
+        }
+        cpu.decrement_timers();
+        rewind.frame(cpu);
+    }
+
+    #[test]
+    fn rewinds_to_snapshots() {
+        let mut cpu = counter();
+        let mut rewind = Rewind::new(5, 100);
+        for _ in 0..23 {
+            run_frame(&mut cpu, &mut rewind);
+        }
+        assert_eq!(cpu.v[0], 23);
+        // snapshots are taken after frames 1, 6, 11, 16 and 21
+        assert_eq!(rewind.len(), 5);
+
+        assert_eq!(rewind.rewind(&mut cpu, 0), 0);
+        assert_eq!(cpu.v[0], 23, "rewinding no frames leaves the CPU alone");
+
+        assert_eq!(rewind.rewind(&mut cpu, 6), 7);
+        assert_eq!(cpu.v[0], 16);
+        assert_eq!(cpu.memory[0x300], 16);
+        assert_eq!(rewind.len(), 4);
+
+        // the history continues from the restored snapshot
+        run_frame(&mut cpu, &mut rewind);
+        assert_eq!(rewind.rewind(&mut cpu, 1), 1);
+        assert_eq!(cpu.v[0], 16);
+
+        // rewinding past the start restores the oldest snapshot
+        assert_eq!(rewind.rewind(&mut cpu, 1000), 15);
+        assert_eq!(cpu.v[0], 1);
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn capacity_bounds_history() {
+        let mut cpu = counter();
+        let mut rewind = Rewind::new(1, 10);
+        for _ in 0..50 {
+            run_frame(&mut cpu, &mut rewind);
+        }
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.rewind(&mut cpu, 1000), 9);
+        assert_eq!(cpu.v[0], 41);
+    }
+
+    #[test]
+    fn stores_deltas() {
+        let mut cpu = counter();
+        let mut rewind = Rewind::new(1, 100);
+        run_frame(&mut cpu, &mut rewind);
+        let full = rewind.size();
+        for _ in 0..99 {
+            run_frame(&mut cpu, &mut rewind);
+        }
+        // only V0, PC and one byte of memory change between snapshots
+        assert!(rewind.size() < full + 100 * 8);
+
+        let mut empty = Rewind::new(1, 1);
+        assert!(empty.is_empty());
+        assert_eq!(empty.rewind(&mut cpu, 10), 0);
+        assert_eq!(cpu.v[0], 100);
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use quirks::{Platform, Quirks};
+use sha1;
+
+// What is known about a ROM: who wrote it, what it was written for and how
+// to run and play it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    pub title: String,
+    pub author: String,
+    pub year: Option<u16>,
+    pub platform: Platform,
+    pub instructions_per_frame: u32,
+    pub quirks: Quirks,
+    // which keypad keys do what
+    pub keys: String,
+}
+
+// ROMs by the SHA-1 of their contents, read from a database file of one
+// section per ROM:
+//
+//     ; comments run to the end of the line
+//     [b232ef880bd6060fb45fa6effed7edf0ae95670e]
+//     title = Pong
+//     author = Paul Vervalin
+//     year = 1990
+//     platform = chip48          ; vip, chip48, schip or xo
+//     ipf = 10
+//     quirks = clip_sprites      ; the platform's when not given, or none
+//     keys = 1 and 4 move the left paddle, C and D the right
+//
+// Only the title is required. The ROMs in web/roms are built in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Database {
+    roms: BTreeMap<String, RomInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl Error for DatabaseError {}
+
+const BUILTIN: &str = "\
+[ea9af3c09b0d9e265fcd92bcc5d51a2939fdf27a]
+title = 15 Puzzle
+author = Roger Ivie
+platform = vip
+ipf = 10
+keys = the key at the position of a tile next to the gap slides it into the gap
+
+[d40abc54374e4343639f993e897e00904ddf85d9]
+title = Blinky
+author = Hans Christian Egeberg
+year = 1991
+platform = chip48
+ipf = 15
+quirks = none
+keys = 3 and 6 move up and down, 7 and 8 left and right
+
+[6f6509f38220e057a7e32ebb22dd353c1078e3e7]
+title = Blitz
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = clip_sprites
+keys = 5 drops a bomb
+
+[f13766c14aeb02ad8d4d103cb5eadd282d20cddc]
+title = Brix
+author = Andreas Gustafsson
+year = 1990
+platform = chip48
+ipf = 10
+quirks = none
+keys = 4 and 6 move the paddle left and right
+
+[2d10c07b532f4fa7c07a07324ba26ca39fe484fd]
+title = Connect 4
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 4 and 6 move left and right, 5 drops a disc
+
+[5260f8931e0e9f41e555b382a14a88368e3ed886]
+title = Guess
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = think of a number from 1 to 63, then press 5 if a board shows it and any other key if not
+
+[050f07a54371da79f924dd0227b89d07b4f2aed0]
+title = Hidden!
+author = David Winter
+year = 1996
+platform = chip48
+ipf = 10
+quirks = none
+keys = 2, 4, 6 and 8 move, 5 turns over a card
+
+[1ba58656810b67fd131eb9af3e3987863bf26c90]
+title = IBM Logo
+platform = vip
+ipf = 10
+keys = none
+
+[f100197f0f2f05b4f3c8c31ab9c2c3930d3e9571]
+title = Space Invaders
+author = David Winter
+platform = chip48
+ipf = 15
+quirks = none
+keys = 4 and 6 move left and right, 5 starts and fires
+
+[d6fa9dc9005dc0496f39ba52fef56f9fd0a5a158]
+title = Kaleidoscope
+author = Joseph Weisbecker
+year = 1978
+platform = vip
+ipf = 10
+keys = 2, 4, 6 and 8 draw, 0 ends the pattern and repeats it
+
+[b9272ae1acdaaa79ab649f6b48b72088ca2b1d74]
+title = Maze
+author = David Winter
+platform = vip
+ipf = 10
+keys = none
+
+[d979858bb9ffd07b48f52f92a8bcac0199f3623e]
+title = Merlin
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 4, 5, 7 and 8 are the four squares, pressed in the order they lit
+
+[0d0cc129dad3c45ba672f85fec71a668232212cc]
+title = Missile Command
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 8 fires
+
+[b232ef880bd6060fb45fa6effed7edf0ae95670e]
+title = Pong
+author = Paul Vervalin
+year = 1990
+platform = chip48
+ipf = 10
+quirks = none
+keys = 1 and 4 move the left paddle up and down, C and D the right
+
+[a60611339661e3ab2d8af024ad1da5880a6f8665]
+title = Pong 2
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 1 and 4 move the left paddle up and down, C and D the right
+
+[1293db0ccccbe7dd3fc5a09a2abc5d7b175e18e0]
+title = Puzzle
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 2, 4, 6 and 8 move the gap
+
+[1bdb4ddaa7049266fa3226851f28855a365cfd12]
+title = Syzygy
+author = Roy Trevino
+year = 1990
+platform = chip48
+ipf = 15
+quirks = none
+keys = 3 and 6 move up and down, 7 and 8 left and right, E starts without a border and F with one
+
+[18b9d15f4c159e1f0ed58c2d8ec1d89325d3a3b6]
+title = Tank
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 2, 4, 6 and 8 steer, 5 fires
+
+[5f518084744bf3cb8733f6e5454dfd1634320563]
+title = Tetris
+author = Fran Dachille
+year = 1991
+platform = chip48
+ipf = 10
+quirks = none
+keys = 4 rotates, 5 and 6 move left and right, 7 drops
+
+[429d455a4bc53167942bf6fd934d72b0f648dce3]
+title = Tic-Tac-Toe
+author = David Winter
+platform = chip48
+ipf = 10
+quirks = none
+keys = 1 to 9 mark the squares, numbered from the top left
+
+[bdb92475acfe11bc7814a2f5eade13fcd09b756a]
+title = UFO
+author = Lutz V
+year = 1992
+platform = chip48
+ipf = 10
+quirks = none
+keys = 4, 5 and 6 fire left, up and right
+
+[da710f631f8e35534d0b9170bcf892a60f49c43d]
+title = Vertical Brix
+author = Paul Robson
+year = 1996
+platform = chip48
+ipf = 10
+quirks = none
+keys = 1 and 4 move the paddle up and down, 7 starts
+
+[ade839585ddeb0e3633177df03c1d91589e629eb]
+title = Vers
+author = JMN
+year = 1991
+platform = chip48
+ipf = 10
+quirks = none
+keys = the left player steers with 1, 2, 7 and A, the right with B, C, D and F
+
+[d666688a8fce468a7d88b536bc1ef5f35ba12031]
+title = Wipe Off
+author = Joseph Weisbecker
+platform = vip
+ipf = 10
+keys = 4 and 6 move the paddle left and right
+";
+
+fn parse_quirks(text: &str) -> Result<Quirks, String> {
+    if text == "none" {
+        return Ok(Quirks::NONE);
+    }
+    let mut bits = 0;
+    for name in text.split(',').map(str::trim) {
+        match Quirks::NAMES.iter().position(|&quirk| quirk == name) {
+            Some(bit) => bits |= 1 << bit,
+            None => return Err(format!("unknown quirk '{}'", name)),
+        }
+    }
+    Ok(Quirks::from_bits(bits))
+}
+
+// a SHA-1 as 40 hex digits, in either case
+fn is_hash(text: &str) -> bool {
+    text.len() == 40 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database::default()
+    }
+
+    // the ROMs in web/roms
+    pub fn builtin() -> Database {
+        Database::parse(BUILTIN).expect("the built in ROM database is valid")
+    }
+
+    pub fn parse(text: &str) -> Result<Database, DatabaseError> {
+        let mut database = Database::new();
+        // the hash of the section being read, its fields, and whether quirks
+        // were given rather than taken from the platform
+        let mut section: Option<(String, RomInfo, bool)> = None;
+        let mut title_line = 0;
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| Err(DatabaseError { line: index + 1, message });
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let hash = line[1..line.len() - 1].trim().to_ascii_lowercase();
+                if !is_hash(&hash) {
+                    return error(format!("'{}' is not a SHA-1", hash));
+                }
+                database.add_section(section.take(), title_line)?;
+                if database.roms.contains_key(&hash) {
+                    return error(format!("ROM {} is already defined", hash));
+                }
+                let info = RomInfo {
+                    title: String::new(),
+                    author: String::new(),
+                    year: None,
+                    platform: Platform::CosmacVip,
+                    instructions_per_frame: 10,
+                    quirks: Quirks::COSMAC_VIP,
+                    keys: String::new(),
+                };
+                section = Some((hash, info, false));
+                title_line = index + 1;
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+                None => return error("expected [SHA-1] or KEY = VALUE".to_string()),
+            };
+            let (info, quirks_given) = match section {
+                Some((_, ref mut info, ref mut quirks_given)) => (info, quirks_given),
+                None => return error(format!("'{}' is outside a ROM's section", key)),
+            };
+            match key {
+                "title" => info.title = value.to_string(),
+                "author" => info.author = value.to_string(),
+                "year" => match value.parse() {
+                    Ok(year) => info.year = Some(year),
+                    Err(_) => return error(format!("invalid year '{}'", value)),
+                },
+                "platform" => match Platform::from_name(value) {
+                    Some(platform) => {
+                        info.platform = platform;
+                        if !*quirks_given {
+                            info.quirks = platform.quirks();
+                        }
+                    },
+                    None => return error(format!("unknown platform '{}'", value)),
+                },
+                "ipf" => match value.parse() {
+                    Ok(ipf) if ipf > 0 => info.instructions_per_frame = ipf,
+                    _ => return error(format!("invalid instructions per frame '{}'", value)),
+                },
+                "quirks" => match parse_quirks(value) {
+                    Ok(quirks) => {
+                        info.quirks = quirks;
+                        *quirks_given = true;
+                    },
+                    Err(message) => return error(message),
+                },
+                "keys" => info.keys = value.to_string(),
+                _ => return error(format!("unknown key '{}'", key)),
+            }
+        }
+        database.add_section(section, title_line)?;
+        Ok(database)
+    }
+
+    fn add_section(&mut self, section: Option<(String, RomInfo, bool)>, line: usize) -> Result<(), DatabaseError> {
+        if let Some((hash, info, _)) = section {
+            if info.title.is_empty() {
+                return Err(DatabaseError { line, message: format!("ROM {} has no title", hash) });
+            }
+            self.roms.insert(hash, info);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+
+    // adds the ROMs of other, which take precedence
+    pub fn merge(&mut self, other: &Database) {
+        for (hash, info) in &other.roms {
+            self.roms.insert(hash.clone(), info.clone());
+        }
+    }
+
+    // the ROM with the SHA-1, as 40 hex digits
+    pub fn get(&self, hash: &str) -> Option<&RomInfo> {
+        self.roms.get(&hash.to_ascii_lowercase())
+    }
+
+    // the ROM with the contents
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.get(&sha1::hex(&sha1::sha1(rom)))
+    }
+}
+
+// writes the database in the format parse reads
+impl fmt::Display for Database {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, (hash, info)) in self.roms.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "[{}]\ntitle = {}", hash, info.title)?;
+            if !info.author.is_empty() {
+                writeln!(f, "author = {}", info.author)?;
+            }
+            if let Some(year) = info.year {
+                writeln!(f, "year = {}", year)?;
+            }
+            writeln!(f, "platform = {}\nipf = {}", info.platform.name(), info.instructions_per_frame)?;
+            if info.quirks != info.platform.quirks() {
+                let bits = info.quirks.bits();
+                let names: Vec<_> = Quirks::NAMES.iter().enumerate()
+                    .filter(|&(bit, _)| bits & 1 << bit != 0)
+                    .map(|(_, &name)| name)
+                    .collect();
+                writeln!(f, "quirks = {}", if names.is_empty() { "none".to_string() } else { names.join(", ") })?;
+            }
+            if !info.keys.is_empty() {
+                writeln!(f, "keys = {}", info.keys)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Database;
+    use quirks::{Platform, Quirks};
+    use std::fs;
+
+    #[test]
+    fn knows_bundled_roms() {
+        let database = Database::builtin();
+        let mut count = 0;
+        for entry in fs::read_dir("web/roms").unwrap() {
+            let path = entry.unwrap().path();
+            let rom = fs::read(&path).unwrap();
+            assert!(database.lookup(&rom).is_some(), "{} is not in the database", path.display());
+            count += 1;
+        }
+        assert_eq!(database.len(), count);
+
+        let pong = database.get("B232EF880BD6060FB45FA6EFFED7EDF0AE95670E").unwrap();
+        assert_eq!(pong.title, "Pong");
+        assert_eq!(pong.year, Some(1990));
+        assert_eq!(pong.platform, Platform::Chip48);
+        assert_eq!(pong.quirks, Quirks::NONE);
+        let kaleid = database.get("d6fa9dc9005dc0496f39ba52fef56f9fd0a5a158").unwrap();
+        assert_eq!(kaleid.quirks, Quirks::COSMAC_VIP, "quirks default to the platform's");
+
+        assert_eq!(Database::parse(&database.to_string()), Ok(database));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text: &str| Database::parse(text).unwrap_err().to_string();
+        let hash = "[0d0cc129dad3c45ba672f85fec71a668232212cc]\n";
+        assert_eq!(error("title = Pong"), "1: 'title' is outside a ROM's section");
+        assert_eq!(error("[pong]"), "1: 'pong' is not a SHA-1");
+        assert_eq!(error(&format!("{}title", hash)), "2: expected [SHA-1] or KEY = VALUE");
+        assert_eq!(error(&format!("{}year = 90s", hash)), "2: invalid year '90s'");
+        assert_eq!(error(&format!("{}platform = nes", hash)), "2: unknown platform 'nes'");
+        assert_eq!(error(&format!("{}ipf = 0", hash)), "2: invalid instructions per frame '0'");
+        assert_eq!(error(&format!("{}quirks = clip", hash)), "2: unknown quirk 'clip'");
+        assert_eq!(error(&format!("{}speed = 10", hash)), "2: unknown key 'speed'");
+        assert_eq!(error(&format!("{}\nyear = 1990", hash)), "1: ROM 0d0cc129dad3c45ba672f85fec71a668232212cc has no title");
+        assert_eq!(error(&format!("{}title = A\n{}title = B", hash, hash)),
+                   "3: ROM 0d0cc129dad3c45ba672f85fec71a668232212cc is already defined");
+    }
+
+    #[test]
+    fn merges() {
+        let mut database = Database::builtin();
+        let other = Database::parse("\
+[0D0CC129DAD3C45BA672F85FEC71A668232212CC]
+title = Missile
+platform = schip
+quirks = shift_uses_vy, clip_sprites ; set in full, not added to the platform's
+
+[0123456789abcdef0123456789abcdef01234567]
+title = Homebrew
+").unwrap();
+        database.merge(&other);
+        assert_eq!(database.len(), 25);
+        let missile = database.get("0d0cc129dad3c45ba672f85fec71a668232212cc").unwrap();
+        assert_eq!(missile.title, "Missile");
+        assert_eq!(missile.author, "", "an entry replaces the one it merges over");
+        assert_eq!(missile.quirks, Quirks { shift_uses_vy: true, clip_sprites: true, ..Quirks::NONE });
+        assert_eq!(database.get("0123456789abcdef0123456789abcdef01234567").unwrap().platform, Platform::CosmacVip);
+    }
+}
@@ -0,0 +1,299 @@
+use std::error::Error;
+use std::fmt;
+
+use cpu::{Cpu, MEMORY_SIZE};
+use display::{MAX_HEIGHT, MAX_WIDTH};
+use quirks::Quirks;
+use rand::CMWC_CYCLE;
+
+// Save states capture the complete machine, so that loading one resumes
+// execution exactly where it left off, random numbers included. The format
+// is binary with all multi-byte values big-endian:
+//
+//     magic           4 bytes, "C8ST"
+//     version         u16, currently 1
+//     platform        u8 XO-CHIP enabled, u8 quirks as per Quirks::bits
+//     registers       16 x u8 V0-VF, u16 I, u16 PC
+//     stack           u8 SP, 16 x u16 frames
+//     timers          u8 DT, u8 ST
+//     frame           u64 frames since reset
+//     halted          u8
+//     RPL flags       16 x u8
+//     audio           16 x u8 pattern, u8 pitch
+//     keys            u16, bit n set while key n is down
+//     display         u8 hires, u8 selected planes, 128 x 64 x u8 pixels
+//     memory          65536 x u8
+//     RNG             u32 carry, u16 index, 4096 x u32 state
+//
+// The version is bumped whenever the layout changes.
+const MAGIC: &[u8; 4] = b"C8ST";
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    // the data does not start with the save state magic
+    BadMagic,
+    // the state was saved by a different version of the format
+    UnsupportedVersion(u16),
+    // the data ends part way through the state
+    Truncated,
+    // the data continues after the end of the state
+    TrailingBytes,
+    // a field holds a value the machine cannot be in
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::TrailingBytes => write!(f, "save state has trailing bytes"),
+            StateError::InvalidField(field) => write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub(crate) struct Reader<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self, field: &'static str) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidField(field)),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok((bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+}
+
+impl Cpu {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MEMORY_SIZE + MAX_WIDTH * MAX_HEIGHT + CMWC_CYCLE * 4 + 128);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&STATE_VERSION.to_be_bytes());
+        out.push(self.xo_chip as u8);
+        out.push(self.quirks.bits());
+        out.extend_from_slice(&self.v);
+        out.extend_from_slice(&self.i.to_be_bytes());
+        out.extend_from_slice(&self.pc.to_be_bytes());
+        out.push(self.sp);
+        for frame in &self.stack {
+            out.extend_from_slice(&frame.to_be_bytes());
+        }
+        out.push(self.dt);
+        out.push(self.st);
+        out.extend_from_slice(&self.frame.to_be_bytes());
+        out.push(self.halted as u8);
+        out.extend_from_slice(&self.rpl);
+        out.extend_from_slice(&self.audio_pattern);
+        out.push(self.pitch);
+        let keys = self.keypad.keys.iter().enumerate()
+            .fold(0u16, |keys, (key, &down)| keys | (down as u16) << key);
+        out.extend_from_slice(&keys.to_be_bytes());
+        out.push(self.display.hires as u8);
+        out.push(self.display.planes);
+        out.extend_from_slice(&self.display.memory);
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&self.rand.c.to_be_bytes());
+        out.extend_from_slice(&(self.rand.i as u16).to_be_bytes());
+        for word in self.rand.q.iter() {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+
+    // restores a state produced by save_state. The state is validated in
+    // full before any of it is applied, so on error the CPU is unchanged.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader { data };
+        if reader.bytes(4).map_err(|_| StateError::BadMagic)? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let xo_chip = reader.bool("platform")?;
+        // every byte is a valid set of quirks
+        let quirks = reader.u8()?;
+        let mut v = [0; 16];
+        v.copy_from_slice(reader.bytes(16)?);
+        let i = reader.u16()?;
+        let pc = reader.u16()?;
+        let sp = reader.u8()?;
+        if sp as usize > 16 {
+            return Err(StateError::InvalidField("stack pointer"));
+        }
+        let mut stack = [0; 16];
+        for frame in stack.iter_mut() {
+            *frame = reader.u16()?;
+        }
+        let dt = reader.u8()?;
+        let st = reader.u8()?;
+        let frame = reader.u64()?;
+        let halted = reader.bool("halted flag")?;
+        let mut rpl = [0; 16];
+        rpl.copy_from_slice(reader.bytes(16)?);
+        let mut audio_pattern = [0; 16];
+        audio_pattern.copy_from_slice(reader.bytes(16)?);
+        let pitch = reader.u8()?;
+        let keys = reader.u16()?;
+        let hires = reader.bool("resolution")?;
+        let planes = reader.u8()?;
+        if planes > 3 {
+            return Err(StateError::InvalidField("plane selection"));
+        }
+        let display = reader.bytes(MAX_WIDTH * MAX_HEIGHT)?;
+        if display.iter().any(|&pixel| pixel > 3) {
+            return Err(StateError::InvalidField("display"));
+        }
+        let memory = reader.bytes(MEMORY_SIZE)?;
+        let carry = reader.u32()?;
+        let index = reader.u16()? as usize;
+        if index >= CMWC_CYCLE {
+            return Err(StateError::InvalidField("RNG index"));
+        }
+        let mut q = [0; CMWC_CYCLE];
+        for word in q.iter_mut() {
+            *word = reader.u32()?;
+        }
+        if !reader.data.is_empty() {
+            return Err(StateError::TrailingBytes);
+        }
+
+        self.xo_chip = xo_chip;
+        self.quirks = Quirks::from_bits(quirks);
+        self.v = v;
+        self.i = i;
+        self.pc = pc;
+        self.sp = sp;
+        self.stack = stack;
+        self.dt = dt;
+        self.st = st;
+        self.frame = frame;
+        self.halted = halted;
+        self.rpl = rpl;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        for (key, down) in self.keypad.keys.iter_mut().enumerate() {
+            *down = keys & 1 << key != 0;
+        }
+        self.display.hires = hires;
+        self.display.planes = planes;
+        self.display.memory.copy_from_slice(display);
+        self.memory.copy_from_slice(memory);
+        self.rand.c = carry;
+        self.rand.i = index;
+        self.rand.q = q;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{StateError, MAGIC};
+    use cpu::Cpu;
+    use quirks::Platform;
+
+    // a CPU part way through a program which draws random sprites
+    fn running_cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::SuperChip);
+        // LD I, 0; RND V0, 0xFF; RND V1, 0xFF; DRW V0, V1, 5; CALL 0x20A; JP 0x202; RET
+        let program = [0xA0, 0x00, 0xC0, 0xFF, 0xC1, 0xFF, 0xD0, 0x15, 0x22, 0x0C, 0x12, 0x02, 0x00, 0xEE];
+        cpu.load_rom(&program).unwrap();
+        cpu.keypad.key_down(0xA);
+        cpu.dt = 40;
+        cpu.st = 3;
+        cpu.rpl[5] = 9;
+        for _ in 0..25 {
+            cpu.execute_cycle().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+
+        let mut restored = Cpu::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.quirks, cpu.quirks);
+        assert!(restored.keypad.is_key_down(0xA));
+        assert_eq!(restored.rpl[5], 9);
+
+        // both machines continue identically, random numbers included
+        for _ in 0..100 {
+            cpu.execute_cycle().unwrap();
+            restored.execute_cycle().unwrap();
+        }
+        assert_eq!(restored.save_state(), cpu.save_state());
+        assert_eq!(&restored.display.memory[..], &cpu.display.memory[..]);
+    }
+
+    #[test]
+    fn load_rejects_bad_states() {
+        let cpu = running_cpu();
+        let state = cpu.save_state();
+        let mut target = Cpu::new();
+        let before = target.save_state();
+
+        assert_eq!(target.load_state(b"nope"), Err(StateError::BadMagic));
+        assert_eq!(target.load_state(&[]), Err(StateError::BadMagic));
+
+        let mut version = state.clone();
+        version[MAGIC.len() + 1] = 99;
+        assert_eq!(target.load_state(&version), Err(StateError::UnsupportedVersion(99)));
+
+        assert_eq!(target.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+
+        let mut trailing = state.clone();
+        trailing.push(0);
+        assert_eq!(target.load_state(&trailing), Err(StateError::TrailingBytes));
+
+        // the stack pointer follows the magic, version, platform and registers
+        let mut sp = state.clone();
+        sp[4 + 2 + 2 + 16 + 4] = 17;
+        assert_eq!(target.load_state(&sp), Err(StateError::InvalidField("stack pointer")));
+
+        // a failed load leaves the CPU untouched
+        assert_eq!(target.save_state(), before);
+    }
+}
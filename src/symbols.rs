@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+// Names for addresses, and where in the source each address came from, read
+// from a symbol file, one per line:
+//
+//     ; comments run to the end of the line
+//     draw_paddle = 0x2A0      ; a label
+//     0x2A4 = pong.asm:42      ; the source line of an address
+//
+// Numbers are decimal or 0x hex. The assembler writes these for the programs
+// it assembles. An address may have several names, all of which resolve, and
+// is labelled with the first of them in alphabetical order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    // the names at each address, kept in step with addresses
+    labels: BTreeMap<u16, BTreeSet<String>>,
+    addresses: BTreeMap<String, u16>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SymbolError {}
+
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// labels are those the assembler accepts, e.g. draw_paddle or loop.2
+fn is_name(text: &str) -> bool {
+    text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| Err(SymbolError { line: index + 1, message });
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (left, right) = match line.find('=') {
+                Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+                None => return error("expected NAME = ADDRESS or ADDRESS = FILE:LINE".to_string()),
+            };
+            if let Some(address) = parse_number(left) {
+                let source = right.rfind(':').and_then(|colon| {
+                    let line = right[colon + 1..].parse().ok()?;
+                    Some(SourceLine { file: right[..colon].to_string(), line })
+                });
+                match source {
+                    Some(ref source) if !source.file.is_empty() => symbols.add_line(address, source.clone()),
+                    _ => return error(format!("invalid source line '{}', expected FILE:LINE", right)),
+                }
+            } else if is_name(left) {
+                match parse_number(right) {
+                    Some(address) if !symbols.addresses.contains_key(left) => symbols.add_label(left, address),
+                    Some(_) => return error(format!("'{}' is already defined", left)),
+                    None => return error(format!("invalid address '{}'", right)),
+                }
+            } else {
+                return error(format!("'{}' is not a valid name or address", left));
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.lines.is_empty()
+    }
+
+    // names an address, moving the name if it was already defined elsewhere
+    pub fn add_label(&mut self, name: &str, address: u16) {
+        if let Some(old) = self.addresses.insert(name.to_string(), address) {
+            let names = self.labels.get_mut(&old).unwrap();
+            names.remove(name);
+            if names.is_empty() {
+                self.labels.remove(&old);
+            }
+        }
+        self.labels.entry(address).or_default().insert(name.to_string());
+    }
+
+    pub fn add_line(&mut self, address: u16, source: SourceLine) {
+        self.lines.insert(address, source);
+    }
+
+    // adds the labels and source lines of other, which take precedence
+    pub fn merge(&mut self, other: &Symbols) {
+        for (name, &address) in &other.addresses {
+            self.add_label(name, address);
+        }
+        for (&address, source) in &other.lines {
+            self.add_line(address, source.clone());
+        }
+    }
+
+    // the label at exactly the address
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).and_then(|names| names.iter().next()).map(|name| name.as_str())
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).cloned()
+    }
+
+    // the source line an address was assembled from
+    pub fn source(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    // the address of "draw_paddle", "draw_paddle+4", "0x2A4" or "676"
+    pub fn resolve(&self, location: &str) -> Option<u16> {
+        let location = location.trim();
+        if let Some(address) = parse_number(location) {
+            return Some(address);
+        }
+        let (name, offset) = match location.find(['+', '-']) {
+            Some(sign) => {
+                let offset = parse_number(location[sign + 1..].trim())? as i32;
+                (location[..sign].trim(), if location[sign..].starts_with('-') { -offset } else { offset })
+            },
+            None => (location, 0),
+        };
+        let address = self.address(name)? as i32 + offset;
+        if (0..=0xFFFF).contains(&address) { Some(address as u16) } else { None }
+    }
+
+    // the address relative to the nearest label at or before it, e.g.
+    // "draw_paddle+4", with the offset in decimal
+    pub fn location(&self, address: u16) -> Option<String> {
+        let (&start, names) = self.labels.range(..=address).next_back()?;
+        let name = names.iter().next()?;
+        Some(if start == address { name.clone() } else { format!("{}+{}", name, address - start) })
+    }
+
+    // the address, with its location and source line where known, e.g.
+    // "0x02A4 draw_paddle+4 (pong.asm:42)"
+    pub fn describe(&self, address: u16) -> String {
+        let mut out = format!("0x{:04X}", address);
+        if let Some(location) = self.location(address) {
+            out += &format!(" {}", location);
+        }
+        if let Some(source) = self.source(address) {
+            out += &format!(" ({})", source);
+        }
+        out
+    }
+}
+
+// writes the symbols in the format parse reads
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (address, names) in &self.labels {
+            for name in names {
+                writeln!(f, "{} = 0x{:03X}", name, address)?;
+            }
+        }
+        for (address, source) in &self.lines {
+            writeln!(f, "0x{:03X} = {}", address, source)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceLine, Symbols};
+
+    const FILE: &str = "\
+; pong
+main = 0x200
+draw_paddle = 0x2A0   ; draws both paddles
+0x2A4 = pong.asm:42
+";
+
+    #[test]
+    fn parses_and_resolves() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(symbols.address("draw_paddle"), Some(0x2A0));
+        assert_eq!(symbols.label(0x200), Some("main"));
+        assert_eq!(symbols.source(0x2A4), Some(&SourceLine { file: "pong.asm".to_string(), line: 42 }));
+
+        assert_eq!(symbols.resolve("draw_paddle+4"), Some(0x2A4));
+        assert_eq!(symbols.resolve("draw_paddle - 0x10"), Some(0x290));
+        assert_eq!(symbols.resolve("0x2A4"), Some(0x2A4));
+        assert_eq!(symbols.resolve("676"), Some(0x2A4));
+        assert_eq!(symbols.resolve("draw"), None);
+        assert_eq!(symbols.resolve("main-0x201"), None);
+
+        assert_eq!(symbols.location(0x1FF), None);
+        assert_eq!(symbols.location(0x2A0), Some("draw_paddle".to_string()));
+        assert_eq!(symbols.location(0x29E), Some("main+158".to_string()));
+        assert_eq!(symbols.describe(0x2A4), "0x02A4 draw_paddle+4 (pong.asm:42)");
+        assert_eq!(symbols.describe(0x100), "0x0100");
+
+        assert_eq!(Symbols::parse(&symbols.to_string()), Ok(symbols));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text: &str| Symbols::parse(text).unwrap_err().to_string();
+        assert_eq!(error("main 0x200"), "1: expected NAME = ADDRESS or ADDRESS = FILE:LINE");
+        assert_eq!(error("\nmain = 0x10000"), "2: invalid address '0x10000'");
+        assert_eq!(error("main = 1\nmain = 2"), "2: 'main' is already defined");
+        assert_eq!(error("0x200 = pong.asm"), "1: invalid source line 'pong.asm', expected FILE:LINE");
+        assert_eq!(error("2main = 2"), "1: '2main' is not a valid name or address");
+    }
+
+    #[test]
+    fn merges() {
+        let mut symbols = Symbols::parse(FILE).unwrap();
+        let mut other = Symbols::new();
+        other.add_label("paddles", 0x2A0);
+        other.add_label("ball", 0x2C0);
+        other.add_label("main", 0x210);
+        symbols.merge(&other);
+        assert_eq!(symbols.address("paddles"), Some(0x2A0));
+        assert_eq!(symbols.address("draw_paddle"), Some(0x2A0), "names sharing an address are kept");
+        assert_eq!(symbols.resolve("ball+2"), Some(0x2C2));
+        assert_eq!(symbols.address("main"), Some(0x210), "the merged definition takes precedence");
+        assert_eq!(symbols.label(0x200), None);
+    }
+
+    #[test]
+    fn labels_addresses_with_several_names() {
+        let symbols = Symbols::parse("b = 0x200
+a = 0x200
+c = 0x204").unwrap();
+        assert_eq!((symbols.address("a"), symbols.address("b")), (Some(0x200), Some(0x200)));
+        assert_eq!(symbols.label(0x200), Some("a"), "the first name alphabetically labels the address");
+        assert_eq!(symbols.location(0x202), Some("a+2".to_string()));
+        assert_eq!(symbols.to_string(), "a = 0x200\nb = 0x200\nc = 0x204\n");
+        assert_eq!(Symbols::parse(&symbols.to_string()), Ok(symbols.clone()));
+
+        let mut moved = symbols;
+        moved.add_label("a", 0x204);
+        assert_eq!(moved.label(0x200), Some("b"));
+        assert_eq!(moved.label(0x204), Some("a"));
+    }
+}
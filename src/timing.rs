@@ -0,0 +1,93 @@
+use cpu::Cpu;
+use instruction::Instruction;
+
+// The COSMAC VIP runs at 1.76064 MHz, with 8 clocks to a machine cycle, so
+// a 60 Hz frame is 3668 machine cycles. The video DMA steals one of those for
+// each of the 1024 bytes displayed, 8 bytes for each of 128 scan lines, and
+// the interrupt routine which runs the timers takes another 46, leaving the
+// rest for the interpreter.
+pub const VIP_FRAME_CYCLES: u32 = 3668 - 1024 - 46;
+
+// the interpreter's fetch and decode, common to every instruction
+const FETCH: u32 = 40;
+
+// the cost of instructions the VIP interpreter does not have, as the
+// simplest it does
+const FOREIGN: u32 = FETCH + 10;
+
+// Machine cycles taken by the COSMAC VIP interpreter to execute the
+// instruction, given the state the CPU is in before it executes. The counts
+// follow the interpreter's code: skips taken cost 4 more than those not,
+// Fx33 loops once for each unit of each digit, Fx55 and Fx65 once per
+// register, and Dxyn once per row drawn, with rows which straddle a byte of
+// display memory costing more than those which don't.
+pub fn vip_cycles(instruction: Instruction, cpu: &Cpu) -> u32 {
+    use instruction::Instruction::*;
+
+    let skip = |cost: u32, taken: bool| FETCH + if taken { cost + 4 } else { cost };
+    let v = |x: u8| cpu.v[x as usize];
+    let key = |x: u8| cpu.keypad.is_key_down(v(x) & 0xF);
+    FETCH + match instruction {
+        Cls => 3038,
+        Ret => 10,
+        Jp(_) => 12,
+        Call(_) => 26,
+        SeByte(x, kk) => return skip(10, v(x) == kk),
+        SneByte(x, kk) => return skip(10, v(x) != kk),
+        SeReg(x, y) => return skip(10, v(x) == v(y)),
+        SneReg(x, y) => return skip(10, v(x) != v(y)),
+        LdByte(..) => 6,
+        AddByte(..) => 10,
+        // the VIP builds and calls a subroutine for the 8xyn arithmetic
+        LdReg(..) | Or(..) | And(..) | Xor(..) | AddReg(..) | Sub(..) | Shr(..) | Subn(..) | Shl(..) => 44,
+        LdI(_) => 12,
+        JpV0(_) => 22,
+        Rnd(..) => 36,
+        Drw(x, y, n) => {
+            let rows = (n as u32).min(32 - v(y) as u32 % 32);
+            26 + rows * if v(x).is_multiple_of(8) { 34 } else { 50 }
+        },
+        Skp(x) => return skip(18, key(x)),
+        Sknp(x) => return skip(18, !key(x)),
+        LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+        // each poll of the keypad while waiting
+        LdVxK(_) => 18,
+        AddIVx(_) => 16,
+        LdFVx(_) => 20,
+        LdBVx(x) => {
+            let digits = v(x) as u32 / 100 + v(x) as u32 / 10 % 10 + v(x) as u32 % 10;
+            60 + digits * 16
+        },
+        LdIVx(x) | LdVxI(x) => 14 + 14 * (x as u32 + 1),
+        _ => return FOREIGN,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{vip_cycles, FOREIGN};
+    use cpu::Cpu;
+    use instruction::Instruction::*;
+
+    #[test]
+    fn costs_vip_instructions() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 5;
+        cpu.v[1] = 8;
+        cpu.v[2] = 30;
+        assert_eq!(vip_cycles(LdByte(0, 1), &cpu), 46);
+        assert_eq!(vip_cycles(SeByte(0, 5), &cpu), 54, "a skip taken costs more");
+        assert_eq!(vip_cycles(SeByte(0, 6), &cpu), 50);
+        assert_eq!(vip_cycles(LdBVx(2), &cpu), 40 + 60 + 3 * 16);
+        assert_eq!(vip_cycles(LdIVx(3), &cpu), 40 + 14 + 4 * 14);
+
+        // aligned rows, then rows straddling two bytes, then rows clipped at
+        // the bottom of the screen
+        assert_eq!(vip_cycles(Drw(1, 0, 5), &cpu), 40 + 26 + 5 * 34);
+        assert_eq!(vip_cycles(Drw(0, 0, 5), &cpu), 40 + 26 + 5 * 50);
+        assert_eq!(vip_cycles(Drw(1, 2, 5), &cpu), 40 + 26 + 2 * 34);
+
+        assert_eq!(vip_cycles(High, &cpu), FOREIGN);
+    }
+}
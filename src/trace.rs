@@ -0,0 +1,293 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use cpu::{Cpu, CpuError, MemoryAccess};
+use disasm::{self, Syntax};
+use instruction::Instruction;
+use symbols::Symbols;
+
+// the registers an instruction may change, other than the program counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl Registers {
+    pub fn of(cpu: &Cpu) -> Registers {
+        Registers { v: cpu.v, i: cpu.i, sp: cpu.sp, dt: cpu.dt, st: cpu.st }
+    }
+}
+
+// an instruction executed by Cpu::execute_cycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    // the frame the instruction ran in
+    pub frame: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // the second word of an XO-CHIP F000 nnnn
+    pub operand: Option<u16>,
+    // the registers before the instruction, the CPU passed alongside the
+    // event holds them after
+    pub before: Registers,
+    // the data memory the instruction read or wrote, or would have
+    pub access: Option<MemoryAccess>,
+    // set if the instruction faulted, and so changed nothing
+    pub fault: Option<CpuError>,
+}
+
+// receives every instruction executed, when set as Cpu::tracer
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent, cpu: &Cpu);
+}
+
+// shares a tracer, so that its results can be read while it is installed
+impl<T: Tracer> Tracer for Arc<Mutex<T>> {
+    fn trace(&mut self, event: &TraceEvent, cpu: &Cpu) {
+        self.lock().unwrap().trace(event, cpu);
+    }
+}
+
+// passes each event to several tracers in turn
+impl Tracer for Vec<Box<dyn Tracer + Send>> {
+    fn trace(&mut self, event: &TraceEvent, cpu: &Cpu) {
+        for tracer in self.iter_mut() {
+            tracer.trace(event, cpu);
+        }
+    }
+}
+
+// Loads program as a ROM and runs it with tracer installed, for frames of
+// instructions_per_frame instructions or until one faults, returning the
+// tracer once it has seen every instruction.
+#[cfg(test)]
+pub fn run_traced<T: Tracer + Send + 'static>(cpu: &mut Cpu, program: &[u8], frames: usize,
+                                              instructions_per_frame: usize, tracer: T) -> T {
+    cpu.load_rom(program).unwrap();
+    let shared = Arc::new(Mutex::new(tracer));
+    cpu.tracer = Some(Box::new(shared.clone()));
+    'run: for _ in 0..frames {
+        for _ in 0..instructions_per_frame {
+            if cpu.execute_cycle().is_err() {
+                break 'run;
+            }
+        }
+        cpu.decrement_timers();
+    }
+    cpu.tracer = None;
+    Arc::try_unwrap(shared).ok().unwrap().into_inner().unwrap()
+}
+
+// Formats an event as a single line of the trace format, which is stable so
+// that traces can be compared across versions and with other emulators:
+//
+//     000012 0204  7001      ADD V0, 0x01           | V0=06 VF=00
+//
+// The frame in decimal, the pc, the opcode, with the second word of F000 nnnn,
+// the instruction, then after a bar the registers it changed, or the fault it
+// raised. Register values are hex, I four digits and the rest two.
+//
+// With symbols, the pc is followed by its location, e.g. draw_paddle+4, and
+// the instruction uses their names.
+pub fn format_event(event: &TraceEvent, cpu: &Cpu, symbols: &Symbols) -> String {
+    let opcode = match event.operand {
+        Some(operand) => format!("{:04X} {:04X}", event.opcode, operand),
+        None => format!("{:04X}", event.opcode),
+    };
+    let text = match Instruction::decode(event.opcode) {
+        Some(Instruction::LdILong) if event.operand.is_none() => "???".to_string(),
+        Some(instruction) => disasm::format_instruction(instruction, event.operand, Syntax::Cowgod,
+                                                         &|address| symbols.label(address).map(str::to_string)),
+        None => "???".to_string(),
+    };
+    let pc = if symbols.is_empty() {
+        format!("{:04X}", event.pc)
+    } else {
+        format!("{:04X} {:20}", event.pc, symbols.location(event.pc).unwrap_or_default())
+    };
+
+    let mut changes = Vec::new();
+    if let Some(fault) = event.fault {
+        changes.push(format!("FAULT {}", fault));
+    } else {
+        let (before, after) = (&event.before, Registers::of(cpu));
+        for x in 0..16 {
+            if before.v[x] != after.v[x] {
+                changes.push(format!("V{:X}={:02X}", x, after.v[x]));
+            }
+        }
+        if before.i != after.i {
+            changes.push(format!("I={:04X}", after.i));
+        }
+        if before.sp != after.sp {
+            changes.push(format!("SP={:02X}", after.sp));
+        }
+        if before.dt != after.dt {
+            changes.push(format!("DT={:02X}", after.dt));
+        }
+        if before.st != after.st {
+            changes.push(format!("ST={:02X}", after.st));
+        }
+    }
+
+    let line = format!("{:06} {}  {:9} {:22} | {}", event.frame, pc, opcode, text, changes.join(" "));
+    line.trim_end_matches([' ', '|']).to_string()
+}
+
+// writes each event as a line of the trace format
+pub struct TraceWriter<W: Write> {
+    out: W,
+    symbols: Symbols,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W) -> TraceWriter<W> {
+        TraceWriter::with_symbols(out, Symbols::new())
+    }
+
+    pub fn with_symbols(out: W, symbols: Symbols) -> TraceWriter<W> {
+        TraceWriter { out, symbols, error: None }
+    }
+
+    // flushes the output, returning the first error writing the trace
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn trace(&mut self, event: &TraceEvent, cpu: &Cpu) {
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.out, "{}", format_event(event, cpu, &self.symbols)) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+// the first line at which two traces differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // 1-based
+    pub line: usize,
+    // the lines before the divergence, which both traces share
+    pub context: Vec<String>,
+    // the differing lines, None where a trace has ended
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "traces diverge at line {}", self.line)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "- {}", self.left.as_ref().map_or("<end of trace>", |line| line.as_str()))?;
+        writeln!(f, "+ {}", self.right.as_ref().map_or("<end of trace>", |line| line.as_str()))
+    }
+}
+
+// compares two traces line by line, ignoring trailing whitespace, returning
+// the first divergence with up to context lines before it
+pub fn diff(left: &str, right: &str, context: usize) -> Option<Divergence> {
+    let mut left_lines = left.lines().map(str::trim_end);
+    let mut right_lines = right.lines().map(str::trim_end);
+    let mut previous: Vec<&str> = Vec::new();
+    let mut line = 1;
+    loop {
+        match (left_lines.next(), right_lines.next()) {
+            (None, None) => return None,
+            (Some(l), Some(r)) if l == r => {
+                previous.push(l);
+                if previous.len() > context {
+                    previous.remove(0);
+                }
+            },
+            (l, r) => return Some(Divergence {
+                line,
+                context: previous.iter().map(|line| line.to_string()).collect(),
+                left: l.map(str::to_string),
+                right: r.map(str::to_string),
+            }),
+        }
+        line += 1;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, run_traced, TraceWriter};
+    use cpu::Cpu;
+    use quirks::Platform;
+    use symbols::Symbols;
+
+    fn trace(program: &[u8], cycles: usize, symbols: Symbols) -> String {
+        let writer = run_traced(&mut Cpu::new(), program, cycles, 1, TraceWriter::with_symbols(Vec::new(), symbols));
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    // LD V0, 0xFF; ADD V0, 2; LD I, 0x300; CALL 0x20A; JP 0x20A; RET
+    const PROGRAM: [u8; 12] = [0x60, 0xFF, 0x70, 0x02, 0xA3, 0x00, 0x22, 0x0A, 0x12, 0x0A, 0x00, 0xEE];
+
+    #[test]
+    fn traces_instructions() {
+        let lines: Vec<String> = trace(&PROGRAM, 100, Symbols::new()).lines().map(str::to_string).collect();
+        assert_eq!(lines, vec![
+            "000000 0200  60FF      LD V0, 0xFF            | V0=FF",
+            "000001 0202  7002      ADD V0, 0x02           | V0=01",
+            "000002 0204  A300      LD I, 0x300            | I=0300",
+            "000003 0206  220A      CALL 0x20A             | SP=01",
+            "000004 020A  00EE      RET                    | SP=00",
+            "000005 0208  120A      JP 0x20A",
+            "000006 020A  00EE      RET                    | FAULT stack underflow at 0x020A",
+        ]);
+    }
+
+    #[test]
+    fn traces_with_symbols() {
+        let symbols = Symbols::parse("main = 0x200\nreturn = 0x20A").unwrap();
+        let lines: Vec<String> = trace(&PROGRAM, 5, symbols).lines().map(str::to_string).collect();
+        assert_eq!(lines[1], "000001 0202 main+2                7002      ADD V0, 0x02           | V0=01");
+        assert_eq!(lines[3], "000003 0206 main+6                220A      CALL return            | SP=01");
+        assert_eq!(lines[4], "000004 020A return                00EE      RET                    | SP=00");
+    }
+
+    #[test]
+    fn traces_long_instructions() {
+        let mut cpu = Cpu::new();
+        cpu.set_platform(Platform::XoChip);
+        let mut writer = run_traced(&mut cpu, &[0xF0, 0x00, 0x12, 0x34], 1, 1, TraceWriter::new(Vec::new()));
+        writer.flush().unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(),
+                   "000000 0200  F000 1234 LD I, LONG 0x1234      | I=1234\n");
+    }
+
+    #[test]
+    fn reports_first_divergence() {
+        let left = "a\nb\nc\nd\ne\n";
+        assert_eq!(diff(left, "a\nb  \nc\nd\ne", 2), None);
+
+        let divergence = diff(left, "a\nb\nc\nX\ne\n", 2).unwrap();
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.context, vec!["b", "c"]);
+        assert_eq!(divergence.to_string(), "traces diverge at line 4\n  b\n  c\n- d\n+ X\n");
+
+        let divergence = diff(left, "a\nb\n", 1).unwrap();
+        assert_eq!((divergence.line, divergence.left, divergence.right), (3, Some("c".to_string()), None));
+    }
+}
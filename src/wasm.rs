@@ -1,96 +1,863 @@
-use cpu::Cpu;
-use display::Display;
-use keypad::Keypad;
-use rand::{ComplementaryMultiplyWithCarryGen, CMWC_CYCLE};
-
-// TODO: change to a constructor
-static mut CPU: Cpu = Cpu {
-    i: 0,
-    pc: 0,
-    dt: 0,
-    memory: [0; 4096],
-    v: [0; 16],
-    display: Display {
-        memory: [0; 2048]
-    },
-    keypad: Keypad {
-        keys: [false; 16]
-    },
-    stack: [0; 16],
-    sp: 0,
-    rand: ComplementaryMultiplyWithCarryGen {
-        q: [0; CMWC_CYCLE],
-        c: 0,
-        i: 0
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+
+use cpu::{Cpu, CpuError};
+use debugger::{Command, Debugger, StopReason, Watch};
+use disasm::{self, Syntax};
+use machine::{Machine, Timing};
+use quirks::{Platform, Quirks};
+use movie::{Movie, Player, Recorder};
+use rewind::Rewind;
+use romdb::Database;
+
+// The exports for the host. Any number of machines can run side by side:
+// create_machine returns a handle which every other export takes first, and
+// which stays valid until passed to destroy_machine, never to be valid again.
+// Exports given a handle which is not valid do nothing, returning 0, false or
+// a null pointer.
+//
+// Pointers into a machine, such as get_memory, stay valid for its lifetime,
+// though the host must rebuild its views of them whenever the WebAssembly
+// memory grows.
+
+enum Session {
+    Idle,
+    Recording(Recorder),
+    Playing(Player),
+}
+
+// a machine, with the buffers it exchanges with the host
+struct Instance {
+    // the machine, with the breakpoints and watchpoints set by the host
+    debugger: Debugger,
+    // the most recent fault raised by running the machine, cleared on reset
+    last_fault: Option<CpuError>,
+    // why the most recent debugger command stopped
+    stop: Option<StopReason>,
+    // text produced for the host, which reads it via get_text
+    text: String,
+    // a ROM, written by the host ahead of load_rom
+    rom: Vec<u8>,
+    // a save state, written by save_state or by the host ahead of load_state
+    state: Vec<u8>,
+    // the rewind history, captured at the end of every frame, a snapshot
+    // every 10 frames for the last 30 seconds unless configured by set_rewind
+    rewind: Rewind,
+    // the movie being recorded or played back
+    session: Session,
+    // a movie, written by stop_recording or by the host ahead of
+    // start_playback
+    movie: Vec<u8>,
+    // the frame at which the last playback desynced
+    desync_frame: u32,
+    // how the last movie ended, as reported by get_movie_status
+    movie_status: u8,
+}
+
+impl Instance {
+    fn new() -> Instance {
+        Instance {
+            debugger: Debugger::new(Machine::new(Cpu::new())),
+            last_fault: None,
+            stop: None,
+            text: String::new(),
+            rom: Vec::new(),
+            state: Vec::new(),
+            rewind: Rewind::new(10, 180),
+            session: Session::Idle,
+            movie: Vec::new(),
+            desync_frame: 0,
+            movie_status: 0,
+        }
     }
-};
 
-#[no_mangle]
-pub fn reset() {
-    unsafe {
-        CPU.reset();
+    // everything but the CPU, which the caller resets
+    fn reset(&mut self) {
+        self.debugger.machine.restart_frame();
+        self.debugger.clear_journal();
+        self.last_fault = None;
+        self.stop = None;
+        self.rewind.clear();
+        self.session = Session::Idle;
     }
 }
 
-#[no_mangle]
-pub fn get_memory() -> &'static [u8; 4096] {
-    unsafe {
-        &CPU.memory
+// A place for a machine. Its generation counts the machines destroyed in it,
+// and is part of the handle, so that a handle kept after destroy_machine
+// is not taken for the next machine to use the slot.
+struct Slot {
+    generation: u16,
+    instance: Option<Box<Instance>>,
+}
+
+// the machines, a handle being the generation in its high 16 bits and the
+// index of its slot + 1 in the low 16 bits, so that 0 is never a handle
+static INSTANCES: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+
+// the slot the handle refers to, provided the handle is of its generation
+fn slot(instances: &mut [Slot], handle: u32) -> Option<&mut Slot> {
+    let index = ((handle & 0xFFFF) as usize).wrapping_sub(1);
+    instances.get_mut(index).filter(|slot| slot.generation == (handle >> 16) as u16)
+}
+
+// runs f on the instance with the handle, or returns otherwise if there is
+// none
+fn with<T, F: FnOnce(&mut Instance) -> T>(handle: u32, otherwise: T, f: F) -> T {
+    let mut instances = INSTANCES.lock().unwrap();
+    match slot(&mut instances, handle).and_then(|slot| slot.instance.as_mut()) {
+        Some(instance) => f(instance),
+        None => otherwise,
     }
 }
 
+// creates a machine with default quirks, ready for a ROM to be loaded with
+// prepare_rom and load_rom, returning its handle, or 0 if there are already
+// 65535 machines
 #[no_mangle]
-pub fn get_display() -> &'static [u8; 2048] {
-    unsafe {
-        &CPU.display.memory
-    }
+pub fn create_machine() -> u32 {
+    let mut instances = INSTANCES.lock().unwrap();
+    let index = match instances.iter().position(|slot| slot.instance.is_none()) {
+        Some(index) => index,
+        None if instances.len() < 0xFFFF => {
+            instances.push(Slot { generation: 0, instance: None });
+            instances.len() - 1
+        },
+        None => return 0,
+    };
+    let slot = &mut instances[index];
+    slot.instance = Some(Box::new(Instance::new()));
+    (slot.generation as u32) << 16 | (index as u32 + 1)
 }
 
+// frees the machine, returning false if the handle is not valid
 #[no_mangle]
-pub fn key_down(i: u8) {
-    unsafe {
-        CPU.keypad.key_down(i);
+pub fn destroy_machine(handle: u32) -> bool {
+    let mut instances = INSTANCES.lock().unwrap();
+    match slot(&mut instances, handle).filter(|slot| slot.instance.is_some()) {
+        Some(slot) => {
+            slot.instance = None;
+            slot.generation = slot.generation.wrapping_add(1);
+            true
+        },
+        None => false,
     }
 }
 
 #[no_mangle]
-pub fn key_up(i: u8) {
-    unsafe {
-        CPU.keypad.key_up(i);
+pub fn reset(handle: u32) {
+    with(handle, (), |instance| {
+        instance.debugger.machine.cpu.reset();
+        instance.reset();
+    })
+}
+
+// sizes the ROM buffer to hold a ROM of len bytes, which the host then
+// writes via the pointer returned before calling load_rom
+#[no_mangle]
+pub fn prepare_rom(handle: u32, len: usize) -> *mut u8 {
+    with(handle, ptr::null_mut(), |instance| {
+        instance.rom = vec![0; len];
+        instance.rom.as_mut_ptr()
+    })
+}
+
+// Resets the machine and loads the ROM in the ROM buffer, for the active
+// platform. Returns 0 if it loaded, otherwise the machine is unchanged and
+// the length of the reason why, which the host reads via get_text.
+#[no_mangle]
+pub fn load_rom(handle: u32) -> usize {
+    with(handle, 0, |instance| match instance.debugger.machine.cpu.load_rom(&instance.rom) {
+        Ok(()) => {
+            instance.reset();
+            0
+        },
+        Err(error) => {
+            instance.text = error.to_string();
+            instance.text.len()
+        },
+    })
+}
+
+// Looks up the ROM in the ROM buffer in the built in ROM database, and if it
+// is there runs the machine at the platform, quirks and instructions per
+// frame recommended for it. Called before load_rom, as the platform decides
+// the memory the ROM has. Returns 0 if it is unknown, otherwise the length of
+// its title and keys, on separate lines, which the host reads via get_text.
+#[no_mangle]
+pub fn apply_rom_info(handle: u32) -> usize {
+    with(handle, 0, |instance| match Database::builtin().lookup(&instance.rom) {
+        Some(info) => {
+            instance.debugger.machine.cpu.set_platform(info.platform);
+            instance.debugger.machine.cpu.quirks = info.quirks;
+            instance.debugger.machine.instructions_per_frame = info.instructions_per_frame;
+            instance.text = format!("{}\n{}", info.title, info.keys);
+            instance.text.len()
+        },
+        None => 0,
+    })
+}
+
+#[no_mangle]
+pub fn get_memory(handle: u32) -> *const u8 {
+    with(handle, ptr::null(), |instance| instance.debugger.machine.cpu.memory.as_ptr())
+}
+
+// the addressable memory for the active platform
+#[no_mangle]
+pub fn get_memory_size(handle: u32) -> usize {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.memory_size())
+}
+
+#[no_mangle]
+pub fn get_display(handle: u32) -> *const u8 {
+    with(handle, ptr::null(), |instance| instance.debugger.machine.cpu.display.memory.as_ptr())
+}
+
+// the active resolution, the display buffer holds rows of this width
+#[no_mangle]
+pub fn get_display_width(handle: u32) -> usize {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.display.width())
+}
+
+#[no_mangle]
+pub fn get_display_height(handle: u32) -> usize {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.display.height())
+}
+
+// set once the ROM has executed the SUPER-CHIP exit instruction
+#[no_mangle]
+pub fn is_halted(handle: u32) -> bool {
+    with(handle, false, |instance| instance.debugger.machine.cpu.halted)
+}
+
+// key presses are recorded while recording a movie, and ignored while
+// playing one back
+#[no_mangle]
+pub fn key_down(handle: u32, i: u8) {
+    with(handle, (), |instance| {
+        let cpu = &mut instance.debugger.machine.cpu;
+        match instance.session {
+            Session::Idle => cpu.keypad.key_down(i),
+            Session::Recording(ref mut recorder) => recorder.key_down(cpu, i),
+            Session::Playing(_) => {},
+        }
+    })
+}
+
+#[no_mangle]
+pub fn key_up(handle: u32, i: u8) {
+    with(handle, (), |instance| {
+        let cpu = &mut instance.debugger.machine.cpu;
+        match instance.session {
+            Session::Idle => cpu.keypad.key_up(i),
+            Session::Recording(ref mut recorder) => recorder.key_up(cpu, i),
+            Session::Playing(_) => {},
+        }
+    })
+}
+
+#[no_mangle]
+pub fn get_register_v(handle: u32) -> *const u8 {
+    with(handle, ptr::null(), |instance| instance.debugger.machine.cpu.v.as_ptr())
+}
+
+#[no_mangle]
+pub fn get_register_i(handle: u32) -> u16 {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.i)
+}
+
+#[no_mangle]
+pub fn get_register_pc(handle: u32) -> u16 {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.pc)
+}
+
+// records the rewind history and the movie at the end of every frame
+fn end_frame(cpu: &mut Cpu, rewind: &mut Rewind, session: &mut Session, desync_frame: &mut u32,
+             movie_status: &mut u8) {
+    rewind.frame(cpu);
+    let status = match *session {
+        Session::Idle => return,
+        Session::Recording(ref mut recorder) => {
+            recorder.end_frame(cpu);
+            return;
+        },
+        Session::Playing(ref mut player) => match player.end_frame(cpu) {
+            Ok(()) if player.is_finished() => 1,
+            Ok(()) => return,
+            Err(desync) => {
+                *desync_frame = desync.frame;
+                2
+            },
+        },
+    };
+    *session = Session::Idle;
+    *movie_status = status;
+}
+
+// Executes one instruction, completing the frame if it is the last. Returns
+// false if the cycle faulted, the fault is then available via get_last_fault
+// and get_last_fault_address.
+#[no_mangle]
+pub fn execute_cycle(handle: u32) -> bool {
+    with(handle, false, |instance| {
+        let result = {
+            let Instance { ref mut debugger, ref mut rewind, ref mut session, ref mut desync_frame,
+                           ref mut movie_status, .. } = *instance;
+            debugger.clear_journal();
+            let machine = &mut debugger.machine;
+            machine.step().map(|ended| if ended {
+                end_frame(&mut machine.cpu, rewind, session, desync_frame, movie_status);
+            })
+        };
+        match result {
+            Ok(()) => true,
+            Err(fault) => {
+                instance.last_fault = Some(fault);
+                false
+            }
+        }
+    })
+}
+
+// Called by the host once per animation frame with the microseconds since
+// the last call, runs the frames due at 60 a second. Returns the number of
+// frames completed, or -1 if an instruction faulted.
+#[no_mangle]
+pub fn advance(handle: u32, elapsed_micros: u32) -> i32 {
+    with(handle, 0, |instance| {
+        let result = {
+            let Instance { ref mut debugger, ref mut rewind, ref mut session, ref mut desync_frame,
+                           ref mut movie_status, .. } = *instance;
+            debugger.clear_journal();
+            debugger.machine.advance_with(elapsed_micros as u64, &mut |cpu| {
+                end_frame(cpu, rewind, session, desync_frame, movie_status)
+            })
+        };
+        match result {
+            Ok(frames) => frames as i32,
+            Err(fault) => {
+                instance.last_fault = Some(fault);
+                -1
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub fn get_instructions_per_frame(handle: u32) -> u32 {
+    with(handle, 0, |instance| instance.debugger.machine.instructions_per_frame)
+}
+
+// sets the speed, in instructions executed each frame, at least 1
+#[no_mangle]
+pub fn set_instructions_per_frame(handle: u32, instructions: u32) -> bool {
+    if instructions == 0 {
+        return false;
     }
+    with(handle, false, |instance| {
+        instance.debugger.machine.instructions_per_frame = instructions;
+        true
+    })
+}
+
+// 0 - instructions_per_frame each frame, 1 - as many as the COSMAC VIP
+// would run, with DRW waiting for the vertical blank
+#[no_mangle]
+pub fn set_timing(handle: u32, timing: u8) -> bool {
+    let timing = match timing {
+        0 => Timing::Instructions,
+        1 => Timing::CosmacVip,
+        _ => return false,
+    };
+    with(handle, false, |instance| {
+        instance.debugger.machine.timing = timing;
+        true
+    })
+}
+
+// 0 - no fault, 1 - stack overflow, 2 - stack underflow,
+// 3 - memory out of bounds, 4 - pc out of range, 5 - unknown opcode
+#[no_mangle]
+pub fn get_last_fault(handle: u32) -> u8 {
+    with(handle, 0, |instance| match instance.last_fault {
+        None => 0,
+        Some(CpuError::StackOverflow { .. }) => 1,
+        Some(CpuError::StackUnderflow { .. }) => 2,
+        Some(CpuError::MemoryOutOfBounds { .. }) => 3,
+        Some(CpuError::PcOutOfRange { .. }) => 4,
+        Some(CpuError::UnknownOpcode { .. }) => 5,
+    })
+}
+
+// the address of the instruction which faulted
+#[no_mangle]
+pub fn get_last_fault_address(handle: u32) -> u16 {
+    with(handle, 0, |instance| instance.last_fault.map_or(0, |fault| fault.pc()))
+}
+
+#[no_mangle]
+pub fn is_buzzer_on(handle: u32) -> bool {
+    with(handle, false, |instance| instance.debugger.machine.cpu.is_sound_on())
+}
+
+// 0 - COSMAC VIP, 1 - CHIP-48, 2 - SUPER-CHIP 1.1, 3 - XO-CHIP
+#[no_mangle]
+pub fn set_platform(handle: u32, platform: u8) -> bool {
+    let platform = match platform {
+        0 => Platform::CosmacVip,
+        1 => Platform::Chip48,
+        2 => Platform::SuperChip,
+        3 => Platform::XoChip,
+        _ => return false,
+    };
+    with(handle, false, |instance| {
+        instance.debugger.machine.cpu.set_platform(platform);
+        true
+    })
+}
+
+// the quirks packed as per Quirks::bits
+#[no_mangle]
+pub fn get_quirks(handle: u32) -> u8 {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.quirks.bits())
+}
+
+#[no_mangle]
+pub fn set_quirks(handle: u32, bits: u8) {
+    with(handle, (), |instance| instance.debugger.machine.cpu.quirks = Quirks::from_bits(bits))
+}
+
+#[no_mangle]
+pub fn get_audio_pattern(handle: u32) -> *const u8 {
+    with(handle, ptr::null(), |instance| instance.debugger.machine.cpu.audio_pattern.as_ptr())
+}
+
+#[no_mangle]
+pub fn get_audio_pitch(handle: u32) -> u8 {
+    with(handle, 0, |instance| instance.debugger.machine.cpu.pitch)
+}
+
+// the UTF-8 text written by the most recent export which produces text
+#[no_mangle]
+pub fn get_text(handle: u32) -> *const u8 {
+    with(handle, ptr::null(), |instance| instance.text.as_ptr())
+}
+
+// disassembles the program area of memory, 0 - Cowgod syntax, 1 - Octo
+// syntax, returning the length of the listing
+#[no_mangle]
+pub fn disassemble(handle: u32, syntax: u8) -> usize {
+    let syntax = if syntax == 1 { Syntax::Octo } else { Syntax::Cowgod };
+    with(handle, 0, |instance| {
+        let lines = disasm::disassemble_memory(&instance.debugger.machine.cpu, syntax);
+        instance.text = disasm::listing(&lines, syntax);
+        instance.text.len()
+    })
+}
+
+// snapshots the machine into the state buffer, returning its length. The
+// host reads the state via get_state.
+#[no_mangle]
+pub fn save_state(handle: u32) -> usize {
+    with(handle, 0, |instance| {
+        instance.state = instance.debugger.machine.cpu.save_state();
+        instance.state.len()
+    })
+}
+
+#[no_mangle]
+pub fn get_state(handle: u32) -> *mut u8 {
+    with(handle, ptr::null_mut(), |instance| instance.state.as_mut_ptr())
+}
+
+// sizes the state buffer to hold a state of len bytes, which the host then
+// writes via get_state before calling load_state
+#[no_mangle]
+pub fn prepare_state(handle: u32, len: usize) -> *mut u8 {
+    with(handle, ptr::null_mut(), |instance| {
+        instance.state = vec![0; len];
+        instance.state.as_mut_ptr()
+    })
 }
 
+// restores the state in the state buffer, returning false and leaving the
+// machine unchanged if it is not a valid save state. Loading states is
+// disabled while a movie is recorded or played back, as the movie would no
+// longer follow on from its start.
 #[no_mangle]
-pub fn get_register_v() -> &'static [u8; 16] {
-    unsafe {
-        &CPU.v
+pub fn load_state(handle: u32) -> bool {
+    with(handle, false, |instance| {
+        if !matches!(instance.session, Session::Idle) {
+            return false;
+        }
+        if instance.debugger.machine.cpu.load_state(&instance.state).is_err() {
+            return false;
+        }
+        instance.debugger.machine.restart_frame();
+        instance.debugger.clear_journal();
+        instance.last_fault = None;
+        true
+    })
+}
+
+// snapshots every interval frames, keeping up to capacity snapshots, and
+// discards the current history
+#[no_mangle]
+pub fn set_rewind(handle: u32, interval: u32, capacity: usize) -> bool {
+    if interval == 0 || capacity == 0 {
+        return false;
     }
+    with(handle, false, |instance| {
+        instance.rewind = Rewind::new(interval, capacity);
+        true
+    })
+}
+
+// rewinds by at least frames, as far as the history allows, returning the
+// number of frames rewound. Rewinding is disabled while a movie is recorded
+// or played back.
+#[no_mangle]
+pub fn rewind(handle: u32, frames: u32) -> u32 {
+    with(handle, 0, |instance| {
+        if !matches!(instance.session, Session::Idle) {
+            return 0;
+        }
+        let rewound = instance.rewind.rewind(&mut instance.debugger.machine.cpu, frames);
+        if rewound > 0 {
+            instance.debugger.machine.restart_frame();
+            instance.debugger.clear_journal();
+            instance.last_fault = None;
+        }
+        rewound
+    })
+}
+
+// records a movie from the start of the next frame, at the current speed
+#[no_mangle]
+pub fn start_recording(handle: u32) {
+    with(handle, (), |instance| {
+        instance.session = Session::Recording(Recorder::start(&mut instance.debugger.machine));
+    })
+}
+
+// stops recording and writes the movie to the movie buffer, returning its
+// length, or 0 if no movie was being recorded
+#[no_mangle]
+pub fn stop_recording(handle: u32) -> usize {
+    with(handle, 0, |instance| match mem::replace(&mut instance.session, Session::Idle) {
+        Session::Recording(recorder) => {
+            instance.movie = recorder.finish().to_bytes();
+            instance.movie.len()
+        },
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+pub fn get_movie(handle: u32) -> *mut u8 {
+    with(handle, ptr::null_mut(), |instance| instance.movie.as_mut_ptr())
+}
+
+// sizes the movie buffer to hold a movie of len bytes, which the host then
+// writes via get_movie before calling start_playback
+#[no_mangle]
+pub fn prepare_movie(handle: u32, len: usize) -> *mut u8 {
+    with(handle, ptr::null_mut(), |instance| {
+        instance.movie = vec![0; len];
+        instance.movie.as_mut_ptr()
+    })
+}
+
+// restores the initial state of the movie in the movie buffer and starts
+// playing it back at the speed it was recorded, returning false if it is not
+// a valid movie
+#[no_mangle]
+pub fn start_playback(handle: u32) -> bool {
+    with(handle, false, |instance| {
+        let machine = &mut instance.debugger.machine;
+        let player = Movie::from_bytes(&instance.movie).and_then(|movie| Player::start(movie, machine));
+        match player {
+            Ok(player) => {
+                instance.debugger.clear_journal();
+                instance.session = Session::Playing(player);
+                instance.last_fault = None;
+                true
+            },
+            Err(_) => false,
+        }
+    })
+}
+
+// How the last movie played back ended, reported once. 0 - still playing or
+// already reported, 1 - playback finished, 2 - playback desynced, with the
+// frame available via get_desync_frame.
+#[no_mangle]
+pub fn get_movie_status(handle: u32) -> u8 {
+    with(handle, 0, |instance| mem::replace(&mut instance.movie_status, 0))
+}
+
+#[no_mangle]
+pub fn get_desync_frame(handle: u32) -> u32 {
+    with(handle, 0, |instance| instance.desync_frame)
+}
+
+
+// stops before the instruction at address is executed by a debugger command
+#[no_mangle]
+pub fn add_breakpoint(handle: u32, address: u16) -> bool {
+    with(handle, false, |instance| {
+        instance.debugger.add_breakpoint(address);
+        true
+    })
 }
 
+// returns false if there was no breakpoint at the address
 #[no_mangle]
-pub fn get_register_i() -> u16 {
-    unsafe {
-        CPU.i
+pub fn remove_breakpoint(handle: u32, address: u16) -> bool {
+    with(handle, false, |instance| instance.debugger.remove_breakpoint(address))
+}
+
+fn watch(kind: u8) -> Option<Watch> {
+    match kind {
+        0 => Some(Watch::Read),
+        1 => Some(Watch::Write),
+        2 => Some(Watch::Access),
+        _ => None,
     }
 }
 
+// stops after an instruction run by a debugger command accesses memory from
+// start up to end, 0 - reads, 1 - writes, 2 - either
 #[no_mangle]
-pub fn get_register_pc() -> u16 {
-    unsafe {
-        CPU.pc
+pub fn add_watchpoint(handle: u32, start: usize, end: usize, kind: u8) -> bool {
+    match watch(kind) {
+        Some(watch) if start < end => with(handle, false, |instance| {
+            instance.debugger.add_watchpoint(start..end, watch);
+            true
+        }),
+        _ => false,
     }
 }
 
+// returns false if there was no such watchpoint
 #[no_mangle]
-pub fn execute_cycle() {
-    unsafe {
-        CPU.execute_cycle();
+pub fn remove_watchpoint(handle: u32, start: usize, end: usize, kind: u8) -> bool {
+    match watch(kind) {
+        Some(watch) => with(handle, false, |instance| instance.debugger.remove_watchpoint(start..end, watch)),
+        None => false,
     }
 }
 
+// Runs a debugger command, recording the rewind history and the movie at the
+// end of each frame as advance does. Returns why it stopped: 1 - the step
+// completed, 2 - a breakpoint, 3 - a read watchpoint, 4 - a write
+// watchpoint, 5 - a watched register changed, 6 - the frame started,
+// 7 - the machine halted, 8 - a fault, available via get_last_fault,
+// 9 - the instruction limit was reached.
+fn debug(handle: u32, command: Command) -> u8 {
+    with(handle, 0, |instance| {
+        let reason = {
+            let Instance { ref mut debugger, ref mut rewind, ref mut session, ref mut desync_frame,
+                           ref mut movie_status, .. } = *instance;
+            debugger.run_command(command, &mut |cpu| {
+                end_frame(cpu, rewind, session, desync_frame, movie_status)
+            })
+        };
+        if let StopReason::Fault(fault) = reason {
+            instance.last_fault = Some(fault);
+        }
+        instance.stop = Some(reason);
+        match reason {
+            StopReason::Step => 1,
+            StopReason::Breakpoint(_) => 2,
+            StopReason::ReadWatchpoint { .. } => 3,
+            StopReason::WriteWatchpoint { .. } => 4,
+            StopReason::RegisterChanged { .. } => 5,
+            StopReason::Frame(_) => 6,
+            StopReason::Halted => 7,
+            StopReason::Fault(_) => 8,
+            StopReason::InstructionLimit => 9,
+        }
+    })
+}
+
+// executes one instruction, returning the stop reason as per debug
+#[no_mangle]
+pub fn debug_step(handle: u32) -> u8 {
+    debug(handle, Command::Step)
+}
+
+// steps, running a called subroutine through to its return
 #[no_mangle]
-pub fn decrement_timers() {
-    unsafe {
-        CPU.decrement_timers();
+pub fn debug_step_over(handle: u32) -> u8 {
+    debug(handle, Command::StepOver)
+}
+
+// runs until the current subroutine returns
+#[no_mangle]
+pub fn debug_step_out(handle: u32) -> u8 {
+    debug(handle, Command::StepOut)
+}
+
+// runs until stopped, or max_instructions have run
+#[no_mangle]
+pub fn debug_run(handle: u32, max_instructions: u32) -> u8 {
+    debug(handle, Command::Run(max_instructions as u64))
+}
+
+// runs until the frame, counted from reset, starts
+#[no_mangle]
+pub fn debug_run_until_frame(handle: u32, frame: u32, max_instructions: u32) -> u8 {
+    debug(handle, Command::RunUntilFrame(frame as u64, max_instructions as u64))
+}
+
+// Undoes the last instruction run by a debugger command, returning false if
+// there is none. Running the machine other than by the debugger forgets the
+// instructions, and stepping back is disabled while a movie is recorded or
+// played back.
+#[no_mangle]
+pub fn debug_step_back(handle: u32) -> bool {
+    with(handle, false, |instance| {
+        if !matches!(instance.session, Session::Idle) {
+            return false;
+        }
+        instance.last_fault = None;
+        instance.stop = None;
+        instance.debugger.step_back()
+    })
+}
+
+// where the last debugger command stopped: the address of the breakpoint,
+// the watched address accessed, or the instruction which changed a register
+// or faulted
+#[no_mangle]
+pub fn get_stop_address(handle: u32) -> usize {
+    with(handle, 0, |instance| match instance.stop {
+        Some(StopReason::Breakpoint(address)) => address as usize,
+        Some(StopReason::ReadWatchpoint { address, .. }) | Some(StopReason::WriteWatchpoint { address, .. }) => address,
+        Some(StopReason::RegisterChanged { pc, .. }) => pc as usize,
+        Some(StopReason::Fault(fault)) => fault.pc() as usize,
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_breakpoint, add_watchpoint, create_machine, debug_run, debug_run_until_frame,
+                debug_step, debug_step_back, debug_step_out, debug_step_over, destroy_machine,
+                execute_cycle, get_last_fault, get_memory, get_memory_size, get_register_pc,
+                get_stop_address, get_text, key_down, load_rom, load_state, prepare_rom,
+                remove_breakpoint, remove_watchpoint, reset, save_state, set_platform,
+                start_recording, stop_recording};
+    use std::slice;
+
+    #[test]
+    fn runs_machines_independently() {
+        let (first, second) = (create_machine(), create_machine());
+        assert_ne!(first, second);
+        reset(second);
+        assert!(set_platform(second, 3));
+        assert_eq!(get_memory_size(first), 0x1000);
+        assert_eq!(get_memory_size(second), 0x10000);
+
+        // LD V0, 7 in the first, nothing but zeroes in the second
+        unsafe {
+            slice::from_raw_parts_mut(prepare_rom(first, 2), 2).copy_from_slice(&[0x60, 0x07]);
+        }
+        assert_eq!(load_rom(first), 0);
+        assert!(execute_cycle(first));
+        assert!(!execute_cycle(second));
+        assert_eq!(get_last_fault(first), 0);
+        assert_eq!(get_last_fault(second), 5);
+        assert_eq!(get_register_pc(first), 0x202);
+        assert_eq!(get_register_pc(second), 0x200);
+
+        prepare_rom(first, 0x1000);
+        let length = load_rom(first);
+        let text = unsafe { slice::from_raw_parts(get_text(first), length) };
+        assert_eq!(text, b"ROM is 4096 bytes, but only 3584 bytes fit in memory");
+        assert_eq!(get_register_pc(first), 0x202, "a rejected ROM leaves the machine unchanged");
+
+        assert!(destroy_machine(first));
+        assert!(!destroy_machine(first), "a handle is destroyed once");
+        assert_eq!(get_register_pc(first), 0, "exports ignore destroyed handles");
+        assert!(get_memory(first).is_null());
+        let third = create_machine();
+        assert_ne!(third, first, "handles are not reused");
+        reset(third);
+        assert_eq!(get_register_pc(third), 0x200);
+        assert_eq!(get_register_pc(first), 0, "a destroyed handle stays invalid once its slot is reused");
+        assert!(!destroy_machine(first));
+        assert!(destroy_machine(third));
+        assert!(destroy_machine(second));
     }
-}
\ No newline at end of file
+    #[test]
+    fn debugs_machines() {
+        let handle = create_machine();
+        reset(handle);
+        let program = [
+            0x60, 0x00, // 200: LD V0, 0
+            0xA3, 0x00, // 202: LD I, 0x300
+            0x22, 0x0A, // 204: CALL 0x20A
+            0x12, 0x04, // 206: JP 0x204
+            0x00, 0x00, // 208: padding
+            0x70, 0x01, // 20A: ADD V0, 1
+            0xF0, 0x55, // 20C: LD [I], V0
+            0x00, 0xEE, // 20E: RET
+        ];
+        unsafe {
+            slice::from_raw_parts_mut(prepare_rom(handle, program.len()), program.len()).copy_from_slice(&program);
+        }
+        assert_eq!(load_rom(handle), 0);
+
+        assert_eq!(debug_step(handle), 1);
+        assert_eq!(get_register_pc(handle), 0x202);
+        assert!(add_breakpoint(handle, 0x206));
+        assert_eq!(debug_run(handle, 100), 2);
+        assert_eq!(get_stop_address(handle), 0x206);
+        assert_eq!(debug_step(handle), 1);
+        assert_eq!(debug_step_over(handle), 1);
+        assert_eq!(get_register_pc(handle), 0x206);
+
+        assert!(!add_watchpoint(handle, 0x300, 0x301, 3), "watchpoints are reads, writes or either");
+        assert!(add_watchpoint(handle, 0x300, 0x301, 1));
+        assert_eq!(debug_run(handle, 100), 4);
+        assert_eq!(get_stop_address(handle), 0x300);
+        assert_eq!(get_register_pc(handle), 0x20E);
+        assert_eq!(debug_step_out(handle), 1);
+        assert_eq!(get_register_pc(handle), 0x206);
+        assert!(debug_step_back(handle));
+        assert_eq!(get_register_pc(handle), 0x20E);
+
+        assert!(remove_watchpoint(handle, 0x300, 0x301, 1));
+        assert!(remove_breakpoint(handle, 0x206));
+        assert!(!remove_breakpoint(handle, 0x206));
+        assert_eq!(debug_run(handle, 5), 9);
+        assert_eq!(debug_run_until_frame(handle, 2, 1000), 6);
+        assert!(execute_cycle(handle));
+        assert!(!debug_step_back(handle), "running other than by the debugger forgets the journal");
+
+        assert!(destroy_machine(handle));
+        assert_eq!(debug_step(handle), 0);
+    }
+    #[test]
+    fn loads_states_only_outside_movies() {
+        let handle = create_machine();
+        reset(handle);
+        // JP 0x200
+        unsafe {
+            slice::from_raw_parts_mut(prepare_rom(handle, 2), 2).copy_from_slice(&[0x12, 0x00]);
+        }
+        assert_eq!(load_rom(handle), 0);
+        assert!(save_state(handle) > 0);
+        assert_eq!(debug_run_until_frame(handle, 30, 1000), 6);
+
+        start_recording(handle);
+        assert!(!load_state(handle), "a state saved before the recording started is refused");
+        key_down(handle, 1);
+        assert!(stop_recording(handle) > 0);
+        assert!(load_state(handle));
+        assert!(destroy_machine(handle));
+    }
+}
@@ -0,0 +1,183 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// writes a ROM to a temporary file, named for the test using it
+fn rom(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("chip8-runner-{}-{}.ch8", name, std::process::id()));
+    fs::write(&path, bytes).unwrap();
+    path
+}
+
+fn run(rom: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_chip8"))
+        .arg(rom)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn runs_rom_and_writes_display() {
+    let image = env::temp_dir().join(format!("chip8-runner-ibm-{}.pbm", std::process::id()));
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "30", "--pbm", image.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("PC=0228"));
+    assert!(stdout.contains("frames=30 instructions=300"));
+
+    let pbm = fs::read(&image).unwrap();
+    assert!(pbm.starts_with(b"P4\n64 32\n"));
+    assert!(pbm[9..].iter().any(|&byte| byte != 0));
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn limits_instructions() {
+    // JP 0x200
+    let path = rom("limit", &[0x12, 0x00]);
+    let output = run(&path, &["--instructions", "25", "--ipf", "7"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("frames=3 instructions=25"));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn feeds_key_presses() {
+    // LD V0, K; JP 0x202
+    let path = rom("keys", &[0xF0, 0x0A, 0x12, 0x02]);
+    let output = run(&path, &["--frames", "5", "--key", "b@2-4"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("V0=0B"));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn exits_with_error_on_fault() {
+    // RET with an empty stack
+    let path = rom("fault", &[0x00, 0xEE]);
+    let output = run(&path, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("stack underflow at 0x0200"));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rejects_bad_arguments() {
+    let path = rom("arguments", &[0x12, 0x00]);
+    assert_eq!(run(&path, &["--key", "g@1"]).status.code(), Some(2));
+    assert_eq!(run(&path, &["--platform", "nes"]).status.code(), Some(2));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rejects_oversize_roms() {
+    let path = rom("oversize", &[0x12; 0x1000]);
+    let output = run(&path, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().ends_with("ROM is 4096 bytes, but only 3584 bytes fit in memory\n"));
+    assert!(run(&path, &["--platform", "xo", "--frames", "1"]).status.success());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn writes_and_compares_traces() {
+    let trace = |name: &str, instructions: &str| {
+        let path = env::temp_dir().join(format!("chip8-runner-{}-{}.trace", name, std::process::id()));
+        let output = run(&PathBuf::from("web/roms/IBM"), &["--instructions", instructions, "--trace", path.to_str().unwrap()]);
+        assert!(output.status.success());
+        path
+    };
+    let (full, again, short) = (trace("full", "20"), trace("again", "20"), trace("short", "19"));
+    let text = fs::read_to_string(&full).unwrap();
+    assert_eq!(text.lines().count(), 20);
+    assert!(text.starts_with("000000 0200  00E0      CLS\n000000 0202  A22A      LD I, 0x22A            | I=022A\n"));
+
+    let diff = |a: &PathBuf, b: &PathBuf| Command::new(env!("CARGO_BIN_EXE_tracediff")).arg(a).arg(b).args(["--context", "1"]).output().unwrap();
+    assert!(diff(&full, &again).status.success());
+    let output = diff(&full, &short);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("traces diverge at line 20\n  000001 0224  A275 "));
+    assert!(stdout.ends_with("+ <end of trace>\n"));
+    for path in &[full, again, short] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn writes_profiles() {
+    let profile = env::temp_dir().join(format!("chip8-runner-profile-{}.txt", std::process::id()));
+    let chrome = env::temp_dir().join(format!("chip8-runner-profile-{}.json", std::process::id()));
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "3", "--profile", profile.to_str().unwrap(),
+                                                        "--chrome-trace", chrome.to_str().unwrap()]);
+    assert!(output.status.success());
+    let report = fs::read_to_string(&profile).unwrap();
+    assert!(report.starts_with("instructions: 30 in 3 frames, 10.0 per frame (min 10, max 10)\n"));
+    assert!(report.contains("0x0228   JP 0x228\n"));
+    assert!(fs::read_to_string(&chrome).unwrap().contains(r#""name":"frame 2""#));
+    fs::remove_file(profile).unwrap();
+    fs::remove_file(chrome).unwrap();
+}
+
+#[test]
+fn writes_coverage() {
+    let path = |name: &str| env::temp_dir().join(format!("chip8-runner-coverage-{}.{}", std::process::id(), name));
+    let (coverage, heatmap, listing) = (path("txt"), path("png"), path("lst"));
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "3", "--coverage", coverage.to_str().unwrap(),
+                                                        "--heatmap", heatmap.to_str().unwrap(),
+                                                        "--listing", listing.to_str().unwrap()]);
+    assert!(output.status.success());
+    let report = fs::read_to_string(&coverage).unwrap();
+    assert!(report.starts_with("132 bytes: 42 executed, 90 data, 0 unused\nunexecuted code: 0 bytes\n"));
+    assert!(report.ends_with("memory:\n0200-0229 x--\n022A-0283 -r-\n"));
+    assert!(fs::read(&heatmap).unwrap().starts_with(b"\x89PNG"));
+    assert!(fs::read_to_string(&listing).unwrap().contains("022A  FF00        DB 0xFF, 0x00\n"));
+    for path in &[coverage, heatmap, listing] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn names_addresses_from_symbols() {
+    let path = |name: &str| env::temp_dir().join(format!("chip8-runner-symbols-{}.{}", std::process::id(), name));
+    let (symbols, trace, listing) = (path("sym"), path("trace"), path("lst"));
+    fs::write(&symbols, "main = 0x200\nloop = 0x228 ; waits forever\nlogo = 0x22A\n").unwrap();
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--instructions", "20", "--symbols", symbols.to_str().unwrap(),
+                                                        "--trace", trace.to_str().unwrap(),
+                                                        "--listing", listing.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(fs::read_to_string(&trace).unwrap()
+        .contains("000000 0202 main+2                A22A      LD I, logo             | I=022A\n"));
+    assert!(fs::read_to_string(&listing).unwrap().contains("              loop:\n0228  1228        JP loop\n"));
+
+    fs::write(&symbols, "main = 0x200\nmain 3\n").unwrap();
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--symbols", symbols.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().contains(":2: expected NAME = ADDRESS"));
+    for path in &[symbols, trace, listing] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn applies_rom_database() {
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "3"]);
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("chip8: IBM Logo; keys: none\n"));
+
+    let database = env::temp_dir().join(format!("chip8-runner-romdb-{}.txt", std::process::id()));
+    fs::write(&database, "[1ba58656810b67fd131eb9af3e3987863bf26c90]\ntitle = IBM\nipf = 5\n").unwrap();
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "3", "--romdb", database.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("chip8: IBM; keys: unknown\n"));
+    assert!(String::from_utf8(output.stdout).unwrap().contains("frames=3 instructions=15"));
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--frames", "3", "--ipf", "7", "--romdb", database.to_str().unwrap()]);
+    assert!(String::from_utf8(output.stdout).unwrap().contains("frames=3 instructions=21"), "--ipf takes precedence");
+
+    fs::write(&database, "[1ba58656810b67fd131eb9af3e3987863bf26c90]\nipf = 5\n").unwrap();
+    let output = run(&PathBuf::from("web/roms/IBM"), &["--romdb", database.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().contains(":1: ROM 1ba58656810b67fd131eb9af3e3987863bf26c90 has no title"));
+    fs::remove_file(database).unwrap();
+}
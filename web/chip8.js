@@ -3,8 +3,6 @@ const hex = (value, length = 2) => {
   return padded.substr(padded.length - length);
 };
 
-const inRange = (value, lower, upper) => value >= lower && value <= upper;
-
 const ROMS = [
   "15PUZZLE",
   "BLINKY",
@@ -32,6 +30,36 @@ const ROMS = [
   "WIPEOFF"
 ];
 
+const FAULTS = [
+  "",
+  "Stack overflow",
+  "Stack underflow",
+  "Memory access out of bounds",
+  "Program counter out of range",
+  "Unknown opcode"
+];
+
+// why a debugger command stopped, as returned by debug_step and the like,
+// with faults reported as above
+const STOPS = [
+  "",
+  "",
+  "Breakpoint",
+  "Read watchpoint",
+  "Write watchpoint",
+  "Register changed",
+  "Frame started",
+  "Halted",
+  "",
+  "Instruction limit reached"
+];
+
+// the most instructions Continue runs before giving control back to the page
+const CONTINUE_LIMIT = 1000000;
+
+// colours for each combination of the two XO-CHIP bitplanes
+const PALETTE = [[0, 0, 0], [0x33, 0xff, 0x66], [0x11, 0x77, 0x33], [0xcc, 0xff, 0xdd]];
+
 const translateKeys = {
   49: 0x1, // 1
   50: 0x2, // 2
@@ -51,60 +79,9 @@ const translateKeys = {
   86: 0xf // V
 };
 
-const dissassemble = (program, addr) => {
-  const opcode = (program[addr] << 8) | program[addr + 1];
-
-  const x = (opcode & 0x0f00) >> 8;
-  const y = (opcode & 0x00f0) >> 4;
-  const nnn = opcode & 0x0fff;
-  const kk = opcode & 0x00ff;
-  const n = opcode & 0x000f;
-
-  if (opcode === 0x00e0) return "CLS";
-  if (opcode === 0x00ee) return "RET";
-  if (inRange(opcode, 0x1000, 0x1fff)) return `JP 0x${hex(nnn, 3)}`;
-  if (inRange(opcode, 0x2000, 0x2fff)) return `CALL 0x${hex(nnn, 3)}`;
-  if (inRange(opcode, 0x3000, 0x3fff)) return `SE V${n} ${kk}`;
-  if (inRange(opcode, 0x4000, 0x4fff)) return `SNE V${n} ${kk}`;
-  if (inRange(opcode, 0x5000, 0x5fff)) return `SE V${x} V${y}`;
-  if (inRange(opcode, 0x6000, 0x6fff)) return `LD V${x} ${kk}`;
-  if (inRange(opcode, 0x7000, 0x7fff)) return `ADD V${x} ${kk}`;
-  if (inRange(opcode, 0x8000, 0x8fff)) {
-    if (n === 0x0) return `LD V${x} V${y}`;
-    if (n === 0x1) return `OR V${x} V${y}`;
-    if (n === 0x2) return `AND V${x} V${y}`;
-    if (n === 0x3) return `XOR V${x} V${y}`;
-    if (n === 0x4) return `ADD V${x} V${y}`;
-    if (n === 0x5) return `SUB V${x} V${y}`;
-    if (n === 0x6) return `SHR V${x}`;
-    if (n === 0x7) return `SUBN V${x} V${y}`;
-    if (n === 0xe) return `SHL V${x}`;
-  }
-  if (inRange(opcode, 0x9000, 0x9fff)) return `SNE V${x} V${y}`;
-  if (inRange(opcode, 0xa000, 0xafff)) return `LDI ${nnn}`;
-  if (inRange(opcode, 0xb000, 0xbfff)) return `JP V0 + ${nnn}`;
-  if (inRange(opcode, 0xc000, 0xcfff)) return `RND ${kk}`;
-  if (inRange(opcode, 0xd000, 0xdfff)) return `DRW V${x} V${y} ${n}`;
-  if (inRange(opcode, 0xe000, 0xefff)) {
-    if (kk === 0x9e) return `SKP V${x}`;
-    if (kk === 0xa1) return `SKNP V${x}`;
-  }
-  if (inRange(opcode, 0xf000, 0xffff)) {
-    if (kk === 0x07) return `LD V${x} DT`;
-    if (kk === 0x0a) return `LD V${x} K`;
-    if (kk === 0x15) return `LD DT, V${x}`;
-    if (kk === 0x1e) return `ADD I, V${x}`;
-    if (kk === 0x29) return `LD F, V${x}`;
-    if (kk === 0x33) return `LD B, V${x}`;
-    if (kk === 0x55) return `LD [I], ${x}`;
-    if (kk === 0x65) return `LD ${x}, [I]`;
-  }
-  return "-";
-};
-
 const run = async () => {
-  const WIDTH = 64;
-  const HEIGHT = 32;
+  const MAX_WIDTH = 128;
+  const MAX_HEIGHT = 64;
 
   // load and instantiate the WASM module
   const res = await fetch("chip8.wasm");
@@ -112,71 +89,88 @@ const run = async () => {
   const module = await WebAssembly.compile(buffer);
   const instance = await WebAssembly.instantiate(module);
   const exports = instance.exports;
+  const machine = exports.create_machine();
 
-  // obtain the various memory sections
-  const programMemory = new Uint8Array(
-    exports.memory.buffer,
-    exports.get_memory(),
-    4096
-  );
-  const displayMemory = new Uint8Array(
-    exports.memory.buffer,
-    exports.get_display(),
-    2048
-  );
-  const vMemory = new Uint8Array(
-    exports.memory.buffer,
-    exports.get_register_v(),
-    16
-  );
+  // views of the machine's memory, made afresh each time as they are lost
+  // whenever the WebAssembly memory grows
+  const bytes = (pointer, length) => new Uint8Array(exports.memory.buffer, pointer, length);
+  const displayMemory = () => bytes(exports.get_display(machine), MAX_WIDTH * MAX_HEIGHT);
+  const vMemory = () => bytes(exports.get_register_v(machine), 16);
 
   // initialise the canvas
   const canvas = document.getElementById("canvas");
   const ctx = canvas.getContext("2d");
   ctx.fillStyle = "black";
-  ctx.fillRect(0, 0, WIDTH, HEIGHT);
+  ctx.fillRect(0, 0, canvas.width, canvas.height);
 
   const updateDisplay = () => {
-    const imageData = ctx.createImageData(WIDTH, HEIGHT);
-    for (let i = 0; i < displayMemory.length; i++) {
-      imageData.data[i * 4] = displayMemory[i] === 1 ? 0x33 : 0;
-      imageData.data[i * 4 + 1] = displayMemory[i] === 1 ? 0xff : 0;
-      imageData.data[i * 4 + 2] = displayMemory[i] === 1 ? 0x66 : 0;
+    // the canvas tracks the active resolution, scaled to fill the screen
+    const width = exports.get_display_width(machine);
+    const height = exports.get_display_height(machine);
+    if (canvas.width !== width) {
+      canvas.width = width;
+      canvas.height = height;
+      canvas.style.transform = `scale(${512 / width})`;
+    }
+    const imageData = ctx.createImageData(width, height);
+    const display = displayMemory();
+    for (let i = 0; i < width * height; i++) {
+      const colour = PALETTE[display[i]];
+      imageData.data[i * 4] = colour[0];
+      imageData.data[i * 4 + 1] = colour[1];
+      imageData.data[i * 4 + 2] = colour[2];
       imageData.data[i * 4 + 3] = 255;
     }
     ctx.putImageData(imageData, 0, 0);
   };
 
+  let running = false;
+  const runButton = document.getElementById("run");
+
+  const reportFault = () => {
+    running = false;
+    runButton.innerHTML = "Start";
+    const address = "0x" + hex(exports.get_last_fault_address(machine), 4);
+    $("#fault").text(`${FAULTS[exports.get_last_fault(machine)]} at ${address}`);
+  };
+
   const dumpRegisters = () => {
     $("#r1").empty();
-    const vValues = Array(16);
-    for (let i = 0; i < vMemory.length; i++) {
-      $("#r1").append(`<div>V${i}: ${vMemory[i]}</div>`);
+    const v = vMemory();
+    for (let i = 0; i < v.length; i++) {
+      $("#r1").append(`<div>V${i}: ${v[i]}</div>`);
     }
     $("#r2").empty();
-    $("#r2").append(`<div>PC: ${exports.get_register_pc()}</div>`);
-    $("#r2").append(`<div>I: ${exports.get_register_i()}</div>`);
+    $("#r2").append(`<div>PC: ${exports.get_register_pc(machine)}</div>`);
+    $("#r2").append(`<div>I: ${exports.get_register_i(machine)}</div>`);
   };
 
+  const readText = length =>
+    new TextDecoder().decode(
+      new Uint8Array(exports.memory.buffer, exports.get_text(machine), length)
+    );
+
+  // the listing is generated by the core, lines starting with an address
+  // are instructions and the rest are labels
+  const breakpoints = new Set();
   const dumpMemory = () => {
     $(".memory").empty();
-    let address = 0x200;
-    while (address < 4096) {
-      const clazz = `addr_${address}`;
-      const haddress = "0x" + hex(address, 4);
-      $(".memory").append(
-        `<div class='${clazz}'>${haddress} - ${dissassemble(
-          programMemory,
-          address
-        )}</div>`
-      );
-      address += 2;
-    }
+    const listing = readText(exports.disassemble(machine, 0)).split("\n");
+    listing.forEach(line => {
+      const match = /^([0-9A-F]{4}) /.exec(line);
+      if (!match) {
+        $(".memory").append(`<div>${line}</div>`);
+        return;
+      }
+      const address = parseInt(match[1], 16);
+      const clazz = breakpoints.has(address) ? `addr_${address} breakpoint` : `addr_${address}`;
+      $(".memory").append(`<div class='${clazz}' data-address='${address}'>${line}</div>`);
+    });
   };
 
   const updateProgramCounter = () => {
     $(`.memory > div`).removeClass("pc");
-    const pc = exports.get_register_pc();
+    const pc = exports.get_register_pc(machine);
     const currentAddress = $(`.memory .addr_${pc}`).addClass("pc");
     if (currentAddress[0]) {
       const container = $(".memory");
@@ -194,16 +188,19 @@ const run = async () => {
     updateProgramCounter();
   };
 
-  const loadRom = rom =>
-    fetch(`roms/${rom}`)
+  const loadRom = name =>
+    fetch(`roms/${name}`)
       .then(i => i.arrayBuffer())
       .then(buffer => {
-        // write the ROM to memory
-        const rom = new DataView(buffer, 0, buffer.byteLength);
-        exports.reset();
-        for (i = 0; i < rom.byteLength; i++) {
-          programMemory[0x200 + i] = rom.getUint8(i);
-        }
+        // stage the ROM for the core, which checks it fits in memory
+        const rom = new Uint8Array(buffer);
+        bytes(exports.prepare_rom(machine, rom.length), rom.length).set(rom);
+        // the ROM database's settings stand in for the default platform
+        const info = Number($("#platform")[0].value) < 0 ? exports.apply_rom_info(machine) : 0;
+        const [title, keys] = info > 0 ? readText(info).split("\n") : [name, ""];
+        $("#info").text(keys ? `${title}: ${keys}` : title);
+        const error = exports.load_rom(machine);
+        $("#fault").text(error > 0 ? readText(error) : "");
         updateUI();
         dumpMemory();
       });
@@ -216,41 +213,224 @@ const run = async () => {
     loadRom(e.target.value);
   });
 
-  document.getElementById("step").addEventListener("click", () => {
-    exports.execute_cycle();
+  const defaultQuirks = exports.get_quirks(machine);
+  document.getElementById("platform").addEventListener("change", e => {
+    const platform = Number(e.target.value);
+    if (platform < 0) {
+      exports.set_quirks(machine, defaultQuirks);
+    } else {
+      exports.set_platform(machine, platform);
+    }
+  });
+
+  // clicking an instruction in the listing toggles a breakpoint on it
+  $(".memory").on("click", "div[data-address]", e => {
+    const address = Number(e.currentTarget.dataset.address);
+    if (breakpoints.delete(address)) {
+      exports.remove_breakpoint(machine, address);
+    } else {
+      breakpoints.add(address);
+      exports.add_breakpoint(machine, address);
+    }
+    $(e.currentTarget).toggleClass("breakpoint", breakpoints.has(address));
+  });
+
+  // pauses the machine to run a debugger command, reporting why it stopped
+  const debug = command => {
+    running = false;
+    runButton.innerHTML = "Start";
+    const reason = command(machine);
+    if (reason === 8) {
+      reportFault();
+    } else if (reason >= 2 && reason <= 5) {
+      $("#fault").text(`${STOPS[reason]} at 0x${hex(exports.get_stop_address(machine), 4)}`);
+    } else {
+      $("#fault").text(STOPS[reason]);
+    }
+    updateMovie();
     updateUI();
+  };
+
+  document.getElementById("step").addEventListener("click", () => debug(exports.debug_step));
+  document.getElementById("over").addEventListener("click", () => debug(exports.debug_step_over));
+  document.getElementById("out").addEventListener("click", () => debug(exports.debug_step_out));
+  document.getElementById("continue").addEventListener("click", () => {
+    debug(handle => exports.debug_run(handle, CONTINUE_LIMIT));
   });
 
-  let running = false;
-  const runloop = () => {
-    if (running) {
-      for (var i = 0; i < 10; i++) {
-        exports.execute_cycle();
+  document.getElementById("back").addEventListener("click", () => {
+    running = false;
+    runButton.innerHTML = "Start";
+    if (exports.debug_step_back(machine)) {
+      $("#fault").empty();
+      updateUI();
+    }
+  });
+
+  // save states are kept in local storage, base64 encoded, one per slot
+  const slotKey = () => `chip8-state-${$("#slot")[0].value}`;
+
+  document.getElementById("save").addEventListener("click", () => {
+    const length = exports.save_state(machine);
+    const state = new Uint8Array(exports.memory.buffer, exports.get_state(machine), length);
+    let binary = "";
+    for (let i = 0; i < length; i++) {
+      binary += String.fromCharCode(state[i]);
+    }
+    localStorage.setItem(slotKey(), btoa(binary));
+  });
+
+  document.getElementById("load").addEventListener("click", () => {
+    const saved = localStorage.getItem(slotKey());
+    if (!saved) {
+      return;
+    }
+    const binary = atob(saved);
+    const state = new Uint8Array(
+      exports.memory.buffer,
+      exports.prepare_state(machine, binary.length),
+      binary.length
+    );
+    for (let i = 0; i < binary.length; i++) {
+      state[i] = binary.charCodeAt(i);
+    }
+    if (exports.load_state(machine)) {
+      $("#fault").empty();
+      updateUI();
+      dumpMemory();
+    } else {
+      $("#fault").text("The saved state could not be loaded");
+    }
+  });
+
+  // the most recently recorded movie, replayed against its initial state
+  let movie;
+  const recordButton = document.getElementById("record");
+  recordButton.addEventListener("click", () => {
+    if (recordButton.innerHTML === "Record") {
+      exports.start_recording(machine);
+      recordButton.innerHTML = "Stop";
+    } else {
+      const length = exports.stop_recording(machine);
+      movie = new Uint8Array(exports.memory.buffer, exports.get_movie(machine), length).slice();
+      recordButton.innerHTML = "Record";
+    }
+  });
+
+  document.getElementById("replay").addEventListener("click", () => {
+    if (!movie || recordButton.innerHTML !== "Record") {
+      return;
+    }
+    new Uint8Array(
+      exports.memory.buffer,
+      exports.prepare_movie(machine, movie.length),
+      movie.length
+    ).set(movie);
+    if (exports.start_playback(machine)) {
+      $("#fault").empty();
+      updateUI();
+    }
+  });
+
+  const updateMovie = () => {
+    const status = exports.get_movie_status(machine);
+    if (status === 1) {
+      $("#fault").text("Replay finished");
+    } else if (status === 2) {
+      $("#fault").text(`Replay desynced at frame ${exports.get_desync_frame(machine)}`);
+    }
+  };
+
+  // A square wave which is audible whenever the core reports the buzzer is
+  // on. There is one audio context for the page, created on the first click
+  // of Start as browsers only allow audio to start from a user gesture, and
+  // each beep plays a source through its gain.
+  let audio;
+  let source;
+  const startAudio = () => {
+    if (!audio) {
+      const context = new AudioContext();
+      const gain = context.createGain();
+      gain.gain.value = 0.1;
+      gain.connect(context.destination);
+      audio = { context, gain };
+    }
+    audio.context.resume();
+  };
+
+  const updateBuzzer = () => {
+    const on = running && exports.is_buzzer_on(machine);
+    if (on && audio && !source) {
+      const context = audio.context;
+      if (Number($("#platform")[0].value) === 3) {
+        // XO-CHIP plays its 128 bit pattern, looped at a rate set by the pitch
+        const rate = 4000 * Math.pow(2, (exports.get_audio_pitch(machine) - 64) / 48);
+        const buffer = context.createBuffer(1, 128, context.sampleRate);
+        const samples = buffer.getChannelData(0);
+        const audioPattern = bytes(exports.get_audio_pattern(machine), 16);
+        for (let i = 0; i < 128; i++) {
+          samples[i] = (audioPattern[i >> 3] >> (7 - (i & 7))) & 1 ? 1 : -1;
+        }
+        source = context.createBufferSource();
+        source.buffer = buffer;
+        source.loop = true;
+        source.playbackRate.value = rate / context.sampleRate;
+      } else {
+        source = context.createOscillator();
+        source.type = "square";
+        source.frequency.value = 440;
       }
-      exports.decrement_timers();
+      source.connect(audio.gain);
+      source.start();
+    } else if (!on && source) {
+      source.stop();
+      source.disconnect();
+      source = undefined;
     }
+  };
+
+  // the core runs the frames due at 60 a second, whatever the refresh rate
+  let lastTime;
+  const runloop = time => {
+    if (running && lastTime !== undefined) {
+      if (exports.advance(machine, Math.round((time - lastTime) * 1000)) < 0) {
+        reportFault();
+      }
+    }
+    updateMovie();
+    lastTime = time;
+    updateBuzzer();
     updateUI();
     window.requestAnimationFrame(runloop);
   };
   window.requestAnimationFrame(runloop);
 
-  const runButton = document.getElementById("run");
   runButton.addEventListener("click", () => {
     if (running) {
       running = false;
       runButton.innerHTML = "Start";
     } else {
+      startAudio();
       running = true;
       runButton.innerHTML = "Stop";
     }
   });
 
   document.addEventListener("keydown", event => {
-    exports.key_down(translateKeys[event.keyCode]);
+    // backspace rewinds play by a second
+    if (event.keyCode === 8) {
+      event.preventDefault();
+      if (exports.rewind(machine, 60) > 0) {
+        $("#fault").empty();
+        updateUI();
+      }
+      return;
+    }
+    exports.key_down(machine, translateKeys[event.keyCode]);
   });
 
   document.addEventListener("keyup", event => {
-    exports.key_up(translateKeys[event.keyCode]);
+    exports.key_up(machine, translateKeys[event.keyCode]);
   });
 
   $("#roms")[0].value = "WIPEOFF";